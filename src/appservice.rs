@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+use std::result;
+use std::time::Duration;
+
+use itertools::Itertools;
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::config::AppService;
+use crate::errors::Error;
+use crate::matrix_types::{Transaction, TransactionEvent};
+use crate::state;
+
+
+type Result<T> = result::Result<T, Error>;
+
+// Number of recent transaction ids remembered, so transactions retried by the
+// homeserver aren't handled twice. They're saved to survive restarts.
+const SEEN_TRANSACTIONS: usize = 100;
+const SEEN_STATE: &str = "appservice_transactions";
+
+
+/// Generate a random token for the appservice registration
+pub fn generate_token() -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric)
+                      .take(64)
+                      .map(char::from)
+                      .collect()
+}
+
+/// Build the registration file which tells the homeserver about the
+/// application service.
+pub fn registration(sender_localpart: &str, appservice: &AppService, as_token: &str, hs_token: &str) -> String {
+    // JSON strings are valid YAML and take care of quoting
+    let quote = |s: &str| Value::from(s).to_string();
+
+    let users = match &appservice.users {
+        Some(regex) => format!("\n    - exclusive: true\n      regex: {}", quote(regex)),
+        None => " []".to_string(),
+    };
+
+    format!("id: {}\n\
+             url: {}\n\
+             as_token: {}\n\
+             hs_token: {}\n\
+             sender_localpart: {}\n\
+             rate_limited: false\n\
+             namespaces:\n  \
+               users:{}\n  \
+               aliases: []\n  \
+               rooms: []\n",
+            quote(&appservice.id), quote(&appservice.url), quote(as_token),
+            quote(hs_token), quote(sender_localpart), users)
+}
+
+
+/// A transaction pushed by the homeserver. The homeserver keeps retrying it
+/// until it is acknowledged with `Listener::ack`, which should happen once its
+/// events are handled.
+pub struct PendingTransaction {
+    pub events: Vec<TransactionEvent>,
+    txn_id: String,
+    request: Request,
+}
+
+/// HTTP listener for the requests a homeserver makes to an application service
+pub struct Listener {
+    server: Server,
+    hs_token: String,
+    seen: VecDeque<String>,
+}
+
+impl Listener {
+    pub fn bind(appservice: &AppService) -> Result<Self> {
+        let hs_token = appservice.hs_token.clone().ok_or("No hs_token configured for the appservice")?;
+        let server = Server::http(&appservice.bind).map_err(|e| Error::Generic(e.to_string()))?;
+
+        let seen = state::load_state(SEEN_STATE)
+                         .map(|s| s.lines().map(String::from).collect())
+                         .unwrap_or_default();
+
+        Ok(Listener {
+            server,
+            hs_token,
+            seen,
+        })
+    }
+
+    /// Acknowledge a transaction whose events were handled, remembering it in
+    /// case the homeserver sends it again.
+    pub fn ack(&mut self, transaction: PendingTransaction) {
+        self.seen.push_back(transaction.txn_id);
+        if self.seen.len() > SEEN_TRANSACTIONS {
+            self.seen.pop_front();
+        }
+        state::save_state(SEEN_STATE, &self.seen.iter().join("\n"));
+
+        respond(transaction.request, 200, json!({}));
+    }
+
+    /// Wait up to `timeout` for the next transaction from the homeserver,
+    /// answering any other requests along the way.
+    pub fn next_transaction(&mut self, timeout: Duration) -> Option<PendingTransaction> {
+        let mut request = match self.server.recv_timeout(timeout) {
+            Ok(Some(request)) => request,
+            Ok(None) => return None,
+            Err(e) => {
+                println!("Appservice listener error: {:?}", e);
+                return None;
+            },
+        };
+
+        if let Err((status, errcode)) = self.authorize(&request) {
+            respond(request, status, json!({ "errcode": errcode }));
+            return None;
+        }
+
+        let method = request.method().clone();
+        let path = request.url().split('?').next().unwrap_or_default().to_string();
+        // Older homeservers use the unversioned path
+        let txn_id = path.strip_prefix("/_matrix/app/v1/transactions/")
+                         .or_else(|| path.strip_prefix("/transactions/"));
+
+        match (method, txn_id) {
+            (Method::Put, Some(txn_id)) => {
+                if self.seen.iter().any(|t| t == txn_id) {
+                    respond(request, 200, json!({}));
+                    return None;
+                }
+
+                let mut body = String::new();
+                if let Err(e) = request.as_reader().read_to_string(&mut body) {
+                    println!("Unable to read appservice transaction {}: {:?}", txn_id, e);
+                    return None;
+                }
+
+                let transaction = match serde_json::from_str::<Transaction>(&body) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        respond(request, 400, json!({ "errcode": "M_BAD_JSON", "error": e.to_string() }));
+                        return None;
+                    },
+                };
+
+                Some(PendingTransaction {
+                    events: transaction.events,
+                    txn_id: txn_id.to_string(),
+                    request,
+                })
+            },
+            (Method::Post, None) if path == "/_matrix/app/v1/ping" => {
+                respond(request, 200, json!({}));
+                None
+            },
+            // Users and room aliases are never created on demand
+            _ => {
+                respond(request, 404, json!({ "errcode": "M_NOT_FOUND" }));
+                None
+            },
+        }
+    }
+
+    /// Check the request comes from the homeserver, returning the status and
+    /// error code to reject it with otherwise.
+    fn authorize(&self, request: &Request) -> result::Result<(), (u16, &'static str)> {
+        let header_token = request.headers().iter()
+                                  .find(|h| h.field.equiv("Authorization"))
+                                  .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+                                  .map(String::from);
+
+        // Older homeservers send the token as a query parameter instead
+        let query = request.url().split_once('?').map_or("", |(_, q)| q);
+        let query_token = url::form_urlencoded::parse(query.as_bytes())
+                                                .find(|(k, _)| k == "access_token")
+                                                .map(|(_, v)| v.into_owned());
+
+        match header_token.or(query_token) {
+            Some(token) if token == self.hs_token => Ok(()),
+            Some(_) => Err((403, "M_FORBIDDEN")),
+            None => Err((401, "M_UNAUTHORIZED")),
+        }
+    }
+}
+
+
+fn respond(request: Request, status: u16, body: Value) {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let response = Response::from_string(body.to_string())
+                            .with_status_code(status)
+                            .with_header(content_type);
+
+    if let Err(e) = request.respond(response) {
+        println!("Unable to respond to the homeserver: {:?}", e);
+    }
+}
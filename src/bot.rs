@@ -1,20 +1,98 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{result, thread};
 use std::cell::{RefCell, RefMut};
 use std::any::Any;
 
-use reqwest::blocking::Response;
+use serde_json::{json, Value};
+use toml::value::Table;
 
 use crate::errors::Error;
 use crate::client::MatrixClient;
+use crate::appservice::Listener;
+use crate::config::{self, AppService, Backlog, Config, RemovalMode, CONFIG_FILE};
+use crate::graph::{self, Context, GraphEdit, NodeSpec, Registry, Topology};
 use crate::matrix_types::*;
+use crate::room_state::{RoomState, RoomStateCache};
+use crate::state;
+use crate::utils::{html_escape, mention_pills, strip_mx_reply, strip_reply_fallback};
+use crate::workers::WorkerPool;
 
 
 type Result<T> = result::Result<T, Error>;
 
+// Name of the state file the last seen sync token is saved under
+const SYNC_TOKEN_STATE: &str = "sync_token";
+
+// Event types every node graph gets. Nodes which need others declare them
+// with `Node::event_types`, the sync filter asks the server for those too.
+// Any other type never reaches the node graph. Encrypted events are decrypted
+// by the client before being handed to the nodes.
+const SYNC_EVENT_TYPES: &[&str] = &[
+    "m.room.message",
+    "m.room.member",
+    "m.room.encrypted",
+    "m.room.encryption",
+];
+
+// State event types which are only synced to keep the room state cache up to
+// date, they are not handed to the nodes.
+const STATE_EVENT_TYPES: &[&str] = &[
+    "m.room.name",
+    "m.room.canonical_alias",
+    "m.room.power_levels",
+    "m.room.create",
+];
+
+// Threads available to nodes for slow work, see `Bot::spawn`
+const WORKER_THREADS: usize = 4;
+
+// How long a sync request may be held open unless configured otherwise
+const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Longest a sync is held open while jobs are running, so their results are
+// delivered promptly
+const JOB_POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Panics after which a node is disabled for the rest of the run
+const MAX_NODE_PANICS: u32 = 3;
+
+// How long to wait for running jobs when shutting down
+const EXIT_JOB_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// An annotation of another event, usually an emoji reaction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reaction<'e> {
+    /// The event which was reacted to
+    pub event_id: &'e str,
+    pub key: &'e str,
+}
+
+/// Things the bot may be allowed to do in a room, depending on power levels
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action<'t> {
+    Kick,
+    Ban,
+    Redact,
+    Invite,
+    /// Sending a message event of the given type
+    Send(&'t str),
+    /// Sending a state event of the given type
+    SetState(&'t str),
+}
+
+impl From<&RemovalMode> for Action<'_> {
+    fn from(mode: &RemovalMode) -> Self {
+        match mode {
+            RemovalMode::Kick => Action::Kick,
+            RemovalMode::Ban => Action::Ban,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct RoomEvent<'a> {
@@ -31,20 +109,174 @@ impl<'a> RoomEvent<'a> {
     pub fn body(&self) -> Option<&str> {
         self.raw_event.content["body"].as_str()
     }
+
+    pub fn event_id(&self) -> Option<&str> {
+        self.raw_event.event_id.as_deref()
+    }
+
+    /// The id of the message this event replaces, if it is an edit. The
+    /// content of edits is the new content of the message.
+    pub fn edited_event(&self) -> Option<&str> {
+        let relation = &self.raw_event.content["m.relates_to"];
+        if relation["rel_type"] == "m.replace" {
+            relation["event_id"].as_str()
+        } else {
+            None
+        }
+    }
+
+    pub fn is_edit(&self) -> bool {
+        self.edited_event().is_some()
+    }
+
+    /// The uploaded file of an image, file, audio or video message.
+    /// Attachments of encrypted messages aren't supported.
+    pub fn media(&self) -> Option<Mxc<'_>> {
+        let content = &self.raw_event.content;
+        match content["msgtype"].as_str()? {
+            "m.image" | "m.file" | "m.audio" | "m.video" => Mxc::parse(content["url"].as_str()?),
+            _ => None,
+        }
+    }
+
+    pub fn reaction(&self) -> Option<Reaction<'_>> {
+        let relation = &self.raw_event.content["m.relates_to"];
+        if self.raw_event.type_ != "m.reaction" || relation["rel_type"] != "m.annotation" {
+            return None;
+        }
+
+        Some(Reaction {
+            event_id: relation["event_id"].as_str()?,
+            key: relation["key"].as_str()?,
+        })
+    }
+
+    /// The id of the thread's root event, if the event was sent in a thread.
+    pub fn thread_root(&self) -> Option<&str> {
+        let relation = &self.raw_event.content["m.relates_to"];
+        if relation["rel_type"] == "m.thread" {
+            relation["event_id"].as_str()
+        } else {
+            None
+        }
+    }
+
+    /// Content of a plain text message responding to this event. Rich replies
+    /// quote the event, and clients which don't understand replies get the
+    /// quote as part of the body. In threads the response stays in the
+    /// thread, and is only a rich reply if `rich` is set.
+    fn response_content(&self, message: &str, rich: bool) -> Value {
+        let mut content = json!({
+            "msgtype": "m.text",
+            "body": message,
+        });
+
+        let Some(event_id) = self.event_id() else {
+            return content
+        };
+
+        let in_reply_to = json!({ "event_id": event_id });
+
+        content["m.relates_to"] = match (self.thread_root(), rich) {
+            (Some(root), _) => json!({
+                "rel_type": "m.thread",
+                "event_id": root,
+                "is_falling_back": !rich,
+                "m.in_reply_to": in_reply_to,
+            }),
+            (None, true) => json!({ "m.in_reply_to": in_reply_to }),
+            (None, false) => return content,
+        };
+
+        if !rich {
+            return content
+        }
+
+        // A reply's own fallback is left out of the quote, so quotes of quotes
+        // don't pile up
+        let is_reply = self.raw_event.content["m.relates_to"]["m.in_reply_to"].is_object();
+        let sender = &self.raw_event.sender;
+        let quoted = self.body().unwrap_or_default();
+        let quoted = if is_reply { strip_reply_fallback(quoted) } else { quoted };
+        let quoted_html = match self.raw_event.content["formatted_body"].as_str() {
+            Some(html) if is_reply => strip_mx_reply(html),
+            Some(html) => html.to_string(),
+            None => html_escape(quoted),
+        };
+
+        let mut fallback = String::new();
+        for (i, line) in quoted.lines().enumerate() {
+            match i {
+                0 => fallback += &format!("> <{}> {}\n", sender, line),
+                _ => fallback += &format!("> {}\n", line),
+            }
+        }
+
+        content["body"] = Value::String(format!("{}\n{}", fallback, message));
+        content["format"] = Value::from("org.matrix.custom.html");
+        content["formatted_body"] = Value::String(format!(
+            "<mx-reply><blockquote>\
+             <a href=\"https://matrix.to/#/{room}/{event}\">In reply to</a> \
+             <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br />{quote}\
+             </blockquote></mx-reply>{message}",
+            room = self.room_id, event = event_id, sender = sender,
+            quote = quoted_html, message = html_escape(message)
+        ));
+
+        content
+    }
 }
 
-struct Query<'a> {
-    target: Option<String>,
-    func: Box<NodeProcessFn<'a>>
+/// A node as seen by other nodes, see `Bot::inspect_graph`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    pub name: String,
+    pub parent: Option<String>,
+    /// Type of the node, if the graph was loaded with `load_graph`
+    pub type_name: Option<String>,
+    pub children: Vec<String>,
+    pub description: Option<String>,
+    pub status: Option<String>,
+    pub disabled: bool,
 }
 
-type NodeProcessFn<'a> = dyn Fn(&Bot, &mut dyn Node) -> Box<dyn Any> + 'a;
+type AfterEvent<'c> = Box<dyn FnOnce(&Bot) + 'c>;
+
 pub struct Bot<'a, 'c> {
     p_client: Arc<RwLock<MatrixClient>>,
     root_services: Vec<&'a str>,
-    all_services: HashMap<&'a str, RefCell<Box<dyn Node<'a>>>>,
-    delayed_queries: RefCell<HashMap<&'c str, Query<'c>>>,
+    all_services: HashMap<&'a str, RefCell<Box<dyn Node<'a> + 'a>>>,
+    after_event: RefCell<Vec<AfterEvent<'c>>>,
     display_name: String,
+    backlog: Backlog,
+    sync_timeout: Duration,
+    sync_filter: Option<String>,
+    // Event types handed to the nodes, and whether the sync filter is out of
+    // date since the graph changed
+    event_types: BTreeSet<String>,
+    filter_outdated: bool,
+    room_state: RefCell<RoomStateCache>,
+    workers: WorkerPool,
+    node_panics: RefCell<HashMap<String, u32>>,
+    disabled_nodes: RefCell<HashSet<String>>,
+    panic_admins: Vec<String>,
+    graph: Option<Topology<'a>>,
+    graph_edits: RefCell<Vec<PendingEdit>>,
+    config_reloads: RefCell<Vec<String>>,
+    puppets: RefCell<HashMap<String, Puppet>>,
+}
+
+/// A change to the graph waiting to be made, and who to tell about it
+struct PendingEdit {
+    edit: GraphEdit,
+    issuer: String,
+    room_id: String,
+}
+
+/// A virtual user of the application service, and the rooms it has joined
+struct Puppet {
+    client: MatrixClient,
+    rooms: HashSet<String>,
 }
 
 impl<'a, 'c> Bot<'a, 'c> {
@@ -53,70 +285,413 @@ impl<'a, 'c> Bot<'a, 'c> {
             p_client: client_ref,
             root_services: Vec::new(),
             all_services: HashMap::new(),
-            delayed_queries: RefCell::new(HashMap::new()),
+            after_event: RefCell::new(Vec::new()),
             display_name: "".to_string(),
+            backlog: Backlog::default(),
+            sync_timeout: DEFAULT_SYNC_TIMEOUT,
+            sync_filter: None,
+            event_types: SYNC_EVENT_TYPES.iter().map(|t| t.to_string()).collect(),
+            filter_outdated: false,
+            room_state: RefCell::new(RoomStateCache::default()),
+            workers: WorkerPool::new(WORKER_THREADS),
+            node_panics: RefCell::new(HashMap::new()),
+            disabled_nodes: RefCell::new(HashSet::new()),
+            panic_admins: Vec::new(),
+            graph: None,
+            graph_edits: RefCell::new(Vec::new()),
+            config_reloads: RefCell::new(Vec::new()),
+            puppets: RefCell::new(HashMap::new()),
         }
     }
 
-    pub fn client(&self) -> RwLockWriteGuard<MatrixClient> {
-        self.p_client.write().unwrap()
+    pub fn client(&self) -> RwLockReadGuard<'_, MatrixClient> {
+        self.p_client.read().unwrap()
     }
 
     pub fn arc_client(&self) -> Arc<RwLock<MatrixClient>>{
         Arc::clone(&self.p_client)
     }
 
-    pub fn join_public(&self, room_id: &str) -> Result<Response> {
-        let pub_room = self.p_client.read().unwrap().get_public_room_id(room_id);
+    /// Run slow work, e.g. requests to external services, on a worker thread
+    /// so the bot keeps handling events meanwhile. Once `job` is done, `then`
+    /// is called with its result back on the bot's thread, along with the
+    /// event the work was started for.
+    ///
+    /// Jobs can't borrow from their node, so state they share with it has to
+    /// be behind an `Arc`. Use `arc_client` to talk to the homeserver.
+    pub fn spawn<T, J, C>(&self, event: &RoomEvent, job: J, then: C)
+        where T: Send + 'static,
+              J: FnOnce() -> T + Send + 'static,
+              C: FnOnce(&Bot, &RoomEvent, T) + Send + 'static
+    {
+        let room_id = event.room_id.to_string();
+        let from = event.from.to_string();
+        let raw_event = event.raw_event.clone();
+
+        self.workers.submit(move || {
+            let result = job();
 
-        match pub_room {
-            Some(id) => self.p_client.read().unwrap().join(&id),
-            None => Err("Could not join invalid room.".into()),
+            Box::new(move |bot: &Bot| {
+                let event = RoomEvent {
+                    room_id: &room_id,
+                    from: &from,
+                    raw_event,
+                };
+                then(bot, &event, result);
+            })
+        });
+    }
+
+    /// Deliver the results of finished jobs, waiting up to `timeout` for the
+    /// first one.
+    fn process_completions(&self, timeout: Duration) {
+        let mut timeout = timeout;
+        while let Some(completion) = self.workers.completion(timeout) {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| completion(self))) {
+                println!("Job completion panicked: {}", panic_message(&*payload));
+            }
+            timeout = Duration::ZERO;
         }
     }
 
-    pub fn leave_public(&self, room_name: &str) -> Result<Response> {
-        let pub_room = self.p_client.read().unwrap().get_public_room_id(room_name);
+    /// Wait up to `timeout` for all running jobs to finish and deliver their
+    /// results.
+    pub(crate) fn finish_jobs(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while self.workers.pending() > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                println!("Gave up waiting for {} running job(s)", self.workers.pending());
+                return;
+            }
 
-        match pub_room {
-            Some(id) => self.p_client.read().unwrap().leave(&id),
-            None => Err(format!("Could not find room: {}", room_name).into()),
+            self.process_completions(left);
         }
     }
 
-    pub fn reply(&self, event: &RoomEvent, message: &str) -> Result<Response> {
-        self.p_client.write().unwrap().send_msg(event.room_id, message)
+    /// Join a room given by id, alias or public room name. `servers` are used
+    /// to find and join rooms the bot's homeserver doesn't know about yet.
+    pub fn join_room(&self, room: &str, servers: &[&str]) -> Result<String> {
+        let (room_id, via) = self.client().resolve_room(room, servers)?;
+        self.client().join(&room_id, &via)
     }
 
-    pub fn reply_fmt(&self, event: &RoomEvent, fmt_message: &str, message: &str) -> Result<Response> {
-        self.p_client.write().unwrap().send_msg_fmt(event.room_id, fmt_message, message)
+    /// Leave a room given by id, alias or name. Names are matched against the
+    /// rooms the bot is in before falling back to the public room directory.
+    pub fn leave_room(&self, room: &str) -> Result<()> {
+        // The client is locked for each request on its own, the room state
+        // helpers lock it too
+        let room_id = if room.starts_with('!') || room.starts_with('#') {
+            self.client().resolve_room(room, &[])?.0
+        } else {
+            let joined = self.joined_rooms()?.into_iter().find(|id| {
+                self.room_name(id).is_ok_and(|name| name.as_deref() == Some(room))
+            });
+
+            match joined {
+                Some(id) => id,
+                None => self.client().resolve_room(room, &[])?.0,
+            }
+        };
+
+        self.client().leave(&room_id)?;
+        self.room_state.borrow_mut().remove(&room_id);
+
+        Ok(())
+    }
+
+    pub fn reply(&self, event: &RoomEvent, message: &str) -> Result<EventId> {
+        self.p_client.read().unwrap().send_msg(event.room_id, message)
+    }
+
+    /// Respond with a rich reply quoting `event`, making it clear which
+    /// message the response belongs to.
+    pub fn reply_to(&self, event: &RoomEvent, message: &str) -> Result<EventId> {
+        let content = event.response_content(message, true);
+        self.p_client.read().unwrap().send(event.room_id, "m.room.message", &content)
+    }
+
+    /// Respond in the thread `event` was sent in. Outside of threads this is
+    /// the same as `reply`.
+    pub fn reply_in_thread(&self, event: &RoomEvent, message: &str) -> Result<EventId> {
+        let content = event.response_content(message, false);
+        self.p_client.read().unwrap().send(event.room_id, "m.room.message", &content)
+    }
+
+    pub fn reply_image(&self, event: &RoomEvent, filename: &str, data: &[u8], content_type: &str) -> Result<EventId> {
+        self.p_client.read().unwrap().send_image(event.room_id, filename, data, content_type)
+    }
+
+    pub fn reply_file(&self, event: &RoomEvent, filename: &str, data: &[u8], content_type: &str) -> Result<EventId> {
+        self.p_client.read().unwrap().send_file(event.room_id, filename, data, content_type)
+    }
+
+    /// Replace the text of a message previously sent by the bot.
+    pub fn edit(&self, room_id: &str, event_id: &str, message: &str) -> Result<EventId> {
+        self.p_client.read().unwrap().edit_message(room_id, event_id, message)
     }
 
-    pub fn reply_action(&self, event: &RoomEvent, message: &str) -> Result<Response> {
-        self.p_client.write().unwrap().send_action(event.room_id, message)
+    pub fn react(&self, room_id: &str, event_id: &str, key: &str) -> Result<EventId> {
+        self.p_client.read().unwrap().react(room_id, event_id, key)
     }
 
-    pub fn uid_from_displayname(&self, name_query: &str) -> Result<String> {
-        let res = self.p_client.read().unwrap().get_directory(name_query, Some(10))?;
-        match res.results.first() {
-            Some(n) => Ok(n.user_id.clone()),
-            None => Err(Error::Generic("Empty".to_owned())),
+    pub fn redact(&self, room_id: &str, event_id: &str, reason: Option<&str>) -> Result<EventId> {
+        self.p_client.read().unwrap().redact(room_id, event_id, reason)
+    }
+
+    pub fn reply_fmt(&self, event: &RoomEvent, fmt_message: &str, message: &str) -> Result<EventId> {
+        self.p_client.read().unwrap().send_msg_fmt(event.room_id, fmt_message, message)
+    }
+
+    pub fn reply_action(&self, event: &RoomEvent, message: &str) -> Result<EventId> {
+        self.p_client.read().unwrap().send_action(event.room_id, message)
+    }
+
+    /// Send a message as a virtual user of the application service, given by
+    /// the localpart of its user id, which must be in the appservice's user
+    /// namespace. The user is registered and joins the room the first time,
+    /// so the room must let it join.
+    pub fn send_as(&self, localpart: &str, room_id: &str, message: &str) -> Result<EventId> {
+        let mut puppets = self.puppets.borrow_mut();
+        if !puppets.contains_key(localpart) {
+            let client = self.client().puppet(localpart)?;
+            puppets.insert(localpart.to_string(), Puppet { client, rooms: HashSet::new() });
+        }
+
+        let puppet = puppets.get_mut(localpart).unwrap();
+        if !puppet.rooms.contains(room_id) {
+            puppet.client.join(room_id, &[])?;
+            puppet.rooms.insert(room_id.to_string());
         }
+
+        let sent = puppet.client.send_msg(room_id, message);
+        if sent.is_err() {
+            // It may have been removed from the room, join again next time
+            puppet.rooms.remove(room_id);
+        }
+
+        sent
+    }
+
+    /// Send a message to a user in private, reusing the direct message room
+    /// with them if there already is one.
+    pub fn dm(&self, user_id: &str, message: &str) -> Result<EventId> {
+        let room_id = self.direct_room(user_id)?;
+        self.client().send_msg(&room_id, message)
     }
 
-    pub fn set_displayname(&mut self, name: &str) -> Result<Response> {
+    pub fn dm_fmt(&self, user_id: &str, fmt_message: &str, message: &str) -> Result<EventId> {
+        let room_id = self.direct_room(user_id)?;
+        self.client().send_msg_fmt(&room_id, fmt_message, message)
+    }
+
+    /// The direct message room with a user. It's only looked up the first
+    /// time, and again after the bot or the user left it.
+    fn direct_room(&self, user_id: &str) -> Result<String> {
+        if let Some(room_id) = self.room_state.borrow().direct_room(user_id) {
+            return Ok(room_id.to_string());
+        }
+
+        let room_id = self.client().get_direct_room(user_id)?;
+        self.room_state.borrow_mut().set_direct_room(user_id, &room_id);
+
+        Ok(room_id)
+    }
+
+    /// Reply to the sender of `event` in private instead of in the room.
+    pub fn reply_private(&self, event: &RoomEvent, message: &str) -> Result<EventId> {
+        self.dm(&event.raw_event.sender, message)
+    }
+
+    pub fn reply_private_fmt(&self, event: &RoomEvent, fmt_message: &str, message: &str) -> Result<EventId> {
+        self.dm_fmt(&event.raw_event.sender, fmt_message, message)
+    }
+
+    /// Work out which user is meant by `query` in the room `event` was sent
+    /// in. Full user ids, mention pills in the message, display names and then
+    /// localparts of the room members are tried in turn, falling back to the
+    /// user directory for users who aren't in the room. When no single user
+    /// matches the error is an `Error::Generic` explaining why, fit to be
+    /// shown to the sender.
+    pub fn resolve_user(&self, event: &RoomEvent, query: &str) -> Result<String> {
+        let query = query.trim();
+        if query.starts_with('@') && query.contains(':') {
+            return Ok(query.to_string());
+        }
+
+        let formatted = event.raw_event.content["formatted_body"].as_str().unwrap_or("");
+        if let Some((user_id, _)) = mention_pills(formatted).into_iter().find(|(_, text)| text == query) {
+            return Ok(user_id);
+        }
+
+        let localpart = query.trim_start_matches('@').to_lowercase();
+        let lowercase = query.to_lowercase();
+        let is_localpart = |user_id: &str| {
+            user_id[1..].split(':').next().is_some_and(|l| l.to_lowercase() == localpart)
+        };
+
+        let members = self.member_displaynames(event.room_id)?;
+        // Exact display names first, then ignoring case, then localparts
+        let matches = |tier, user_id: &str, name: Option<&str>| match tier {
+            0 => name == Some(query),
+            1 => name.is_some_and(|n| n.to_lowercase() == lowercase),
+            _ => is_localpart(user_id),
+        };
+
+        for tier in 0..3 {
+            let found: Vec<_> = members.iter()
+                                       .filter(|(id, name)| matches(tier, id, name.as_deref()))
+                                       .map(|(id, name)| (id.clone(), name.clone()))
+                                       .collect();
+            if !found.is_empty() {
+                return single_user(query, found);
+            }
+        }
+
+        let directory = self.client().get_directory(query, Some(10))?;
+        let found = directory.results.into_iter()
+                                     .filter(|p| p.display_name.as_deref() == Some(query) || is_localpart(&p.user_id))
+                                     .map(|p| (p.user_id, p.display_name))
+                                     .collect();
+
+        single_user(query, found)
+    }
+
+    /// Ids of the rooms the bot is joined to
+    pub fn joined_rooms(&self) -> Result<Vec<String>> {
+        if let Some(joined) = self.room_state.borrow().joined() {
+            return Ok(joined.iter().cloned().collect());
+        }
+
+        let joined = self.client().get_joined()?.joined_rooms;
+        self.room_state.borrow_mut().set_joined(joined.clone());
+
+        Ok(joined)
+    }
+
+    /// Look at the cached state of a room, loading it from the server the
+    /// first time it's needed.
+    fn with_room_state<T>(&self, room_id: &str, func: impl FnOnce(&RoomState) -> T) -> Result<T> {
+        if let Some(state) = self.room_state.borrow().get(room_id) {
+            return Ok(func(state));
+        }
+
+        let state = RoomState::from_events(&self.client().get_room_state(room_id)?);
+        let result = func(&state);
+        self.room_state.borrow_mut().insert(room_id, state);
+
+        Ok(result)
+    }
+
+    pub fn room_name(&self, room_id: &str) -> Result<Option<String>> {
+        self.with_room_state(room_id, |s| s.name.clone())
+    }
+
+    /// The room's canonical alias followed by its alternative aliases
+    pub fn room_aliases(&self, room_id: &str) -> Result<Vec<String>> {
+        self.with_room_state(room_id, |s| s.aliases().map(String::from).collect())
+    }
+
+    /// Ids of the users joined to the room
+    pub fn room_members(&self, room_id: &str) -> Result<Vec<String>> {
+        self.with_room_state(room_id, |s| s.joined_members().map(String::from).collect())
+    }
+
+    /// Display names of the users joined to the room, by user id
+    pub fn member_displaynames(&self, room_id: &str) -> Result<HashMap<String, Option<String>>> {
+        self.with_room_state(room_id, |s| {
+            s.members.iter()
+                     .filter(|(_, m)| m.membership == "join")
+                     .map(|(id, m)| (id.clone(), m.displayname.clone()))
+                     .collect()
+        })
+    }
+
+    pub fn member_displayname(&self, room_id: &str, user_id: &str) -> Result<Option<String>> {
+        self.with_room_state(room_id, |s| {
+            s.members.get(user_id).and_then(|m| m.displayname.clone())
+        })
+    }
+
+    /// The power levels in effect in the room, see `RoomState::levels`
+    pub fn power_levels(&self, room_id: &str) -> Result<PowerLevels> {
+        self.with_room_state(room_id, |s| s.levels())
+    }
+
+    pub fn power_level(&self, room_id: &str, user_id: &str) -> Result<i64> {
+        Ok(self.power_levels(room_id)?.user_level(user_id))
+    }
+
+    /// Whether the bot's power level is high enough for `action` in the room
+    pub fn can(&self, room_id: &str, action: Action) -> Result<bool> {
+        let levels = self.power_levels(room_id)?;
+        let required = match action {
+            Action::Kick => levels.kick,
+            Action::Ban => levels.ban,
+            Action::Redact => levels.redact,
+            Action::Invite => levels.invite,
+            Action::Send(t) => levels.events.get(t).copied().unwrap_or(levels.events_default),
+            Action::SetState(t) => levels.events.get(t).copied().unwrap_or(levels.state_default),
+        };
+
+        let own_level = levels.user_level(self.client().get_user_id().unwrap_or_default());
+
+        Ok(own_level >= required)
+    }
+
+    /// Check whether the bot can and should kick or ban `target` from the
+    /// room. Room moderators, anyone whose power level allows them to kick or
+    /// ban the same way, are never removed. The error explains the refusal to
+    /// users.
+    pub fn check_removal(&self, room_id: &str, mode: &RemovalMode, target: &str) -> result::Result<(), String> {
+        let levels = self.power_levels(room_id)
+                         .map_err(|e| format!("Unable to check power levels: {}", e))?;
+        let own_level = levels.user_level(self.client().get_user_id().unwrap_or_default());
+        let target_level = levels.user_level(target);
+        let moderator_level = match mode {
+            RemovalMode::Kick => levels.kick,
+            RemovalMode::Ban => levels.ban,
+        };
+
+        if !self.can(room_id, mode.into()).unwrap_or(false) {
+            Err(format!("I don't have permission to {} in this room", mode.as_str()))
+        } else if target_level >= moderator_level {
+            Err(format!("I won't {} {}, they are a room moderator", mode.as_str(), target))
+        } else if target_level >= own_level {
+            Err(format!("I can't {} {}, their power level is not below mine", mode.as_str(), target))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn set_displayname(&mut self, name: &str) -> Result<()> {
         self.display_name = name.to_string();
-        self.p_client.write().unwrap().set_displayname(name)
+        self.p_client.read().unwrap().set_displayname(name)
     }
 
     pub fn get_displayname(&self) -> &str {
         &self.display_name
     }
 
+    pub fn set_backlog(&mut self, backlog: Backlog) {
+        self.backlog = backlog;
+    }
+
+    /// Set how long the server may hold a sync request open while waiting for
+    /// new events. A zero duration disables long-polling.
+    pub fn set_sync_timeout(&mut self, timeout: Duration) {
+        self.sync_timeout = timeout;
+    }
+
+    /// Send the admins a direct message whenever a node panics
+    pub fn set_panic_admins(&mut self, admins: Vec<String>) {
+        self.panic_admins = admins;
+    }
+
     pub fn register_service(&mut self,
                             name: &'a str,
-                            parent: Option<&'a str>,
-                            mut service: Box<dyn Node<'a>>) -> Option<&'a str> {
+                            parent: Option<&str>,
+                            mut service: Box<dyn Node<'a> + 'a>) -> Option<&'a str> {
         match parent {
             Some(p) => self.all_services.get_mut(p).expect("Invalid parent node")
                            .borrow_mut().register_child(name),
@@ -127,16 +702,300 @@ impl<'a, 'c> Bot<'a, 'c> {
             println!("Encountered error when loading `{}` service: {}", name, e);
         }
 
+        for event_type in service.event_types() {
+            if self.event_types.insert(event_type.to_string()) {
+                self.filter_outdated = true;
+            }
+        }
+
         self.all_services.insert(name, RefCell::new(service));
 
         Some(name)
     }
 
+    /// Build the node graph described by `config`, including any changes made
+    /// at runtime by previous runs, and keep it so it can be changed further.
+    pub fn load_graph(&mut self, registry: Registry<'a>, config: Config, user_id: &str) -> result::Result<(), String> {
+        let topology = Topology::load(registry, config, user_id);
+        graph::build(self, &topology.registry, &topology.context(), &topology.nodes)?;
+        self.graph = Some(topology);
+
+        Ok(())
+    }
+
+    /// Stop or resume handing events to a node, and so to the nodes below it
+    pub fn set_node_enabled(&self, name: &str, enabled: bool) {
+        if enabled {
+            self.disabled_nodes.borrow_mut().remove(name);
+            self.node_panics.borrow_mut().remove(name);
+        } else {
+            self.disabled_nodes.borrow_mut().insert(name.to_string());
+        }
+    }
+
+    pub fn is_node_disabled(&self, name: &str) -> bool {
+        self.disabled_nodes.borrow().contains(name)
+    }
+
+    /// Type of a node of the graph loaded with `load_graph`
+    pub fn node_type(&self, name: &str) -> Option<&str> {
+        self.graph.as_ref()?.nodes.iter().find(|n| n.name == name).map(|n| n.type_name())
+    }
+
+    /// Change the node graph once the current event has been handled by every
+    /// node, reporting the outcome to `room_id`. The `issuer` node can't remove
+    /// or disable itself or the nodes above it, so it stays reachable.
+    pub fn edit_graph(&self, issuer: &str, room_id: &str, edit: GraphEdit) {
+        self.graph_edits.borrow_mut().push(PendingEdit {
+            edit,
+            issuer: issuer.to_string(),
+            room_id: room_id.to_string(),
+        });
+    }
+
+    fn apply_graph_edits(&mut self) {
+        let edits = self.graph_edits.take();
+        for PendingEdit { edit, issuer, room_id } in edits {
+            let Some(mut topology) = self.graph.take() else {
+                self.client().send_msg(&room_id, "The node graph can't be changed at runtime").ok();
+                continue;
+            };
+
+            let outcome = self.apply_graph_edit(&mut topology, &issuer, edit);
+            if outcome.is_ok() {
+                topology.changes.save();
+            }
+            self.graph = Some(topology);
+
+            let message = outcome.unwrap_or_else(|e| e);
+            self.client().send_msg(&room_id, &message).ok();
+        }
+    }
+
+    fn apply_graph_edit(&mut self, topology: &mut Topology<'a>, issuer: &str, edit: GraphEdit) -> result::Result<String, String> {
+        let exists = |name: &str| {
+            if self.all_services.contains_key(name) {
+                Ok(())
+            } else {
+                Err(format!("There is no node named `{}`", name))
+            }
+        };
+
+        let takes_children = |name: &str| {
+            if self.takes_children(name) {
+                Ok(())
+            } else {
+                Err(format!("`{}` takes no nodes under it", name))
+            }
+        };
+
+        let protected = |name: &str| {
+            if name == issuer || graph::ancestors(&topology.nodes, issuer).contains(&name) {
+                Err(format!("`{}` is needed to reach `{}`", name, issuer))
+            } else {
+                Ok(())
+            }
+        };
+
+        let enable = matches!(edit, GraphEdit::Enable(_));
+        match edit {
+            GraphEdit::Add { type_name, name, parent } => {
+                if exists(&name).is_ok() {
+                    return Err(format!("There already is a node named `{}`", name));
+                }
+                exists(&parent)?;
+                takes_children(&parent)?;
+
+                let spec = NodeSpec::new(&name, &type_name, Some(&parent));
+                let node = topology.registry.construct(&topology.context(), &spec)?;
+                self.register_service(graph::node_name(&name), Some(&parent), node);
+
+                topology.nodes.push(spec.clone());
+                topology.changes.set(spec);
+
+                Ok(format!("Added `{}` under `{}`", name, parent))
+            },
+            GraphEdit::Remove(name) => {
+                exists(&name)?;
+                protected(&name)?;
+
+                let has_children = self.get_service(&name)
+                                       .is_some_and(|n| n.children().is_some_and(|c| !c.is_empty()));
+                if has_children {
+                    return Err(format!("`{}` still has nodes under it, move or remove them first", name));
+                }
+
+                self.detach(topology, &name);
+                if let Some(node) = self.all_services.remove(name.as_str()) {
+                    node.borrow().on_exit(&name);
+                }
+                self.set_node_enabled(&name, true);
+                self.refresh_event_types();
+
+                topology.nodes.retain(|n| n.name != name);
+                let configured = topology.is_configured(&name);
+                topology.changes.remove(&name, configured);
+
+                Ok(format!("Removed `{}`", name))
+            },
+            GraphEdit::Move { name, parent } => {
+                exists(&name)?;
+                exists(&parent)?;
+                takes_children(&parent)?;
+                if name == parent || graph::ancestors(&topology.nodes, &parent).contains(&name.as_str()) {
+                    return Err(format!("`{}` can't be moved under itself", name));
+                }
+
+                self.detach(topology, &name);
+                let (&key, _) = self.all_services.get_key_value(name.as_str()).unwrap();
+                self.all_services[parent.as_str()].borrow_mut().register_child(key);
+
+                let spec = topology.spec_mut(&name).ok_or("Node has no spec")?;
+                spec.parent = Some(parent.clone());
+                let spec = spec.clone();
+                topology.changes.set(spec);
+
+                Ok(format!("Moved `{}` under `{}`", name, parent))
+            },
+            GraphEdit::Enable(name) | GraphEdit::Disable(name) => {
+                exists(&name)?;
+                if !enable {
+                    protected(&name)?;
+                }
+
+                self.set_node_enabled(&name, enable);
+
+                let spec = topology.spec_mut(&name).ok_or("Node has no spec")?;
+                spec.enabled = enable;
+                let spec = spec.clone();
+                topology.changes.set(spec);
+
+                Ok(format!("{} `{}`", if enable { "Enabled" } else { "Disabled" }, name))
+            },
+        }
+    }
+
+    /// Re-read the config file once the current event has been handled by
+    /// every node, reporting the outcome to `room_id`
+    pub fn reload_config(&self, room_id: &str) {
+        self.config_reloads.borrow_mut().push(room_id.to_string());
+    }
+
+    fn apply_config_reloads(&mut self) {
+        let rooms = self.config_reloads.take();
+        for room_id in rooms {
+            let message = match config::read_config(CONFIG_FILE) {
+                Ok(config) => self.update_config(config),
+                Err(e) => format!("Unable to reload {}: {}", CONFIG_FILE, e),
+            };
+
+            self.client().send_msg(&room_id, &message).ok();
+        }
+    }
+
+    /// Switch to the settings of a changed config, both the bot's own and
+    /// those of running nodes, which get them through `Node::reload_config`.
+    /// Returns a report of the changes which need a restart or were refused.
+    pub fn update_config(&mut self, config: Config) -> String {
+        let Some(mut topology) = self.graph.take() else {
+            return "The config can't be reloaded without a node graph".to_string();
+        };
+        let old = &topology.config;
+        let mut notes = Vec::new();
+
+        let mut connection = config.connection.clone();
+        connection.sync_timeout = old.connection.sync_timeout;
+        if connection != old.connection || config.appservice != old.appservice {
+            notes.push("Connection changes take effect on restart".to_string());
+        }
+
+        if config.bot.display_name != old.bot.display_name {
+            if let Err(e) = self.set_displayname(&config.bot.display_name) {
+                notes.push(format!("Unable to change the display name: {}", e));
+            }
+        }
+
+        for room in config.bot.rooms.iter().filter(|r| !old.bot.rooms.contains(r)) {
+            if let Err(e) = self.join_room(room, &[]) {
+                notes.push(format!("Unable to join {}: {}", room, e));
+            }
+        }
+
+        self.set_backlog(config.bot.backlog);
+        self.set_sync_timeout(config.connection.sync_timeout.map_or(DEFAULT_SYNC_TIMEOUT, Duration::from_secs));
+        self.set_panic_admins(if config.bot.notify_panics { config.bot.admins.clone() } else { Vec::new() });
+
+        // Nodes are only rebuilt on restart, running ones just get new settings
+        let mut nodes = graph::resolve(&config);
+        topology.changes.apply(&mut nodes);
+
+        let shape = |nodes: &[NodeSpec]| -> HashSet<(String, String, Option<String>, bool)> {
+            nodes.iter()
+                 .map(|n| (n.name.clone(), n.type_name().to_string(), n.parent.clone(), n.enabled))
+                 .collect()
+        };
+        if shape(&nodes) != shape(&topology.nodes) {
+            notes.push("Changes to the node graph take effect on restart".to_string());
+        }
+
+        // Nodes may take settings from the [bot] and [services] sections
+        let shared_changed = config.bot != old.bot || config.services != old.services;
+        let ctx = Context {
+            config: &config,
+            user_id: &topology.user_id,
+        };
+
+        for spec in &mut topology.nodes {
+            let Some(new_spec) = nodes.iter().find(|n| n.name == spec.name && n.type_name() == spec.type_name()) else {
+                continue;
+            };
+            if new_spec.config == spec.config && !shared_changed {
+                continue;
+            }
+
+            let reloaded = match self.get_service(&spec.name) {
+                Some(mut node) => node.reload_config(&ctx, &new_spec.config),
+                None => Ok(()),
+            };
+            match reloaded {
+                Ok(()) => spec.config = new_spec.config.clone(),
+                Err(e) => notes.push(format!("`{}` kept its old settings: {}", spec.name, e)),
+            }
+        }
+
+        topology.config = config;
+        self.graph = Some(topology);
+
+        if notes.is_empty() {
+            format!("Reloaded {}", CONFIG_FILE)
+        } else {
+            format!("Reloaded {}, but:\n{}", CONFIG_FILE, notes.join("\n"))
+        }
+    }
+
+    /// Take a node out from under its parent
+    fn detach(&mut self, topology: &Topology, name: &str) {
+        let parent = topology.nodes.iter().find(|n| n.name == name).and_then(|n| n.parent.as_deref());
+        match parent {
+            Some(p) => {
+                if let Some(mut parent) = self.get_service(p) {
+                    parent.unregister_child(name);
+                }
+            },
+            None => self.root_services.retain(|n| *n != name),
+        }
+    }
+
     // TODO: This should be a Result and use ? instead of .unwrap()
-    pub fn get_service(&self, name: &str) -> Option<RefMut<Box<dyn Node<'a>>>> {
+    pub fn get_service(&self, name: &str) -> Option<RefMut<'_, Box<dyn Node<'a> + 'a>>> {
         Some(self.all_services.get(name)?.borrow_mut())
     }
 
+    /// Whether a node can have nodes under it
+    pub fn takes_children(&self, name: &str) -> bool {
+        self.all_services.get(name).is_some_and(|n| n.borrow().children().is_some())
+    }
+
     pub fn get_service_names(&self) -> Vec<&str> {
         let keys = self.all_services.keys();
         keys.copied().collect()
@@ -146,38 +1005,117 @@ impl<'a, 'c> Bot<'a, 'c> {
         &self.root_services
     }
 
-    // Two stage query all method
-    pub fn delay_service_query<T: Fn(&Bot, &mut dyn Node) -> Box<dyn Any> + 'c>(&self, node: &'c str, target: Option<String>, func: T) {
-        self.delayed_queries.borrow_mut().insert(node, Query{target, func: Box::new(func)});
+    /// Call `func` with the node named `name`. A node can't be reached while
+    /// it's handling an event, as is the case for the calling node and those
+    /// above it, so reaching those has to wait for `after_event`.
+    pub fn with_node<T>(&self, name: &str, func: impl FnOnce(&mut (dyn Node<'a> + 'a)) -> T) -> result::Result<T, String> {
+        let node = self.all_services.get(name).ok_or_else(|| format!("There is no node named `{}`", name))?;
+        let mut node = node.try_borrow_mut().map_err(|_| format!("`{}` is busy handling an event", name))?;
+
+        Ok(func(&mut **node))
     }
 
-    fn process_delayed_queries(&mut self) {
-        for (query_service_name, Query{target, func}) in self.delayed_queries.borrow().iter() {
-            let mut results: Vec<(&str, Box<dyn Any>)> = Vec::new();
-            if let Some(t) = target {
-                if let Some(mut service) = self.get_service(t) {
-                    results.push((t, func(self, &mut **service)));
-                }
-            } else {
-                for (service_name, service) in &self.all_services {
-                    results.push((service_name, func(self, &mut **service.borrow_mut())));
-                }
+    /// Every node reachable from the root nodes, each before its children.
+    /// Fails while any of them is busy, see `with_node`.
+    pub fn inspect_graph(&self) -> result::Result<Vec<NodeInfo>, String> {
+        let mut nodes = Vec::new();
+        let mut stack: Vec<(String, Option<String>)> = self.root_services.iter().rev()
+                                                           .map(|r| (r.to_string(), None))
+                                                           .collect();
+
+        while let Some((name, parent)) = stack.pop() {
+            let info = self.with_node(&name, |n| NodeInfo {
+                name: name.clone(),
+                parent,
+                type_name: self.node_type(&name).map(String::from),
+                children: n.children().into_iter().flatten().map(|c| c.to_string()).collect(),
+                description: n.description(),
+                status: n.status(),
+                disabled: self.is_node_disabled(&name),
+            })?;
+
+            stack.extend(info.children.iter().rev().map(|c| (c.clone(), Some(name.clone()))));
+            nodes.push(info);
+        }
+
+        Ok(nodes)
+    }
+
+    /// Run `func` once the current event has been handled by every node. No
+    /// node is busy then, so `func` can reach any of them with `with_node`,
+    /// e.g. to look at the nodes above the caller.
+    pub fn after_event(&self, func: impl FnOnce(&Bot) + 'c) {
+        self.after_event.borrow_mut().push(Box::new(func));
+    }
+
+    /// Run the work put off with `after_event`, including any put off by it
+    fn run_after_event(&self) {
+        loop {
+            let funcs = self.after_event.take();
+            if funcs.is_empty() {
+                return;
             }
 
-            // Get the service
-            if let Some(mut service) = self.get_service(query_service_name) {
-                service.recieve_all_node_post(self, results);
+            for func in funcs {
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| func(self))) {
+                    println!("Work put off until after an event panicked: {}", panic_message(&*payload));
+                }
             }
         }
-
-        self.delayed_queries = RefCell::new(HashMap::new());
     }
-    // end
 
     pub fn propagate_event(&self, event: &RoomEvent) {
         for service in &self.root_services {
-            self.all_services.get(service).unwrap()
-                .borrow_mut().handle(self, event.clone());
+            self.handle_node(service, event);
+        }
+
+        self.run_after_event();
+    }
+
+    /// Hand an event to a single node. A panic in the node, or in any node
+    /// below it, is caught so it can't take down the bot. Nodes which keep
+    /// panicking are disabled.
+    pub fn handle_node(&self, name: &str, event: &RoomEvent) {
+        if self.disabled_nodes.borrow().contains(name) {
+            return;
+        }
+
+        let Some(service) = self.all_services.get(name) else {
+            return
+        };
+
+        let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+            service.borrow_mut().handle(self, event.clone());
+        }));
+
+        if let Err(payload) = handled {
+            self.node_panicked(name, event, panic_message(&*payload));
+        }
+    }
+
+    fn node_panicked(&self, name: &str, event: &RoomEvent, message: &str) {
+        let count = {
+            let mut panics = self.node_panics.borrow_mut();
+            let count = panics.entry(name.to_string()).or_insert(0);
+            *count += 1;
+            *count
+        };
+
+        let mut notice = format!("Node `{}` panicked handling {} from {} in {}: {}",
+                                 name, event.raw_event.event_id.as_deref().unwrap_or("an event"),
+                                 event.raw_event.sender, event.room_id, message);
+
+        if count >= MAX_NODE_PANICS {
+            self.disabled_nodes.borrow_mut().insert(name.to_string());
+            notice += &format!("\nIt has panicked {} times and is now disabled.", count);
+        }
+
+        println!("{}", notice);
+
+        for admin in &self.panic_admins {
+            if let Err(e) = self.dm(admin, &notice) {
+                println!("Unable to tell {} about the panic: {:?}", admin, e);
+            }
         }
     }
 
@@ -187,64 +1125,293 @@ impl<'a, 'c> Bot<'a, 'c> {
         }
     }
 
-    fn handle_event_source<T: EventContainer>(&self, events: Option<HashMap<String, T>>, source: &str) {
+    fn handle_event_source<T: EventContainer>(&self, events: Option<HashMap<String, T>>, source: &str, min_ts: Option<u64>) {
         let Some(room_events) = events else {
             return
         };
 
         for (room_id, room) in room_events {
             for raw_event in room.get_events() {
-                self.propagate_event(
-                    &RoomEvent {
-                        room_id: &room_id,
-                        from: source,
-                        raw_event: raw_event.clone(),
+                // Events without a timestamp (e.g. stripped invite state) are never stale
+                if let (Some(min), Some(ts)) = (min_ts, raw_event.origin_server_ts) {
+                    if ts < min {
+                        continue;
                     }
-                );
+                }
+
+                self.dispatch_event(&room_id, source, raw_event);
             }
         }
     }
 
-    pub fn run(&mut self, exit_flag: &Arc<AtomicBool>) {
-        let mut next_batch: String = self.p_client.read().unwrap().sync(None).unwrap().next_batch;
+    /// Hand an event to the nodes, if it is of a type they handle
+    fn dispatch_event(&self, room_id: &str, source: &str, raw_event: &Event) {
+        if !self.event_types.contains(&raw_event.type_) {
+            return;
+        }
 
-        let delay = Duration::from_millis(500);
+        let mut raw_event = raw_event.clone();
+        normalize_edit(&mut raw_event);
 
-        while !exit_flag.load(Ordering::Relaxed) {
-            let sync = self.p_client.read().unwrap().sync(Some(&next_batch));
-            match sync {
+        self.propagate_event(
+            &RoomEvent {
+                room_id,
+                from: source,
+                raw_event,
+            }
+        );
+    }
+
+    fn handle_transaction(&mut self, events: &[TransactionEvent]) {
+        let own_user_id = self.client().get_user_id().unwrap_or_default().to_string();
+
+        for TransactionEvent { room_id, event } in events {
+            self.room_state.borrow_mut().apply(room_id, event, &own_user_id);
+
+            // Tell invites of the bot apart the same way syncing does
+            let invited = event.type_ == "m.room.member" &&
+                          event.state_key.as_deref() == Some(own_user_id.as_str()) &&
+                          event.content["membership"] == "invite";
+            let source = if invited { "invite" } else { "join" };
+
+            self.dispatch_event(room_id, source, event);
+        }
+
+        self.apply_graph_edits();
+        self.apply_config_reloads();
+    }
+
+    fn handle_sync(&mut self, sync_data: MatrixSync, min_ts: Option<u64>) -> String {
+        if let Some(rooms) = sync_data.rooms {
+            self.room_state.borrow_mut().update(&rooms);
+
+            self.handle_event_source(rooms.join,   "join",   min_ts);
+            self.handle_event_source(rooms.invite, "invite", min_ts);
+            self.handle_event_source(rooms.leave,  "leave",  min_ts);
+        }
+
+        self.apply_graph_edits();
+        self.apply_config_reloads();
+
+        // Nodes added meanwhile may need more event types
+        if self.filter_outdated && self.sync_filter.is_some() {
+            self.setup_sync_filter();
+        }
+
+        state::save_state(SYNC_TOKEN_STATE, &sync_data.next_batch);
+
+        sync_data.next_batch
+    }
+
+    /// Recompute the event types handed to the nodes from the nodes left in
+    /// the graph, so that removed nodes don't keep them in the sync filter.
+    fn refresh_event_types(&mut self) {
+        let mut types: BTreeSet<String> = SYNC_EVENT_TYPES.iter().map(|t| t.to_string()).collect();
+        for service in self.all_services.values() {
+            types.extend(service.borrow().event_types().iter().map(|t| t.to_string()));
+        }
+
+        if types != self.event_types {
+            self.event_types = types;
+            self.filter_outdated = true;
+        }
+    }
+
+    /// Upload the filter limiting sync responses to what the node graph needs.
+    /// If the server won't store it, the filter is sent inline with every sync.
+    fn setup_sync_filter(&mut self) {
+        let types: Vec<&str> = self.event_types.iter()
+                                   .map(String::as_str)
+                                   .chain(STATE_EVENT_TYPES.iter().copied())
+                                   .collect();
+        let filter = json!({
+            "presence": { "not_types": ["*"] },
+            "account_data": { "not_types": ["*"] },
+            "room": {
+                "timeline": { "types": types, "lazy_load_members": true },
+                "state": { "types": types, "lazy_load_members": true },
+                "ephemeral": { "not_types": ["*"] },
+                "account_data": { "not_types": ["*"] },
+            },
+        });
+
+        let uploaded = self.p_client.read().unwrap().upload_filter(&filter);
+        self.sync_filter = match uploaded {
+            Ok(id) => Some(id),
+            Err(e) => {
+                println!("Unable to upload sync filter, sending it inline: {:?}", e);
+                Some(filter.to_string())
+            },
+        };
+        self.filter_outdated = false;
+    }
+
+    fn sync(&self, since: Option<&str>) -> Result<MatrixSync> {
+        // Long-polling makes no sense without a since token, the server
+        // responds immediately anyway.
+        let timeout = match since {
+            Some(_) if !self.sync_timeout.is_zero() => match self.workers.pending() {
+                0 => Some(self.sync_timeout),
+                _ => Some(self.sync_timeout.min(JOB_POLL_TIMEOUT)),
+            },
+            _ => None,
+        };
+
+        // Only build the request with the client locked, the long-poll can
+        // take a while and the client is needed meanwhile by worker threads
+        let request = self.client().sync_request(since, self.sync_filter.as_deref(), timeout)?;
+        let mut sync_data = request.send()?;
+        self.client().process_sync(&mut sync_data);
+
+        Ok(sync_data)
+    }
+
+    /// Sync once and hand the new events to the nodes, returning the token
+    /// for the next sync.
+    pub(crate) fn sync_once(&mut self, since: &str) -> Result<String> {
+        let sync_data = self.sync(Some(since))?;
+
+        Ok(self.handle_sync(sync_data, None))
+    }
+
+    /// Perform the first sync of a run. When a sync token was saved by a
+    /// previous run, the events missed in the meantime are processed according
+    /// to the configured backlog policy. Otherwise there is nothing to catch up
+    /// on and the initial batch is only used for its token.
+    fn initial_sync(&mut self) -> String {
+        if let Some(saved) = state::load_state(SYNC_TOKEN_STATE) {
+            match self.sync(Some(saved.trim())) {
                 Ok(sync_data) => {
-                    if let Some(rooms) = sync_data.rooms {
-                        self.handle_event_source(rooms.join,   "join");
-                        self.handle_event_source(rooms.invite, "invite");
-                        self.handle_event_source(rooms.leave,  "leave");
-                    }
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                                               .map_or(0, |d| d.as_millis() as u64);
+                    let min_ts = match self.backlog {
+                        Backlog::All => None,
+                        Backlog::None => Some(now),
+                        Backlog::Minutes(m) => Some(now.saturating_sub(m * 60 * 1000)),
+                    };
+
+                    println!("Resuming from saved sync token (backlog: {:?})", self.backlog);
+                    return self.handle_sync(sync_data, min_ts);
+                },
+                Err(e) => println!("Unable to resume from saved sync token: {:?}", e),
+            }
+        }
 
-                    self.process_delayed_queries();
+        let next_batch = self.sync(None).unwrap().next_batch;
+        state::save_state(SYNC_TOKEN_STATE, &next_batch);
+
+        next_batch
+    }
+
+    pub fn run(&mut self, exit_flag: &Arc<AtomicBool>) {
+        self.setup_sync_filter();
+
+        let mut next_batch = self.initial_sync();
+
+        // Only used to pace requests when not long-polling or after errors
+        let delay = Duration::from_millis(500);
 
-                    next_batch = sync_data.next_batch;
+        while !exit_flag.load(Ordering::Relaxed) {
+            let synced = match self.sync_once(&next_batch) {
+                Ok(next) => {
+                    next_batch = next;
+                    true
                 },
                 Err(Error::Reqwest(e)) if e.is_timeout() => {
                     match e.url() {
                         Some(url) => println!("Request timed out for {}", url),
                         None => println!("Request timed out"),
                     }
+                    false
                 }
-                Err(Error::Reqwest(e)) => println!("ReqwestError: {:?}", e),
+                Err(Error::Reqwest(e)) => {
+                    println!("ReqwestError: {:?}", e);
+                    false
+                },
                 Err(e) => {
                     println!("Error: {:?}", e);
+                    false
                 }
+            };
+
+            self.process_completions(Duration::ZERO);
+
+            if !synced || self.sync_timeout.is_zero() {
+                thread::sleep(delay);
             }
+        }
 
-            thread::sleep(delay);
+        println!("Allowing services to exit cleanly...");
+        self.finish_jobs(EXIT_JOB_TIMEOUT);
+        self.on_exit();
+    }
+
+    /// Run as an application service, handling the events the homeserver
+    /// pushes to the listener instead of syncing.
+    pub fn run_appservice(&mut self, appservice: &AppService, exit_flag: &Arc<AtomicBool>) {
+        let mut listener = Listener::bind(appservice).expect("Unable to start the appservice listener");
+        println!("Listening for appservice transactions on {}", appservice.bind);
+
+        while !exit_flag.load(Ordering::Relaxed) {
+            if let Some(transaction) = listener.next_transaction(Duration::from_millis(500)) {
+                self.handle_transaction(&transaction.events);
+                listener.ack(transaction);
+            }
+
+            self.process_completions(Duration::ZERO);
         }
 
         println!("Allowing services to exit cleanly...");
+        self.finish_jobs(EXIT_JOB_TIMEOUT);
         self.on_exit();
     }
 }
 
 
+/// The message a panic was raised with
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload.downcast_ref::<&str>().copied()
+           .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+           .unwrap_or("unknown panic")
+}
+
+
+/// Pick the only user found for `query`, or explain why that's not possible.
+fn single_user(query: &str, mut found: Vec<(String, Option<String>)>) -> Result<String> {
+    match found.len() {
+        0 => Err(Error::Generic(format!("Unable to find a user matching '{}'", query))),
+        1 => Ok(found.remove(0).0),
+        _ => {
+            found.sort();
+            let candidates: Vec<String> = found.into_iter().map(|(id, name)| match name {
+                Some(name) => format!("{} ({})", id, name),
+                None => id,
+            }).collect();
+
+            Err(Error::Generic(format!("'{}' could be any of {}, please use their full user id",
+                                       query, candidates.join(", "))))
+        },
+    }
+}
+
+
+/// Edits carry the new message as `m.new_content` and a `*` marked fallback as
+/// the body. Swap in the new content so nodes see the edited message as is,
+/// keeping the relation to tell it apart from a new message.
+fn normalize_edit(event: &mut Event) {
+    let relation = &event.content["m.relates_to"];
+    if event.type_ != "m.room.message" || relation["rel_type"] != "m.replace" {
+        return;
+    }
+
+    let relation = relation.clone();
+    if let Some(Value::Object(mut new_content)) = event.content.get("m.new_content").cloned() {
+        new_content.insert("m.relates_to".to_string(), relation);
+        event.content = Value::Object(new_content);
+    }
+}
+
+
 pub trait Node<'a> {
     fn description(&self) -> Option<String> {
         None
@@ -254,16 +1421,25 @@ pub trait Node<'a> {
         None
     }
 
+    /// Event types the node needs besides the ones every node gets, such as
+    /// messages and membership changes. They only reach the node if every
+    /// node above it passes them on.
+    fn event_types(&self) -> &[&str] {
+        &[]
+    }
+
     #[allow(unused_variables)]
     fn register_child(&mut self, name: &'a str) {
     }
 
+    #[allow(unused_variables)]
+    fn unregister_child(&mut self, name: &str) {
+    }
+
     fn propagate_event(&self, bot: &Bot, event: &RoomEvent) {
         if let Some(children) = self.children() {
             for child in children {
-                if let Some(mut service) = bot.get_service(child) {
-                    service.handle(bot, event.clone());
-                }
+                bot.handle_node(child, event);
             }
         }
     }
@@ -272,10 +1448,6 @@ pub trait Node<'a> {
         self.propagate_event(bot, &event);
     }
 
-    #[allow(unused_variables)]
-    fn recieve_all_node_post(&mut self, bot: &Bot, result: Vec<(&str, Box<dyn Any>)>) {
-    }
-
     #[allow(unused_variables)]
     fn on_load(&mut self, service_name: &str) -> result::Result<(), String> {
         Ok(())
@@ -284,10 +1456,464 @@ pub trait Node<'a> {
     #[allow(unused_variables)]
     fn on_exit(&self, service_name: &str) { }
 
+    /// Take on the settings of a reloaded config. `config` is the node's own
+    /// config from the graph, as its constructor got it.
+    #[allow(unused_variables)]
+    fn reload_config(&mut self, ctx: &Context, config: &Table) -> result::Result<(), String> {
+        Ok(())
+    }
+
     #[allow(unused_variables)]
     fn configure(&mut self, bot: &Bot, command: &str, event: RoomEvent) { }
 
+    /// A short summary of the node's settings, such as the users a filter
+    /// lets through
+    fn status(&self) -> Option<String> {
+        None
+    }
+
     fn configure_description(&self) -> Option<String> {
         None
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::filters::ReactionFilter;
+    use crate::services::{echo::Echo, help::Help, prefix::Prefix};
+    use crate::test_support::*;
+
+    fn member(user_id: &str, displayname: &str) -> Value {
+        state_event("m.room.member", user_id, json!({ "membership": "join", "displayname": displayname }))
+    }
+
+    #[test]
+    fn synced_commands_reach_nodes() {
+        let _state = test_state();
+        let mock = MockServer::start();
+        let mut bot = Bot::new(mock.client());
+        let pf = bot.register_service("prefix", None, Box::new(Prefix::new("!".to_string(), false)));
+        bot.register_service("echo", pf, Box::new(Echo::new()));
+
+        let mut edit = message_event(TEST_SENDER, "* !echo edited");
+        edit.content["m.new_content"] = json!({ "msgtype": "m.text", "body": "!echo edited" });
+        edit.content["m.relates_to"] = json!({ "rel_type": "m.replace", "event_id": "$original" });
+
+        mock.queue_sync(sync_response("s1", &[
+            message_event(TEST_SENDER, "!echo hello"),
+            message_event(TEST_SENDER, "echo without prefix"),
+            edit,
+        ]));
+
+        assert_eq!(bot.sync_once("s0").unwrap(), "s1");
+        assert_eq!(mock.replies(), ["hello"]);
+    }
+
+    /// Records what it can reach of the graph while handling an event, and
+    /// once the event has been handled
+    struct Peek {
+        seen: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl<'a> Node<'a> for Peek {
+        fn handle(&mut self, bot: &Bot, event: RoomEvent) {
+            if event.body() != Some("peek") {
+                return;
+            }
+
+            let reach = |bot: &Bot, name: &str| {
+                bot.with_node(name, |_| format!("{} reached", name)).unwrap_or_else(|e| e)
+            };
+            for name in ["prefix", "peek", "echo", "nowhere"] {
+                self.seen.borrow_mut().push(reach(bot, name));
+            }
+
+            let seen = Rc::clone(&self.seen);
+            bot.after_event(move |bot| seen.borrow_mut().push(reach(bot, "prefix")));
+        }
+    }
+
+    #[test]
+    fn nodes_reach_each_other_once_free() {
+        let _state = test_state();
+        let mock = MockServer::start();
+        let mut bot = Bot::new(mock.client());
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let pf = bot.register_service("prefix", None, Box::new(Prefix::new("!".to_string(), false)));
+        bot.register_service("peek", pf, Box::new(Peek { seen: Rc::clone(&seen) }));
+        bot.register_service("echo", pf, Box::new(Echo::new()));
+        bot.register_service("help", pf, Box::new(Help::new()));
+
+        bot.propagate_event(&text("!peek"));
+        assert_eq!(*seen.borrow(), [
+            "`prefix` is busy handling an event",
+            "`peek` is busy handling an event",
+            "echo reached",
+            "There is no node named `nowhere`",
+            "prefix reached",
+        ]);
+
+        // Requests from different rooms handled in one sync each get answered
+        let other_room = "!other:mock.server";
+        let mut sync = sync_response("s1", &[message_event(TEST_SENDER, "!help echo")]);
+        sync["rooms"]["join"][other_room] = sync["rooms"]["join"][TEST_ROOM].clone();
+        sync["rooms"]["join"][other_room]["timeline"]["events"] = json!([message_event(TEST_SENDER, "!help nowhere")]);
+        mock.queue_sync(sync);
+        bot.sync_once("s0").unwrap();
+
+        let replies: Vec<_> = mock.sent().into_iter()
+                                  .map(|e| (e.room_id, e.content["body"].as_str().unwrap_or_default().to_string()))
+                                  .collect();
+        assert_eq!(replies.len(), 2);
+        assert!(replies.contains(&(TEST_ROOM.to_string(), "echo <any message> - Replys with the argument passed.".to_string())));
+        assert!(replies.contains(&(other_room.to_string(), "No help found.".to_string())));
+    }
+
+    /// Records the types of the events it gets
+    struct Tally {
+        types: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl<'a> Node<'a> for Tally {
+        fn handle(&mut self, _: &Bot, event: RoomEvent) {
+            self.types.borrow_mut().push(event.raw_event.type_);
+        }
+    }
+
+    #[test]
+    fn nodes_get_the_event_types_they_ask_for() {
+        let _state = test_state();
+        let mock = MockServer::start();
+        let mut bot = Bot::new(mock.client());
+        let types = Rc::new(RefCell::new(Vec::new()));
+        bot.register_service("tally", None, Box::new(Tally { types: Rc::clone(&types) }));
+
+        let mut reaction = message_event(TEST_SENDER, "");
+        reaction.type_ = "m.reaction".to_string();
+        reaction.content = json!({ "m.relates_to": { "rel_type": "m.annotation", "event_id": "$x", "key": "+1" } });
+        let events = [message_event(TEST_SENDER, "hi"), reaction];
+
+        mock.queue_sync(sync_response("s1", &events));
+        bot.sync_once("s0").unwrap();
+        assert_eq!(*types.borrow(), ["m.room.message"]);
+
+        // Nobody asked for reactions until now
+        bot.register_service("reactions", None, Box::new(ReactionFilter::new(Vec::new())));
+        mock.queue_sync(sync_response("s2", &events));
+        bot.sync_once("s1").unwrap();
+        assert_eq!(*types.borrow(), ["m.room.message", "m.room.message", "m.reaction"]);
+    }
+
+    /// Replies once it's told to, from a worker thread
+    struct Slow {
+        go: Option<mpsc::Receiver<()>>,
+    }
+
+    impl<'a> Node<'a> for Slow {
+        fn handle(&mut self, bot: &Bot, event: RoomEvent) {
+            if let Some(go) = self.go.take() {
+                bot.spawn(&event, move || go.recv().is_ok(), |bot, event, went| {
+                    if went {
+                        bot.reply(event, "done").ok();
+                    }
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn slow_jobs_dont_block_nodes() {
+        let _state = test_state();
+        let mock = MockServer::start();
+        let mut bot = Bot::new(mock.client());
+        let (go, wait) = mpsc::channel();
+        let pf = bot.register_service("prefix", None, Box::new(Prefix::new("!".to_string(), false)));
+        bot.register_service("slow", None, Box::new(Slow { go: Some(wait) }));
+        bot.register_service("echo", pf, Box::new(Echo::new()));
+
+        mock.queue_sync(sync_response("s1", &[
+            message_event(TEST_SENDER, "slow"),
+            message_event(TEST_SENDER, "!echo fast"),
+        ]));
+
+        bot.sync_once("s0").unwrap();
+        assert_eq!(mock.replies(), ["fast"]);
+
+        go.send(()).unwrap();
+        bot.finish_jobs(Duration::from_secs(5));
+        assert_eq!(mock.replies(), ["fast", "done"]);
+    }
+
+    /// Panics on every event it gets, counting them
+    struct Panicky {
+        calls: Rc<Cell<u32>>,
+    }
+
+    impl<'a> Node<'a> for Panicky {
+        fn handle(&mut self, _: &Bot, _: RoomEvent) {
+            self.calls.set(self.calls.get() + 1);
+            panic!("boom");
+        }
+    }
+
+    #[test]
+    fn panicking_nodes_are_isolated_then_disabled() {
+        let _state = test_state();
+        let mock = MockServer::start();
+        let mut bot = Bot::new(mock.client());
+        let calls = Rc::new(Cell::new(0));
+        let pf = bot.register_service("prefix", None, Box::new(Prefix::new("!".to_string(), false)));
+        bot.register_service("panicky", pf, Box::new(Panicky { calls: Rc::clone(&calls) }));
+        bot.register_service("echo", pf, Box::new(Echo::new()));
+
+        for i in 0..MAX_NODE_PANICS + 1 {
+            mock.queue_sync(sync_response("s1", &[message_event(TEST_SENDER, &format!("!echo {}", i))]));
+            bot.sync_once("s0").unwrap();
+        }
+
+        assert_eq!(mock.replies(), ["0", "1", "2", "3"]);
+        assert_eq!(calls.get(), MAX_NODE_PANICS);
+    }
+
+    #[test]
+    fn join_room_resolves_aliases() {
+        let mock = MockServer::start();
+        mock.respond("GET", "/directory/room/%23rust:other.server", 200, json!({
+            "room_id": "!rust:other.server",
+            "servers": ["other.server", "third.server"],
+        }));
+        mock.respond("POST", "/join/!rust:other.server", 200, json!({ "room_id": "!rust:other.server" }));
+        let bot = Bot::new(mock.client());
+
+        assert_eq!(bot.join_room("#rust:other.server", &["hint.server"]).unwrap(), "!rust:other.server");
+
+        let join = mock.requests().into_iter().find(|r| r.path.starts_with("/join/")).unwrap();
+        let servers: Vec<&str> = join.query.iter()
+                                           .filter(|(k, _)| k == "server_name")
+                                           .map(|(_, v)| v.as_str())
+                                           .collect();
+        assert_eq!(servers, ["hint.server", "other.server", "third.server"]);
+    }
+
+    #[test]
+    fn resolve_user_prefers_room_members() {
+        let mock = MockServer::start();
+        set_room_state(&mock, vec![
+            member("@bob:mock.server", "Bob"),
+            member("@robert:other.server", "bob"),
+            member("@carol:mock.server", "Carol"),
+        ]);
+        let bot = Bot::new(mock.client());
+        let event = text("votekick Bob");
+
+        assert_eq!(bot.resolve_user(&event, "Bob").unwrap(), "@bob:mock.server");
+        assert_eq!(bot.resolve_user(&event, "carol").unwrap(), "@carol:mock.server");
+        assert_eq!(bot.resolve_user(&event, "robert").unwrap(), "@robert:other.server");
+        assert_eq!(bot.resolve_user(&event, "@dave:mock.server").unwrap(), "@dave:mock.server");
+
+        match bot.resolve_user(&event, "BOB") {
+            Err(Error::Generic(e)) => assert!(e.contains("@bob:mock.server") && e.contains("@robert:other.server")),
+            other => panic!("Expected an ambiguous match, got {:?}", other),
+        }
+
+        let mut mention = message_event(TEST_SENDER, "votekick bob");
+        mention.content["formatted_body"] =
+            json!("votekick <a href=\"https://matrix.to/#/@robert:other.server\">bob</a>");
+        assert_eq!(bot.resolve_user(&room_event(mention), "bob").unwrap(), "@robert:other.server");
+    }
+
+    #[test]
+    fn removal_respects_power_levels() {
+        let mock = MockServer::start();
+        set_room_state(&mock, vec![
+            state_event("m.room.power_levels", "", json!({
+                "users": { TEST_USER: 75, "@mod:mock.server": 50, "@peer:mock.server": 75 },
+            })),
+        ]);
+        let bot = Bot::new(mock.client());
+
+        assert_eq!(bot.power_level(TEST_ROOM, TEST_USER).unwrap(), 75);
+        assert!(bot.can(TEST_ROOM, Action::Ban).unwrap());
+        assert!(bot.can(TEST_ROOM, Action::SetState("m.room.power_levels")).unwrap());
+        assert!(bot.can(TEST_ROOM, Action::Send("m.room.message")).unwrap());
+        assert!(bot.check_removal(TEST_ROOM, &RemovalMode::Kick, TEST_SENDER).is_ok());
+        assert!(bot.check_removal(TEST_ROOM, &RemovalMode::Kick, "@mod:mock.server").is_err());
+        assert!(bot.check_removal(TEST_ROOM, &RemovalMode::Ban, "@peer:mock.server").is_err());
+    }
+
+    #[test]
+    fn removal_needs_permission() {
+        let mock = MockServer::start();
+        set_room_state(&mock, vec![
+            state_event("m.room.power_levels", "", json!({ "users": { TEST_USER: 10 }, "kick": 20 })),
+        ]);
+        let bot = Bot::new(mock.client());
+
+        let refusal = bot.check_removal(TEST_ROOM, &RemovalMode::Kick, TEST_SENDER).unwrap_err();
+        assert!(refusal.contains("permission"));
+    }
+
+    #[test]
+    fn moderators_follow_room_levels() {
+        let mock = MockServer::start();
+        set_room_state(&mock, vec![
+            state_event("m.room.power_levels", "", json!({
+                "users": { TEST_USER: 100, "@mod:mock.server": 50 }, "kick": 60, "ban": 40,
+            })),
+        ]);
+        let bot = Bot::new(mock.client());
+
+        assert!(bot.check_removal(TEST_ROOM, &RemovalMode::Kick, "@mod:mock.server").is_ok());
+        let refusal = bot.check_removal(TEST_ROOM, &RemovalMode::Ban, "@mod:mock.server").unwrap_err();
+        assert!(refusal.contains("moderator"));
+    }
+
+    #[test]
+    fn sends_as_appservice_users() {
+        let mock = MockServer::start();
+        let bot = Bot::new(mock.client());
+
+        bot.send_as("rustix_echo", TEST_ROOM, "one").unwrap();
+        bot.send_as("rustix_echo", TEST_ROOM, "two").unwrap();
+
+        let puppet = "@rustix_echo:mock.server";
+        let requests: Vec<_> = mock.requests().into_iter()
+                                   .filter(|r| r.path != "/login")
+                                   .map(|r| {
+                                       let user_id = r.query.iter().find(|(k, _)| k == "user_id").map(|(_, v)| v.clone());
+                                       (r.method, r.path.split('/').nth(1).unwrap_or_default().to_string(), user_id)
+                                   })
+                                   .collect();
+        let as_puppet = |method: &str, path: &str| (method.to_string(), path.to_string(), Some(puppet.to_string()));
+
+        assert_eq!(requests, [
+            ("POST".to_string(), "register".to_string(), None),
+            as_puppet("POST", "join"),
+            as_puppet("PUT", "rooms"),
+            as_puppet("PUT", "rooms"),
+        ]);
+        assert_eq!(mock.requests()[1].body["type"], "m.login.application_service");
+        assert_eq!(mock.replies(), ["one", "two"]);
+    }
+
+    #[test]
+    fn replies_to_replies_quote_only_the_reply() {
+        let mock = MockServer::start();
+        let bot = Bot::new(mock.client());
+
+        let mut reply = message_event(TEST_SENDER, "> <@bob:mock.server> ping\n> again\n\nhi");
+        reply.content["format"] = json!("org.matrix.custom.html");
+        reply.content["formatted_body"] = json!("<mx-reply><blockquote>ping<br />again</blockquote></mx-reply>hi");
+        reply.content["m.relates_to"] = json!({ "m.in_reply_to": { "event_id": "$ping" } });
+
+        bot.reply_to(&room_event(reply), "pong").unwrap();
+
+        let content = &mock.sent()[0].content;
+        assert_eq!(content["body"], format!("> <{}> hi\n\npong", TEST_SENDER));
+        assert_eq!(content["formatted_body"], format!(
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/{room}/$incoming\">In reply to</a> \
+             <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br />hi</blockquote></mx-reply>pong",
+            room = TEST_ROOM, sender = TEST_SENDER));
+    }
+
+    #[test]
+    fn room_creator_has_all_power_without_power_levels() {
+        let mock = MockServer::start();
+        set_room_state(&mock, vec![state_event("m.room.create", "", json!({ "room_version": "11" }))]);
+        let bot = Bot::new(mock.client());
+
+        assert_eq!(bot.power_level(TEST_ROOM, TEST_USER).unwrap(), 100);
+        assert!(bot.check_removal(TEST_ROOM, &RemovalMode::Kick, TEST_SENDER).is_ok());
+
+        let mut created = state_event("m.room.create", "", json!({ "room_version": "11" }));
+        created["sender"] = json!(TEST_SENDER);
+        set_room_state(&mock, vec![created]);
+        let bot = Bot::new(mock.client());
+
+        assert_eq!(bot.power_level(TEST_ROOM, TEST_SENDER).unwrap(), 100);
+        let refusal = bot.check_removal(TEST_ROOM, &RemovalMode::Kick, TEST_SENDER).unwrap_err();
+        assert!(refusal.contains("permission"));
+    }
+
+    #[test]
+    fn power_levels_may_be_strings() {
+        let mock = MockServer::start();
+        set_room_state(&mock, vec![
+            state_event("m.room.power_levels", "", json!({ "users": { TEST_USER: "75" }, "kick": " 60" })),
+        ]);
+        let bot = Bot::new(mock.client());
+
+        assert_eq!(bot.power_level(TEST_ROOM, TEST_USER).unwrap(), 75);
+        assert_eq!(bot.power_levels(TEST_ROOM).unwrap().kick, 60);
+    }
+
+    #[test]
+    fn removed_nodes_give_up_their_event_types() {
+        let _state = test_state();
+        let config: Config = toml::from_str(r#"
+            [connection]
+            server = "http://localhost"
+            username = "rustix"
+
+            [bot]
+            display_name = "rustix"
+            prefix = "!"
+            rooms = []
+            admins = []
+            ignore = []
+
+            [graph]
+            extend_default = false
+
+            [[graph.nodes]]
+            name = "prefix"
+
+            [[graph.nodes]]
+            name = "nodectl"
+            parent = "prefix"
+
+            [[graph.nodes]]
+            name = "votes"
+            type = "reaction_filter"
+            parent = "prefix"
+        "#).unwrap();
+
+        let mock = MockServer::start();
+        let mut bot = Bot::new(mock.client());
+        bot.load_graph(graph::Registry::with_builtins(), config, TEST_USER).unwrap();
+        assert!(bot.event_types.contains("m.reaction"));
+
+        mock.queue_sync(sync_response("s1", &[message_event(TEST_SENDER, "!node rm votes")]));
+        bot.sync_once("s0").unwrap();
+
+        assert_eq!(mock.replies(), ["Removed `votes`"]);
+        assert!(!bot.event_types.contains("m.reaction"));
+        assert!(bot.filter_outdated);
+    }
+
+    #[test]
+    fn direct_rooms_are_looked_up_once() {
+        let mock = MockServer::start();
+        mock.respond("POST", "/createRoom", 200, json!({ "room_id": "!dm:mock.server" }));
+        let bot = Bot::new(mock.client());
+        let lookups = || mock.requests().iter()
+                             .filter(|r| r.method == "GET" && r.path.ends_with("/account_data/m.direct"))
+                             .count();
+
+        bot.dm(TEST_SENDER, "one").unwrap();
+        bot.dm(TEST_SENDER, "two").unwrap();
+        assert_eq!(lookups(), 1);
+
+        bot.leave_room("!dm:mock.server").unwrap();
+        bot.dm(TEST_SENDER, "three").unwrap();
+        assert_eq!(lookups(), 2);
+    }
+}
@@ -1,21 +1,50 @@
 #![allow(dead_code)]
-use std::time::Duration;
-use std::result;
-use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{result, thread};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use reqwest;
 use reqwest::Url;
-use reqwest::blocking::Response;
+use reqwest::blocking::{RequestBuilder, Response};
 use reqwest::header;
+use reqwest::StatusCode;
 use http::Method;
+use itertools::Itertools;
 use serde::Serialize;
+use serde_json::{json, Value};
 
+use crate::config::{AppService, Connection};
+use crate::crypto::{self, Crypto, Device, PickleKey};
 use crate::errors::Error;
 use crate::matrix_types::*;
+use crate::state;
+use crate::utils::media_info;
 
 
 type Result<T> = result::Result<T, Error>;
 
+// Name of the state file the login session is saved under
+const SESSION_STATE: &str = "session";
+
+// Extra time allowed for a long-polling sync request beyond the poll timeout
+const SYNC_TIMEOUT_SLACK: Duration = Duration::from_secs(30);
+
+// Retry policy for rate limited requests and transient server errors. The
+// waits block the calling thread, usually the sync loop, so their total is
+// capped and requests which would need to wait longer fail instead.
+const MAX_RETRIES: u32 = 5;
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(30);
+
+// Number of rooms requested per page of the public room directory
+const PUBLIC_ROOMS_PAGE_SIZE: u32 = 100;
+
+// Times the room key is shared before sending an encrypted event gives up, in
+// case the room's outbound session keeps being replaced meanwhile
+const ROOM_KEY_ATTEMPTS: u32 = 3;
+
 
 pub struct MatrixClient {
     base_url: Url,
@@ -25,8 +54,108 @@ pub struct MatrixClient {
     device_id: Option<String>,
     user_id: Option<String>,
 
-    transaction_id: u64,
+    txn_prefix: String,
+    transaction_id: AtomicU64,
     client: reqwest::blocking::Client,
+
+    // End-to-end encryption state, set up once logged in. It's never locked
+    // during a request, other threads may want to send meanwhile.
+    crypto: Mutex<Option<Crypto>>,
+    // Verified devices of each user and the users who get the room keys of
+    // each encrypted room, kept until a sync says they changed
+    devices: Mutex<HashMap<String, Vec<Device>>>,
+    key_recipients: Mutex<HashMap<String, Vec<String>>>,
+
+    // User an application service is acting as, see `puppet`
+    masquerade: Option<String>,
+}
+
+
+/// A sync request which is sent without holding on to the client, see
+/// `MatrixClient::sync_request`
+pub struct SyncRequest {
+    client: reqwest::blocking::Client,
+    url: Url,
+    timeout: Option<Duration>,
+}
+
+impl SyncRequest {
+    pub fn send(self) -> Result<MatrixSync> {
+        execute(&self.client, Method::GET, self.url, "/sync", self.timeout, |b| b)
+            .and_then(|r| r.json().map_err(|e| format!("Problem syncing: {:?}", e).into()))
+    }
+}
+
+
+fn txn_prefix() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    format!("rustix{}", now.as_millis())
+}
+
+
+/// Send a request, retrying when rate limited or on server errors. Server
+/// errors are only retried for idempotent methods, a non-idempotent request
+/// may have gone through before the error, e.g. a gateway timeout. PUTs are
+/// idempotent in the client-server API: they either carry a transaction id or
+/// set a value. `body` fills in the request and is called again for every
+/// attempt.
+fn execute<F>(client: &reqwest::blocking::Client,
+              method: Method,
+              url: Url,
+              path: &str,
+              timeout: Option<Duration>,
+              body: F) -> Result<Response>
+    where F: Fn(RequestBuilder) -> RequestBuilder
+{
+    let mut attempt = 0;
+    let mut waited = Duration::ZERO;
+    loop {
+        let mut builder = client.request(method.clone(), url.clone());
+
+        if let Some(t) = timeout {
+            builder = builder.timeout(t);
+        }
+
+        let response = body(builder).send()?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let retry_header = response.headers().get(header::RETRY_AFTER)
+                                   .and_then(|v| v.to_str().ok())
+                                   .and_then(|v| v.parse().ok())
+                                   .map(Duration::from_secs);
+
+        let err = response.json::<MatrixError>().unwrap_or(MatrixError {
+            errcode: "M_UNKNOWN".to_string(),
+            error: Some(status.to_string()),
+            retry_after_ms: None,
+        });
+
+        let rate_limited = status == StatusCode::TOO_MANY_REQUESTS || err.errcode == "M_LIMIT_EXCEEDED";
+        let retry = rate_limited || (status.is_server_error() && method.is_idempotent());
+        let backoff = BASE_BACKOFF * 2u32.pow(attempt);
+        let wait = err.retry_after_ms.map(Duration::from_millis)
+                      .or(retry_header)
+                      .unwrap_or(backoff);
+
+        if retry && attempt < MAX_RETRIES && waited + wait <= MAX_RETRY_WAIT {
+            println!("Request to {} failed with {} ({}), retrying in {:?}",
+                     path, status, err.errcode, wait);
+            thread::sleep(wait);
+            waited += wait;
+            attempt += 1;
+            continue;
+        }
+
+        return Err(Error::Matrix {
+            status,
+            errcode: err.errcode,
+            error: err.error.unwrap_or_default(),
+        });
+    }
 }
 
 
@@ -38,15 +167,24 @@ impl MatrixClient {
             access_token: None,
             device_id: None,
             user_id: None,
-            transaction_id: 0,
+            txn_prefix: txn_prefix(),
+            transaction_id: AtomicU64::new(0),
             client: reqwest::blocking::Client::new(),
+            crypto: Mutex::new(None),
+            devices: Mutex::new(HashMap::new()),
+            key_recipients: Mutex::new(HashMap::new()),
+            masquerade: None,
         }
     }
 
-    fn get_transaction_id(&mut self) -> u64 {
-        self.transaction_id += 1;
+    /// Transaction ids must not repeat for the same access token, even across
+    /// restarts, or the server will dedupe the request and drop the message.
+    /// Prefixing the counter with the client's creation time keeps ids from
+    /// different runs apart.
+    fn get_transaction_id(&self) -> String {
+        let n = self.transaction_id.fetch_add(1, Ordering::Relaxed) + 1;
 
-        self.transaction_id
+        format!("{}.{}", self.txn_prefix, n)
     }
 
     pub fn query<T: Serialize + ?Sized>(&self,
@@ -55,33 +193,63 @@ impl MatrixClient {
              params: Option<&HashMap<&str, &str>>,
              data: Option<&T>,
              version: Option<&str>) -> Result<Response> {
+        self.query_timeout(method, path, params, data, version, None)
+    }
+
+    /// Same as `query` but allows overriding the client's default request
+    /// timeout, which is needed for long-polling requests.
+    fn query_timeout<T: Serialize + ?Sized>(&self,
+                     method: Method,
+                     path: &str,
+                     params: Option<&HashMap<&str, &str>>,
+                     data: Option<&T>,
+                     version: Option<&str>,
+                     timeout: Option<Duration>) -> Result<Response> {
+
+        let url = self.api_url(&["client", version.unwrap_or("v3")], path, params)?;
+
+        let nothing = HashMap::<String, String>::new();
+
+        self.execute(method.clone(), url, path, timeout, |builder| {
+            match method {
+                Method::POST | Method::PUT => {
+                    let partial = builder.header(header::CONTENT_TYPE, "text/json");
+                    match data {
+                        Some(d) => partial.json(d),
+                        None    => partial.json(&nothing),
+                    }
+                },
+                _ => builder,
+            }
+        })
+    }
 
+    /// Build the url of an endpoint under `/_matrix/<api>/`
+    fn api_url(&self, api: &[&str], path: &str, params: Option<&HashMap<&str, &str>>) -> Result<Url> {
         // Concat the path to the base url and constant string
         let mut url = self.base_url.clone();
         url.path_segments_mut().map_err(|_| "Cannot be base")?
-           .extend(["_matrix", "client", version.unwrap_or("v3")]);
+           .push("_matrix")
+           .extend(api);
         url.set_path(&(url.path().to_string() + "/" + path.trim_start_matches('/')));
 
         if let Some(v) = params {
             url.query_pairs_mut().extend_pairs(v);
         }
 
-        let nothing = HashMap::<String, String>::new();
-
-        let builder = self.client.request(method.clone(), url);
-
-        let request = match method {
-            Method::POST | Method::PUT => {
-                let partial = builder.header(header::CONTENT_TYPE, "text/json");
-                match data {
-                    Some(d) => partial.json(d),
-                    None    => partial.json(&nothing),
-                }
-            },
-            _ => builder,
-        };
+        Ok(url)
+    }
 
-        Ok(request.send()?)
+    /// Send a request with this client, see `execute`
+    fn execute<F>(&self,
+                  method: Method,
+                  url: Url,
+                  path: &str,
+                  timeout: Option<Duration>,
+                  body: F) -> Result<Response>
+        where F: Fn(RequestBuilder) -> RequestBuilder
+    {
+        execute(&self.client, method, url, path, timeout, body)
     }
 
     pub fn auth_query<T: Serialize + ?Sized>(&self,
@@ -90,16 +258,31 @@ impl MatrixClient {
                   params: Option<HashMap<&str, &str>>,
                   data: Option<&T>,
                   version: Option<&str>) -> Result<Response> {
+        self.auth_query_timeout(method, path, params, data, version, None)
+    }
 
-        let mut real_params = params.unwrap_or_default();
+    fn auth_query_timeout<T: Serialize + ?Sized>(&self,
+                          method: Method,
+                          path: &str,
+                          params: Option<HashMap<&str, &str>>,
+                          data: Option<&T>,
+                          version: Option<&str>,
+                          timeout: Option<Duration>) -> Result<Response> {
 
-        match self.access_token {
-            Some(ref v) => {
-                real_params.insert("access_token", v);
-                self.query(method, path, Some(&real_params), data, version)
-            },
-            None => Err("User must be authenticated first.".into()),
+        let real_params = self.with_auth(params.unwrap_or_default())?;
+        self.query_timeout(method, path, Some(&real_params), data, version, timeout)
+    }
+
+    /// Add the parameters authenticating a request to `params`
+    fn with_auth<'p>(&'p self, mut params: HashMap<&'p str, &'p str>) -> Result<HashMap<&'p str, &'p str>> {
+        let token = self.access_token.as_deref().ok_or("User must be authenticated first.")?;
+        params.insert("access_token", token);
+
+        if let Some(ref user_id) = self.masquerade {
+            params.insert("user_id", user_id);
         }
+
+        Ok(params)
     }
 
     pub fn get(&self, path: &str,
@@ -114,14 +297,16 @@ impl MatrixClient {
         self.auth_query::<()>(Method::GET, path, params, None, version)
     }
 
-    pub fn login(&mut self, username: &str, password: &str) -> Result<()> {
+    pub fn login(&mut self, username: &str, password: &str, device_id: Option<&str>) -> Result<()> {
         let login = Login {
             type_: "m.login.password",
             identifier: LoginIdentifier {
                 type_: "m.id.user",
                 user: username,
             },
-            password
+            password,
+            device_id,
+            initial_device_display_name: "rustix",
         };
 
         // Parse response into client state
@@ -137,52 +322,712 @@ impl MatrixClient {
         }
     }
 
-    pub fn sync(&self, since: Option<&str>) -> Result<MatrixSync>{
+    /// Ask the server who the current access token belongs to. Returns `None`
+    /// when the server rejects the token.
+    pub fn whoami(&self) -> Result<Option<WhoAmI>> {
+        match self.auth_get("/account/whoami", None, None) {
+            Ok(res) => Ok(Some(res.json()?)),
+            Err(Error::Matrix { errcode, .. }) if errcode == "M_UNKNOWN_TOKEN" ||
+                                                 errcode == "M_MISSING_TOKEN" => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Use `access_token` for future requests if the server accepts it.
+    fn try_token(&mut self, access_token: &str, device_id: Option<String>) -> Result<bool> {
+        self.access_token = Some(access_token.to_string());
+
+        match self.whoami()? {
+            Some(who) => {
+                self.user_id = Some(who.user_id);
+                self.device_id = who.device_id.or(device_id);
+                Ok(true)
+            },
+            None => {
+                self.access_token = None;
+                Ok(false)
+            },
+        }
+    }
+
+    /// Log in and set up end-to-end encryption for the logged in device.
+    pub fn connect(&mut self, connection: &Connection) -> Result<()> {
+        self.authenticate(connection)?;
+        self.setup_crypto(connection)
+    }
+
+    /// Authenticate as an application service, acting as its sender user.
+    /// Encryption isn't set up, application services don't have devices.
+    pub fn connect_appservice(&mut self, appservice: &AppService) -> Result<()> {
+        let token = appservice.as_token.as_deref().ok_or("No as_token configured for the appservice")?;
+        if self.try_token(token, None)? {
+            Ok(())
+        } else {
+            Err("The appservice as_token was rejected by the server".into())
+        }
+    }
+
+    /// Get a client acting as another user in the application service's
+    /// namespace, registering the user if it doesn't exist yet.
+    pub fn puppet(&self, localpart: &str) -> Result<MatrixClient> {
+        let server_name = self.user_id.as_deref()
+                              .and_then(|u| u.split_once(':'))
+                              .map(|(_, s)| s)
+                              .ok_or("Must be logged in")?;
+        let user_id = format!("@{}:{}", localpart, server_name);
+
+        let data = json!({
+            "type": "m.login.application_service",
+            "username": localpart,
+        });
+        match self.auth_query(Method::POST, "/register", None, Some(&data), None) {
+            Ok(_) => (),
+            Err(Error::Matrix { errcode, .. }) if errcode == "M_USER_IN_USE" => (),
+            Err(e) => return Err(e),
+        }
+
+        let mut client = MatrixClient::new(self.base_url.as_str());
+        client.access_token = self.access_token.clone();
+        client.user_id = Some(user_id.clone());
+        // Transaction ids are scoped to the access token, which is shared
+        client.txn_prefix = format!("{}.{}", self.txn_prefix, localpart);
+        client.client = self.client.clone();
+        client.masquerade = Some(user_id);
+
+        Ok(client)
+    }
+
+    /// Log in by reusing, in order of preference, the session saved by a
+    /// previous run, the access token from the config, or finally a fresh
+    /// password login. Password logins reuse the saved device id so the bot
+    /// doesn't leave a trail of stale devices behind on the homeserver.
+    fn authenticate(&mut self, connection: &Connection) -> Result<()> {
+        let saved = state::load_state(SESSION_STATE)
+                         .and_then(|s| serde_json::from_str::<Session>(&s).ok());
+        let mut saved_device = None;
+
+        if let Some(session) = saved {
+            if self.try_token(&session.access_token, session.device_id.clone())? {
+                println!("Resumed session for {}", session.user_id);
+                return Ok(());
+            }
+            println!("Saved session was rejected by the server");
+            saved_device = session.device_id;
+        }
+
+        if let Some(ref token) = connection.access_token {
+            if self.try_token(token, None)? {
+                self.save_session();
+                return Ok(());
+            }
+            println!("Configured access token was rejected by the server");
+        }
+
+        match connection.password {
+            Some(ref password) => {
+                self.login(&connection.username, password, saved_device.as_deref())?;
+                self.save_session();
+                Ok(())
+            },
+            None => Err("No valid access token and no password configured".into()),
+        }
+    }
+
+    fn save_session(&self) {
+        if let (Some(user_id), Some(access_token)) = (&self.user_id, &self.access_token) {
+            let session = Session {
+                user_id: user_id.clone(),
+                access_token: access_token.clone(),
+                device_id: self.device_id.clone(),
+            };
+
+            match serde_json::to_string(&session) {
+                Ok(s) => state::save_state(SESSION_STATE, &s),
+                Err(e) => println!("Unable to save session: {:?}", e),
+            }
+        }
+    }
+
+    /// Load or create this device's encryption keys and make sure the server
+    /// has them, so other devices can send encrypted messages to the bot.
+    /// Encryption stays off without a pickle key to keep the keys safe with.
+    fn setup_crypto(&mut self, connection: &Connection) -> Result<()> {
+        let (Some(user_id), Some(device_id)) = (&self.user_id, &self.device_id) else {
+            println!("No device id for this session, end-to-end encryption is disabled");
+            return Ok(());
+        };
+
+        let Some(pickle_key) = PickleKey::find(connection.pickle_key.as_deref()) else {
+            println!("No pickle key configured, end-to-end encryption is disabled");
+            return Ok(());
+        };
+
+        let mut crypto = Crypto::load(user_id, device_id, pickle_key)?;
+        let published = self.upload_keys(&key_upload(&mut crypto, None))?;
+        crypto.mark_keys_as_published();
+        if crypto.needs_one_time_keys(published) {
+            self.upload_keys(&key_upload(&mut crypto, Some(published)))?;
+            crypto.mark_keys_as_published();
+        }
+        crypto.save();
+
+        self.crypto = Mutex::new(Some(crypto));
+
+        Ok(())
+    }
+
+    fn has_crypto(&self) -> bool {
+        self.crypto.lock().unwrap().is_some()
+    }
+
+    /// Run `func` on the crypto state, if encryption is set up. Requests must
+    /// not be made from `func`.
+    fn with_crypto<T>(&self, func: impl FnOnce(&mut Crypto) -> T) -> Option<T> {
+        self.crypto.lock().unwrap().as_mut().map(func)
+    }
+
+    /// Upload keys made by `key_upload`, returning the server's new one-time
+    /// key count. They must be marked as published afterwards.
+    fn upload_keys(&self, keys: &Value) -> Result<u64> {
+        let res: KeysUploadResponse = self.auth_query(Method::POST, "/keys/upload", None, Some(keys), None)?
+                                          .json()?;
+
+        Ok(res.one_time_key_counts.get("signed_curve25519").copied().unwrap_or(0))
+    }
+
+    /// Fetch and verify the devices of the given users.
+    fn query_devices(&self, users: &[String]) -> Result<Vec<Device>> {
+        let device_keys: HashMap<&str, Vec<String>> = users.iter()
+                                                           .map(|u| (u.as_str(), Vec::new()))
+                                                           .collect();
+        let data = json!({ "device_keys": device_keys });
+
+        let res: KeysQueryResponse = self.auth_query(Method::POST, "/keys/query", None, Some(&data), None)?
+                                         .json()?;
+
+        let mut devices = Vec::new();
+        for (user_id, user_devices) in &res.device_keys {
+            for (device_id, keys) in user_devices {
+                match crypto::verify_device(user_id, device_id, keys) {
+                    Some(d) => devices.push(d),
+                    None => println!("Ignoring device {} of {} with invalid keys", device_id, user_id),
+                }
+            }
+        }
+
+        Ok(devices)
+    }
+
+    /// The devices of the given users, only querying the server for users
+    /// whose devices aren't known yet.
+    fn get_devices(&self, users: &[String]) -> Result<Vec<Device>> {
+        let unknown: Vec<String> = {
+            let known = self.devices.lock().unwrap();
+            users.iter().filter(|u| !known.contains_key(*u)).cloned().collect()
+        };
+
+        if !unknown.is_empty() {
+            let queried = self.query_devices(&unknown)?;
+            let mut known = self.devices.lock().unwrap();
+            for user_id in unknown {
+                let devices = queried.iter().filter(|d| d.user_id == user_id).cloned().collect();
+                known.insert(user_id, devices);
+            }
+        }
+
+        let known = self.devices.lock().unwrap();
+        Ok(users.iter().filter_map(|u| known.get(u)).flatten().cloned().collect())
+    }
+
+    /// Claim a one-time key of each device and start olm sessions with them.
+    fn create_sessions(&self, devices: &[Device]) -> Result<()> {
+        let mut one_time_keys: HashMap<&str, HashMap<&str, &str>> = HashMap::new();
+        for d in devices {
+            one_time_keys.entry(&d.user_id).or_default().insert(&d.device_id, "signed_curve25519");
+        }
+        let data = json!({ "one_time_keys": one_time_keys });
+
+        let res: KeysClaimResponse = self.auth_query(Method::POST, "/keys/claim", None, Some(&data), None)?
+                                         .json()?;
+
+        self.with_crypto(|crypto| {
+            for d in devices {
+                let claimed = res.one_time_keys.get(&d.user_id)
+                                               .and_then(|u| u.get(&d.device_id))
+                                               .and_then(|k| k.iter().next());
+
+                match claimed {
+                    Some((key_id, key)) => if let Err(e) = crypto.create_session(d, key_id, key) {
+                        println!("{}", e);
+                    },
+                    None => println!("No one-time keys left for device {} of {}", d.device_id, d.user_id),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// The users who should be able to read messages sent to a room: the
+    /// joined and the invited members.
+    fn get_key_recipients(&self, room_id: &str) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct Members {
+            chunk: Vec<Value>,
+        }
+
+        if let Some(recipients) = self.key_recipients.lock().unwrap().get(room_id) {
+            return Ok(recipients.clone());
+        }
+
+        let members: Members = self.auth_get(&format!("/rooms/{}/members", room_id), None, None)?
+                                   .json()?;
+
+        let recipients: Vec<String> = members.chunk.iter()
+                                             .filter(|e| e["content"]["membership"] == "join" ||
+                                                         e["content"]["membership"] == "invite")
+                                             .filter_map(|e| e["state_key"].as_str().map(|s| s.to_string()))
+                                             .collect();
+        self.key_recipients.lock().unwrap().insert(room_id.to_string(), recipients.clone());
+
+        Ok(recipients)
+    }
+
+    /// Send the key of the room's current megolm session to every one of
+    /// `devices` which doesn't have it yet, returning the session's id.
+    fn share_room_key(&self, room_id: &str, devices: &[Device]) -> Result<String> {
+        let (session_id, missing, need_session) = self.with_crypto(|crypto| {
+            let (session_id, missing) = crypto.devices_without_key(room_id, devices);
+            let need_session: Vec<Device> = missing.iter()
+                                                   .filter(|d| !crypto.has_session(d))
+                                                   .map(|d| (*d).clone())
+                                                   .collect();
+            (session_id, missing, need_session)
+        }).ok_or("End-to-end encryption is not set up")?;
+
+        if missing.is_empty() {
+            return Ok(session_id);
+        }
+
+        if !need_session.is_empty() {
+            self.create_sessions(&need_session)?;
+        }
+
+        let mut messages: HashMap<&str, HashMap<&str, Value>> = HashMap::new();
+        let mut shared = Vec::new();
+        self.with_crypto(|crypto| {
+            for d in missing {
+                match crypto.encrypt_room_key(room_id, &session_id, d) {
+                    Ok(content) => {
+                        messages.entry(&d.user_id).or_default().insert(&d.device_id, content);
+                        shared.push(d);
+                    },
+                    Err(e) => println!("Not sharing room key with {} {}: {}", d.user_id, d.device_id, e),
+                }
+            }
+            crypto.save();
+        });
+
+        let path = format!("/sendToDevice/m.room.encrypted/{}", self.get_transaction_id());
+        self.auth_query(Method::PUT, &path, None, Some(&json!({ "messages": messages })), None)?;
+
+        self.with_crypto(|crypto| crypto.mark_shared(room_id, &session_id, &shared));
+
+        Ok(session_id)
+    }
+
+    /// Whether a room has encryption enabled, asking the server if it's a
+    /// room the bot hasn't seen the encryption state of yet.
+    fn room_is_encrypted(&self, room_id: &str) -> Result<bool> {
+        if let Some(encrypted) = self.with_crypto(|crypto| crypto.is_encrypted(room_id)).flatten() {
+            return Ok(encrypted);
+        }
+
+        let path = format!("/rooms/{}/state/m.room.encryption/", room_id);
+        let encrypted = match self.auth_get(&path, None, None) {
+            Ok(_) => true,
+            Err(Error::Matrix { errcode, .. }) if errcode == "M_NOT_FOUND" => false,
+            Err(e) => return Err(e),
+        };
+        self.with_crypto(|crypto| crypto.set_encrypted(room_id, encrypted));
+
+        Ok(encrypted)
+    }
+
+    /// Megolm encrypt an event for a room, first sharing the room key with
+    /// the devices of the room's members which don't have it yet. Returns
+    /// None for rooms which aren't encrypted.
+    fn encrypt_for_room(&self, room_id: &str, event_type: &str, content: &Value) -> Result<Option<Value>> {
+        if !self.has_crypto() || !self.room_is_encrypted(room_id)? {
+            return Ok(None);
+        }
+
+        let devices = self.get_devices(&self.get_key_recipients(room_id)?)?;
+
+        for _ in 0..ROOM_KEY_ATTEMPTS {
+            let session_id = self.share_room_key(room_id, &devices)?;
+            let encrypted = self.with_crypto(|crypto| {
+                let encrypted = crypto.encrypt_room_event(room_id, &session_id, event_type, content);
+                crypto.save();
+                encrypted
+            });
+
+            if let Some(Ok(encrypted)) = encrypted {
+                return Ok(Some(encrypted));
+            }
+        }
+
+        Err(format!("The room key of {} kept changing while it was shared", room_id).into())
+    }
+
+    /// Handle the to-device messages, device list changes and key counts of
+    /// a sync response and decrypt its encrypted room events in place.
+    fn process_crypto(&self, sync_data: &mut MatrixSync) {
+        if !self.has_crypto() {
+            return;
+        }
+
+        if let Some(ref device_lists) = sync_data.device_lists {
+            let mut devices = self.devices.lock().unwrap();
+            for user_id in device_lists.changed.iter().chain(&device_lists.left) {
+                devices.remove(user_id);
+            }
+        }
+
+        let to_device: Vec<&ToDeviceEvent> = sync_data.to_device.iter()
+                                                      .flat_map(|t| &t.events)
+                                                      .filter(|e| e.type_ == "m.room.encrypted")
+                                                      .collect();
+
+        // A message from a device we don't know about means the sender's
+        // devices must be queried again
+        let senders: Vec<String> = {
+            let mut devices = self.devices.lock().unwrap();
+            for event in &to_device {
+                let known = devices.get(&event.sender)
+                                   .is_some_and(|d| d.iter().any(|d| event.content["sender_key"] == d.curve25519.as_str()));
+                if !known {
+                    devices.remove(&event.sender);
+                }
+            }
+            to_device.iter().map(|e| e.sender.clone()).unique().collect()
+        };
+        let sender_devices = self.get_devices(&senders).unwrap_or_else(|e| {
+            println!("Unable to query the devices of to-device message senders: {}", e);
+            Vec::new()
+        });
+
+        let joined = sync_data.rooms.as_mut().and_then(|r| r.join.as_mut());
+        let key_counts = sync_data.device_one_time_keys_count.as_ref();
+
+        let upload = self.with_crypto(|crypto| {
+            for event in &to_device {
+                if let Err(e) = crypto.handle_to_device(event, &sender_devices) {
+                    println!("Unable to decrypt to-device event from {}: {}", event.sender, e);
+                }
+            }
+
+            for (room_id, room) in joined.into_iter().flatten() {
+                let events = room.state.events.iter_mut().chain(room.timeline.events.iter_mut());
+                for event in events {
+                    match event.type_.as_str() {
+                        "m.room.encryption" => crypto.set_encrypted(room_id, true),
+                        "m.room.member" => {
+                            self.key_recipients.lock().unwrap().remove(room_id);
+                            // Whoever left must not be able to read what comes next
+                            if event.content["membership"] == "leave" || event.content["membership"] == "ban" {
+                                crypto.discard_outbound(room_id);
+                            }
+                        },
+                        "m.room.encrypted" => {
+                            crypto.set_encrypted(room_id, true);
+                            if let Err(e) = crypto.decrypt_room_event(room_id, event) {
+                                println!("Unable to decrypt event in {}: {}", room_id, e);
+                            }
+                        },
+                        _ => {},
+                    }
+                }
+            }
+
+            let published = key_counts.map(|counts| counts.get("signed_curve25519").copied().unwrap_or(0));
+            let upload = published.filter(|p| crypto.needs_one_time_keys(*p))
+                                  .map(|p| key_upload(crypto, Some(p)));
+            crypto.save();
+
+            upload
+        }).flatten();
+
+        if let Some(keys) = upload {
+            match self.upload_keys(&keys) {
+                Ok(_) => {
+                    self.with_crypto(|crypto| {
+                        crypto.mark_keys_as_published();
+                        crypto.save();
+                    });
+                },
+                Err(e) => println!("Unable to upload one-time keys: {}", e),
+            }
+        }
+    }
+
+    /// Fetch new events. When `timeout` is given the server holds the request
+    /// open for up to that long while waiting for events to arrive. Encrypted
+    /// room events are returned decrypted where possible.
+    pub fn sync(&self, since: Option<&str>, filter: Option<&str>, timeout: Option<Duration>) -> Result<MatrixSync>{
+        let mut sync_data = self.sync_request(since, filter, timeout)?.send()?;
+        self.process_sync(&mut sync_data);
+
+        Ok(sync_data)
+    }
+
+    /// Prepare a `sync` which can be sent without the client. Its response
+    /// must be passed to `process_sync` afterwards.
+    pub fn sync_request(&self, since: Option<&str>, filter: Option<&str>, timeout: Option<Duration>) -> Result<SyncRequest> {
         let mut params = HashMap::new();
         if let Some(v) = since {
             params.insert("since", v);
         }
 
-        self.auth_get("/sync", Some(params), None)
-            .and_then(|r| r.json().map_err(|e| format!("Problem syncing: {:?}", e).into()))
+        if let Some(f) = filter {
+            params.insert("filter", f);
+        }
+
+        let timeout_str = timeout.map(|t| t.as_millis().to_string());
+        if let Some(ref t) = timeout_str {
+            params.insert("timeout", t);
+        }
+
+        let params = self.with_auth(params)?;
+
+        Ok(SyncRequest {
+            client: self.client.clone(),
+            url: self.api_url(&["client", "v3"], "/sync", Some(&params))?,
+            // Leave the HTTP request some slack on top of the long-poll timeout
+            timeout: timeout.map(|t| t + SYNC_TIMEOUT_SLACK),
+        })
     }
 
-    //TODO: Validate this
-    pub fn get_public_rooms(&self) -> Result<PublicRooms> {
-        let params = hashmap! {
-            "from" => "",
-            "dir"  => "f"
-        };
+    /// Decrypt the encrypted room events of a sync response in place and
+    /// handle its encryption key updates.
+    pub fn process_sync(&self, sync_data: &mut MatrixSync) {
+        self.process_crypto(sync_data);
+    }
 
-        self.auth_get("/publicRooms", Some(params), None)
+    /// Upload a sync filter definition and get back its id for use with `sync`.
+    pub fn upload_filter(&self, filter: &Value) -> Result<String> {
+        #[derive(Deserialize)]
+        struct FilterId {
+            filter_id: String,
+        }
+
+        let path = format!("/user/{}/filter",
+                           self.user_id.as_ref().ok_or("Must be logged in")?);
+        self.auth_query(Method::POST, &path, None, Some(filter), None)
+            .and_then(|r| r.json::<FilterId>().map_err(|e| e.into()))
+            .map(|f| f.filter_id)
+    }
+
+    /// Get one page of a server's public room directory, starting at the
+    /// `since` token of the previous page. `server` selects a remote server's
+    /// directory instead of our own.
+    pub fn get_public_rooms(&self, since: Option<&str>, search: Option<&str>,
+                            server: Option<&str>) -> Result<PublicRooms> {
+        let mut data = json!({
+            "limit": PUBLIC_ROOMS_PAGE_SIZE,
+        });
+        if let Some(since) = since {
+            data["since"] = json!(since);
+        }
+        if let Some(search) = search {
+            data["filter"] = json!({"generic_search_term": search});
+        }
+
+        let params = server.map(|s| hashmap! {
+            "server" => s,
+        });
+
+        self.auth_query(Method::POST, "/publicRooms", params, Some(&data), None)
             .and_then(|r| r.json().map_err(|e| e.into()))
     }
 
-    pub fn get_public_room_id(&self, name: &str) -> Option<String> {
-        if let Ok(v) = self.get_public_rooms() {
-            for room in v.chunk {
-                if room.name == name {
-                    return Some(room.room_id);
+    /// Find a room in the public room directory by its exact name or alias,
+    /// going through every page of the directory.
+    pub fn get_public_room_id(&self, name: &str, server: Option<&str>) -> Result<Option<String>> {
+        let mut since: Option<String> = None;
+        loop {
+            let page = self.get_public_rooms(since.as_deref(), Some(name), server)?;
+            let found = page.chunk.into_iter().find(|room| {
+                room.name.as_deref() == Some(name) ||
+                room.canonical_alias.as_deref() == Some(name) ||
+                room.aliases.iter().flatten().any(|a| a == name)
+            });
+
+            if let Some(room) = found {
+                return Ok(Some(room.room_id));
+            }
+
+            match page.next_batch {
+                Some(next) if since.as_ref() != Some(&next) => since = Some(next),
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    /// Look up the room an alias points to, along with servers that are in
+    /// the room.
+    pub fn resolve_alias(&self, alias: &str) -> Result<RoomAlias> {
+        self.auth_get(&format!("/directory/room/{}", alias), None, None)
+            .and_then(|r| r.json().map_err(|e| e.into()))
+    }
+
+    /// Resolve a room id, alias or public room name to a room id and the
+    /// servers which can be asked to join it. Names are looked up in our own
+    /// public room directory, then in the directory of each server in
+    /// `servers`.
+    pub fn resolve_room(&self, room: &str, servers: &[&str]) -> Result<(String, Vec<String>)> {
+        let mut via: Vec<String> = servers.iter().map(|s| s.to_string()).collect();
+
+        let room_id = if room.starts_with('!') {
+            if let Some((_, server)) = room.split_once(':') {
+                via.push(server.to_string());
+            }
+            room.to_string()
+        } else if room.starts_with('#') {
+            let alias = self.resolve_alias(room)?;
+            via.extend(alias.servers);
+            alias.room_id
+        } else {
+            let mut found = self.get_public_room_id(room, None)?;
+            for server in servers {
+                if found.is_some() {
+                    break;
                 }
+                found = self.get_public_room_id(room, Some(server))?;
             }
+
+            found.ok_or("Room not found in the public room directory")?
+        };
+
+        let mut seen = HashSet::new();
+        via.retain(|s| seen.insert(s.clone()));
+
+        Ok((room_id, via))
+    }
+
+    /// Join a room by id or alias, returning the id of the joined room.
+    /// `servers` are asked to help with the join when our homeserver is not
+    /// in the room yet.
+    pub fn join(&self, room: &str, servers: &[String]) -> Result<String> {
+        let path = format!("/join/{}", room);
+        let params = self.with_auth(HashMap::new())?;
+        let mut url = self.api_url(&["client", "v3"], &path, Some(&params))?;
+
+        // The server hints are a repeated parameter, which the params map
+        // can't hold. Older servers only know server_name, newer ones via.
+        for server in servers {
+            url.query_pairs_mut()
+               .append_pair("server_name", server)
+               .append_pair("via", server);
         }
 
-        None
+        self.execute(Method::POST, url, &path, None, |builder| {
+                builder.header(header::CONTENT_TYPE, "text/json").json(&json!({}))
+            })
+            .and_then(|r| r.json::<RoomId>().map_err(|e| e.into()))
+            .map(|r| r.room_id)
     }
 
-    pub fn join(&self, room_id: &str) -> Result<Response> {
-        self.auth_query::<()>(Method::POST,
-                              &format!("/join/{}", room_id),
-                              None, None, None)
+    /// Create a new room, returning its id.
+    pub fn create_room(&self, options: &CreateRoom) -> Result<String> {
+        self.auth_query(Method::POST, "/createRoom", None, Some(options), None)
+            .and_then(|r| r.json::<CreatedRoom>().map_err(|e| e.into()))
+            .map(|r| r.room_id)
+    }
+
+    pub fn invite(&self, room_id: &str, user_id: &str) -> Result<()> {
+        let data = hashmap! {
+            "user_id" => user_id,
+        };
+
+        self.auth_query(Method::POST, &format!("/rooms/{}/invite", room_id), None, Some(&data), None)
+            .map(|_| ())
+    }
+
+    /// Get the bot's global account data of the given type, if it was set.
+    pub fn get_account_data(&self, event_type: &str) -> Result<Option<Value>> {
+        let path = format!("/user/{}/account_data/{}",
+                           self.user_id.as_ref().ok_or("Must be logged in")?, event_type);
+
+        match self.auth_get(&path, None, None) {
+            Ok(r) => Ok(Some(r.json()?)),
+            Err(e) if e.errcode() == Some("M_NOT_FOUND") => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
-    pub fn leave(&self, room_id: &str) -> Result<Response> {
+    pub fn set_account_data(&self, event_type: &str, content: &Value) -> Result<()> {
+        let path = format!("/user/{}/account_data/{}",
+                           self.user_id.as_ref().ok_or("Must be logged in")?, event_type);
+
+        self.auth_query(Method::PUT, &path, None, Some(content), None)
+            .map(|_| ())
+    }
+
+    /// Find the bot's direct message room with a user. The `m.direct` account
+    /// data, which clients also use to tell DMs apart, is checked for a room
+    /// the bot is still in. If there is none a new room is created and added.
+    pub fn get_direct_room(&self, user_id: &str) -> Result<String> {
+        let mut direct = self.get_account_data("m.direct")?
+                             .filter(|d| d.is_object())
+                             .unwrap_or_else(|| json!({}));
+
+        if let Some(rooms) = direct[user_id].as_array() {
+            let joined = self.get_joined()?.joined_rooms;
+            let existing = rooms.iter()
+                                .filter_map(|r| r.as_str())
+                                .find(|r| joined.iter().any(|j| j == r));
+
+            if let Some(room_id) = existing {
+                return Ok(room_id.to_string());
+            }
+        }
+
+        // Encrypt the conversation if the bot is able to
+        let mut initial_state = Vec::new();
+        if self.has_crypto() {
+            initial_state.push(json!({
+                "type": "m.room.encryption",
+                "state_key": "",
+                "content": { "algorithm": crypto::MEGOLM_ALGORITHM },
+            }));
+        }
+
+        let room_id = self.create_room(&CreateRoom {
+            preset: Some("trusted_private_chat"),
+            invite: vec![user_id],
+            is_direct: true,
+            initial_state,
+            ..Default::default()
+        })?;
+
+        match direct[user_id].as_array_mut() {
+            Some(rooms) => rooms.push(Value::from(room_id.as_str())),
+            None => direct[user_id] = json!([room_id]),
+        }
+        self.set_account_data("m.direct", &direct)?;
+
+        Ok(room_id)
+    }
+
+    pub fn leave(&self, room_id: &str) -> Result<()> {
         self.auth_query::<()>(Method::POST,
                               &format!("/rooms/{}/leave", room_id),
                               None, None, None)
+            .map(|_| ())
     }
 
-    pub fn set_displayname(&self, name: &str) -> Result<Response> {
+    pub fn set_displayname(&self, name: &str) -> Result<()> {
         let data = hashmap! {
             "displayname" => name,
         };
@@ -190,28 +1035,41 @@ impl MatrixClient {
         let path = format!("/profile/{}/displayname",
                            self.user_id.as_ref().expect("Must be logged in"));
         self.auth_query(Method::PUT, &path, None, Some(&data), None)
+            .map(|_| ())
     }
 
-    pub fn send(&mut self,
-                room_id: &str,
-                event_type: &str,
-                data: Option<&HashMap<&str, &str>>) -> Result<Response> {
+    /// Send an event to a room. The transaction id is part of the path, so
+    /// any retries made by `query` reuse it and can't produce duplicates.
+    /// In encrypted rooms the event is sent megolm encrypted.
+    pub fn send<T: Serialize + ?Sized>(&self,
+                                       room_id: &str,
+                                       event_type: &str,
+                                       content: &T) -> Result<EventId> {
+        let mut content = serde_json::to_value(content)?;
+        let mut event_type = event_type;
+
+        if let Some(encrypted) = self.encrypt_for_room(room_id, event_type, &content)? {
+            content = encrypted;
+            event_type = "m.room.encrypted";
+        }
+
         let path = format!("/rooms/{}/send/{}/{}", room_id, event_type,
                            self.get_transaction_id());
 
-        self.auth_query(Method::PUT, &path, None, data, None)
+        self.auth_query(Method::PUT, &path, None, Some(&content), None)
+            .and_then(|r| r.json().map_err(|e| e.into()))
     }
 
-    pub fn send_msg(&mut self, room_id: &str, message: &str) -> Result<Response> {
+    pub fn send_msg(&self, room_id: &str, message: &str) -> Result<EventId> {
         let data = hashmap! {
             "msgtype" => "m.text",
             "body"    => message,
         };
 
-        self.send(room_id, "m.room.message", Some(&data))
+        self.send(room_id, "m.room.message", &data)
     }
 
-    pub fn send_msg_fmt(&mut self, room_id: &str, fmt_message: &str, message: &str) -> Result<Response> {
+    pub fn send_msg_fmt(&self, room_id: &str, fmt_message: &str, message: &str) -> Result<EventId> {
         let data = hashmap! {
             "format"  => "org.matrix.custom.html",
             "msgtype" => "m.text",
@@ -219,19 +1077,152 @@ impl MatrixClient {
             "body"    => message,
         };
 
-        self.send(room_id, "m.room.message", Some(&data))
+        self.send(room_id, "m.room.message", &data)
     }
 
-    pub fn send_action(&mut self, room_id: &str, message: &str) -> Result<Response> {
+    /// Replace the text of a previously sent message. Clients which don't
+    /// support edits show the body, a `*` marked copy of the new text.
+    pub fn edit_message(&self, room_id: &str, event_id: &str, message: &str) -> Result<EventId> {
+        let data = json!({
+            "msgtype": "m.text",
+            "body": format!("* {}", message),
+            "m.new_content": {
+                "msgtype": "m.text",
+                "body": message,
+            },
+            "m.relates_to": {
+                "rel_type": "m.replace",
+                "event_id": event_id,
+            },
+        });
+
+        self.send(room_id, "m.room.message", &data)
+    }
+
+    /// Annotate an event with `key`, usually an emoji.
+    pub fn react(&self, room_id: &str, event_id: &str, key: &str) -> Result<EventId> {
+        let data = json!({
+            "m.relates_to": {
+                "rel_type": "m.annotation",
+                "event_id": event_id,
+                "key": key,
+            },
+        });
+
+        self.send(room_id, "m.reaction", &data)
+    }
+
+    pub fn redact(&self, room_id: &str, event_id: &str, reason: Option<&str>) -> Result<EventId> {
+        let path = format!("/rooms/{}/redact/{}/{}", room_id, event_id,
+                           self.get_transaction_id());
+
+        let mut data = HashMap::new();
+        if let Some(r) = reason {
+            data.insert("reason", r);
+        }
+
+        self.auth_query(Method::PUT, &path, None, Some(&data), None)
+            .and_then(|r| r.json().map_err(|e| e.into()))
+    }
+
+    pub fn send_action(&self, room_id: &str, message: &str) -> Result<EventId> {
         let data = hashmap! {
             "msgtype" => "m.emote",
             "body"    => message,
         };
 
-        self.send(room_id, "m.room.message", Some(&data))
+        self.send(room_id, "m.room.message", &data)
+    }
+
+    /// Upload a file to the content repository, returning its `mxc://` uri.
+    pub fn upload(&self, data: &[u8], content_type: &str, filename: Option<&str>) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Uploaded {
+            content_uri: String,
+        }
+
+        let mut params = self.with_auth(HashMap::new())?;
+        if let Some(f) = filename {
+            params.insert("filename", f);
+        }
+
+        let url = self.api_url(&["media", "v3"], "/upload", Some(&params))?;
+        self.execute(Method::POST, url, "/upload", None, |builder| {
+                builder.header(header::CONTENT_TYPE, content_type).body(data.to_vec())
+            })
+            .and_then(|r| r.json::<Uploaded>().map_err(|e| e.into()))
+            .map(|u| u.content_uri)
+    }
+
+    pub fn download(&self, mxc: &Mxc) -> Result<Media> {
+        let path = format!("/download/{}/{}", mxc.server_name, mxc.media_id);
+        self.get_media(&path, HashMap::new())
+    }
+
+    /// Download a thumbnail of an image scaled to fit within the given size.
+    pub fn thumbnail(&self, mxc: &Mxc, width: u32, height: u32) -> Result<Media> {
+        let width_str = width.to_string();
+        let height_str = height.to_string();
+        let params = hashmap! {
+            "width"  => width_str.as_str(),
+            "height" => height_str.as_str(),
+            "method" => "scale",
+        };
+
+        let path = format!("/thumbnail/{}/{}", mxc.server_name, mxc.media_id);
+        self.get_media(&path, params)
+    }
+
+    /// Fetch from the authenticated media endpoints, falling back to the
+    /// legacy unauthenticated ones on servers which don't have them yet.
+    fn get_media(&self, path: &str, params: HashMap<&str, &str>) -> Result<Media> {
+        let params = self.with_auth(params)?;
+        let url = self.api_url(&["client", "v1", "media"], path, Some(&params))?;
+        let response = match self.execute(Method::GET, url, path, None, |b| b) {
+            Err(Error::Matrix { errcode, .. }) if errcode == "M_UNRECOGNIZED" => {
+                let url = self.api_url(&["media", "v3"], path, Some(&params))?;
+                self.execute(Method::GET, url, path, None, |b| b)?
+            },
+            other => other?,
+        };
+
+        let content_type = response.headers().get(header::CONTENT_TYPE)
+                                   .and_then(|v| v.to_str().ok())
+                                   .map(|v| v.to_string());
+
+        Ok(Media {
+            content_type,
+            data: response.bytes()?.to_vec(),
+        })
+    }
+
+    /// Send a message referring to uploaded media. `msgtype` is one of
+    /// `m.image`, `m.file`, `m.audio` or `m.video`.
+    pub fn send_media(&self, room_id: &str, msgtype: &str, filename: &str, mxc: &str, info: &Value) -> Result<EventId> {
+        let data = json!({
+            "msgtype": msgtype,
+            "body": filename,
+            "filename": filename,
+            "url": mxc,
+            "info": info,
+        });
+
+        self.send(room_id, "m.room.message", &data)
     }
 
-    pub fn kick(&self, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<Response> {
+    /// Upload an image and send it to a room.
+    pub fn send_image(&self, room_id: &str, filename: &str, data: &[u8], content_type: &str) -> Result<EventId> {
+        let mxc = self.upload(data, content_type, Some(filename))?;
+        self.send_media(room_id, "m.image", filename, &mxc, &media_info(data, content_type))
+    }
+
+    /// Upload a file and send it to a room.
+    pub fn send_file(&self, room_id: &str, filename: &str, data: &[u8], content_type: &str) -> Result<EventId> {
+        let mxc = self.upload(data, content_type, Some(filename))?;
+        self.send_media(room_id, "m.file", filename, &mxc, &media_info(data, content_type))
+    }
+
+    pub fn kick(&self, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<()> {
         let path = format!("/rooms/{}/kick", room_id);
 
         let mut data = hashmap! {
@@ -243,9 +1234,10 @@ impl MatrixClient {
         }
 
         self.auth_query(Method::POST, &path, None, Some(&data), None)
+            .map(|_| ())
     }
 
-    pub fn ban(&self, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<Response> {
+    pub fn ban(&self, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<()> {
         let path = format!("/rooms/{}/ban", room_id);
 
         let mut data = hashmap! {
@@ -257,6 +1249,7 @@ impl MatrixClient {
         }
 
         self.auth_query(Method::POST, &path, None, Some(&data), None)
+            .map(|_| ())
     }
 
     pub fn get_joined(&self) -> Result<JoinedRooms> {
@@ -271,6 +1264,12 @@ impl MatrixClient {
         res.map(|v| v.name)
     }
 
+    /// Get the full current state of a room
+    pub fn get_room_state(&self, room_id: &str) -> Result<Vec<Event>> {
+        self.auth_get(&format!("/rooms/{}/state", room_id), None, None)
+            .and_then(|o| o.json().map_err(|e| e.into()))
+    }
+
     pub fn get_directory(&self, search_term: &str, limit: Option<u32>) -> Result<UserDirectory> {
         #[derive(Serialize)]
         struct Query<'a> {
@@ -310,7 +1309,7 @@ impl MatrixClient {
             .and_then(|o| o.json().map_err(|e| e.into()))
     }
 
-    pub fn indicate_typing(&self, room_id: &str, length: Option<Duration>) -> Result<Response> {
+    pub fn indicate_typing(&self, room_id: &str, length: Option<Duration>) -> Result<()> {
         #[derive(Serialize)]
         struct Data {
             typing: bool,
@@ -327,9 +1326,32 @@ impl MatrixClient {
         let path = format!("/rooms/{}/typing/{}", room_id,
                            self.user_id.as_ref().expect("Must be logged in"));
         self.auth_query(Method::PUT, &path, None, Some(&data), None)
+            .map(|_| ())
     }
 
     pub fn get_user_id(&self) -> Option<&str> {
         self.user_id.as_deref()
     }
-}
\ No newline at end of file
+
+    pub fn get_device_id(&self) -> Option<&str> {
+        self.device_id.as_deref()
+    }
+}
+
+
+/// The keys `Crypto` has for the server: the device keys if they weren't
+/// uploaded yet, and when `published` (the number of one-time keys the server
+/// holds) is given, fresh one-time keys to top the server back up.
+fn key_upload(crypto: &mut Crypto, published: Option<u64>) -> Value {
+    let mut data = serde_json::Map::new();
+
+    if let Some(keys) = crypto.device_keys() {
+        data.insert("device_keys".to_string(), keys);
+    }
+
+    if let Some(count) = published {
+        data.insert("one_time_keys".to_string(), crypto.one_time_keys(count));
+    }
+
+    Value::Object(data)
+}
@@ -3,42 +3,99 @@ use std::fs::File;
 
 use toml::value::Table;
 
+use crate::graph::Graph;
 
-#[derive(Deserialize, Debug)]
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
     pub connection: Connection,
     pub bot: Bot,
     pub services: Option<Table>,
+    /// Run as an application service instead of syncing as a regular user
+    pub appservice: Option<AppService>,
+    /// Changes to, or a replacement of, the built in node graph
+    pub graph: Option<Graph>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Connection {
     pub server: String,
     pub username: String,
-    pub password: String,
+    pub password: Option<String>,
+    pub access_token: Option<String>,
+    pub sync_timeout: Option<u64>,
+    /// Secret the saved encryption keys are encrypted with
+    pub pickle_key: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct AppService {
+    pub id: String,
+    /// Address the transaction listener binds to
+    pub bind: String,
+    /// Url the homeserver reaches the listener at
+    pub url: String,
+    pub as_token: Option<String>,
+    pub hs_token: Option<String>,
+    /// Regex of the user ids which may be puppeted, besides the bot's own
+    pub users: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Bot {
     pub display_name: String,
     pub prefix: String,
     pub rooms: Vec<String>,
     pub admins: Vec<String>,
     pub ignore: Vec<String>,
+    #[serde(default)]
+    pub backlog: Backlog,
+    /// Whether edited messages may trigger commands
+    #[serde(default)]
+    pub edits: bool,
+    /// Whether admins get a direct message when a node panics
+    #[serde(default)]
+    pub notify_panics: bool,
 }
 
 
+/// The config file rustix runs with, in the current working directory
+pub const CONFIG_FILE: &str = "config.toml";
+
 pub fn load_config(filename: &str) -> Config {
-    let mut f = File::open(filename).unwrap_or_else(|_| panic!("Missing required file: {}", filename));
+    read_config(filename).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Read and parse a config file, explaining what's wrong with it otherwise
+pub fn read_config(filename: &str) -> Result<Config, String> {
+    let mut f = File::open(filename).map_err(|_| format!("Missing required file: {}", filename))?;
     let mut config_data = String::new();
-    f.read_to_string(&mut config_data).unwrap_or_else(|_| panic!("Could not read {}", filename));
+    f.read_to_string(&mut config_data).map_err(|_| format!("Could not read {}", filename))?;
+
+    toml::from_str(&config_data).map_err(|e| format!("Bad config file formatting: {}", e))
+}
+
 
-    toml::from_str(&config_data).expect("Bad config file formatting")
+/// How much of the events missed while the bot was offline should be processed
+/// when resuming from a saved sync token.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Backlog {
+    /// Process everything the server hands back
+    All,
+    /// Skip everything which happened while offline
+    #[default]
+    None,
+    /// Only process events sent in the N minutes before startup, going by
+    /// the same cutoff time in every room
+    Minutes(u64),
 }
 
 
-#[derive(PartialEq)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
 pub enum RemovalMode {
+    #[default]
     Kick,
     Ban,
 }
@@ -0,0 +1,849 @@
+use std::collections::{HashMap, HashSet};
+use std::{env, mem, result};
+
+use itertools::Itertools;
+use serde_json::{json, Map, Value};
+use sha3::{Digest, Sha3_256};
+use vodozemac::{Curve25519PublicKey, Ed25519PublicKey, Ed25519Signature};
+use vodozemac::olm::{self, Account, AccountPickle, OlmMessage, Session, SessionPickle};
+use vodozemac::megolm::{self, GroupSession, GroupSessionPickle, InboundGroupSession,
+                        InboundGroupSessionPickle, MegolmMessage, SessionKey};
+
+use crate::matrix_types::{Event, ToDeviceEvent};
+use crate::state;
+
+
+type Result<T> = result::Result<T, String>;
+
+pub const OLM_ALGORITHM: &str = "m.olm.v1.curve25519-aes-sha2";
+pub const MEGOLM_ALGORITHM: &str = "m.megolm.v1.aes-sha2";
+const OTK_ALGORITHM: &str = "signed_curve25519";
+
+// Directory of the state directory the crypto account and sessions are saved
+// in. Each is saved to a file of its own, so only what changed gets written.
+const CRYPTO_STATE: &str = "crypto";
+
+// Environment variable the pickle key may be given in instead of the config
+const PICKLE_KEY_VAR: &str = "RUSTIX_PICKLE_KEY";
+
+// Outbound megolm sessions are replaced after this many messages
+const MEGOLM_ROTATION_MESSAGES: u32 = 100;
+
+
+/// A device which passed signature verification of its published keys.
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub user_id: String,
+    pub device_id: String,
+    pub curve25519: String,
+    pub ed25519: String,
+}
+
+/// Key the saved account and sessions are encrypted with.
+#[derive(Clone)]
+pub struct PickleKey([u8; 32]);
+
+impl PickleKey {
+    /// The key from the `RUSTIX_PICKLE_KEY` environment variable, or else the
+    /// configured one. Secrets of any length are hashed down to a key.
+    pub fn find(configured: Option<&str>) -> Option<Self> {
+        let secret = env::var(PICKLE_KEY_VAR).ok()
+                         .or_else(|| configured.map(String::from))
+                         .filter(|s| !s.is_empty())?;
+
+        let mut key = [0; 32];
+        key.copy_from_slice(&Sha3_256::digest(secret.as_bytes()));
+
+        Some(PickleKey(key))
+    }
+}
+
+// Inbound megolm sessions are told apart by the curve25519 key of the device
+// which shared them along with their id, so no device can replace a session
+// shared by another
+type InboundKey = (String, String);
+
+struct InboundSession {
+    session: InboundGroupSession,
+    room_id: String,
+    // User who shared the session, the only one whose events it decrypts
+    sender: String,
+    // Event id and timestamp of the event each message index was decrypted
+    // in. An index showing up in another event is a replay.
+    seen: HashMap<u32, (String, u64)>,
+}
+
+struct OutboundSession {
+    session: GroupSession,
+    // Curve25519 keys of the devices the session key has been sent to
+    shared_with: HashSet<String>,
+}
+
+/// A part of the crypto state which is saved to a file of its own.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Entry {
+    // The identity account, along with which rooms are encrypted
+    Account,
+    // Olm sessions with the device of a curve25519 key
+    Olm(String),
+    Inbound(InboundKey),
+    // Outbound megolm session of a room
+    Outbound(String),
+}
+
+impl Entry {
+    fn file_name(&self) -> String {
+        let (kind, key) = match self {
+            Entry::Account => return format!("{}/account", CRYPTO_STATE),
+            Entry::Olm(sender_key) => ("olm", sender_key.clone()),
+            Entry::Inbound((sender_key, session_id)) => ("inbound", format!("{}|{}", sender_key, session_id)),
+            Entry::Outbound(room_id) => ("outbound", room_id.clone()),
+        };
+
+        // Keys and room ids have characters which don't belong in file names
+        let hash = Sha3_256::digest(key.as_bytes());
+        format!("{}/{}-{}", CRYPTO_STATE, kind, base16ct::lower::encode_string(&hash))
+    }
+}
+
+// Saved forms of the entries, the account and sessions are pickled encrypted
+
+#[derive(Deserialize)]
+struct AccountState {
+    user_id: String,
+    device_id: String,
+    account: String,
+    keys_uploaded: bool,
+    rooms: HashMap<String, bool>,
+}
+
+#[derive(Deserialize)]
+struct OlmState {
+    sender_key: String,
+    sessions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct InboundState {
+    sender_key: String,
+    room_id: String,
+    sender: String,
+    session: String,
+    #[serde(default)]
+    seen: HashMap<u32, (String, u64)>,
+}
+
+#[derive(Deserialize)]
+struct OutboundState {
+    room_id: String,
+    session: String,
+    shared_with: HashSet<String>,
+}
+
+/// Olm and megolm state of this device: the identity account, olm sessions
+/// with other devices (keyed by their curve25519 key), megolm sessions for
+/// decrypting room events (keyed by sender key and session id) and the
+/// outbound megolm session of each encrypted room.
+pub struct Crypto {
+    user_id: String,
+    device_id: String,
+    account: Account,
+    keys_uploaded: bool,
+    sessions: HashMap<String, Vec<Session>>,
+    inbound: HashMap<InboundKey, InboundSession>,
+    outbound: HashMap<String, OutboundSession>,
+    // Whether each room we've seen is known to be encrypted
+    rooms: HashMap<String, bool>,
+    pickle_key: PickleKey,
+    // Entries changed since they were last saved
+    changed: HashSet<Entry>,
+}
+
+/// Matrix signs the canonical JSON form of an object, minus its `signatures`
+/// and `unsigned` fields. serde_json keeps object keys sorted, so compact
+/// output is canonical for the objects exchanged here.
+fn canonical_json(value: &Value) -> String {
+    let mut value = value.clone();
+    if let Some(obj) = value.as_object_mut() {
+        obj.remove("signatures");
+        obj.remove("unsigned");
+    }
+
+    value.to_string()
+}
+
+/// Check the ed25519 signature made by `user_id`'s `key_id` over `value`.
+fn verify_signature(value: &Value, user_id: &str, key_id: &str, ed25519: &str) -> bool {
+    let Some(signature) = value["signatures"][user_id][key_id].as_str() else {
+        return false
+    };
+
+    match (Ed25519PublicKey::from_base64(ed25519), Ed25519Signature::from_base64(signature)) {
+        (Ok(key), Ok(sig)) => key.verify(canonical_json(value).as_bytes(), &sig).is_ok(),
+        _ => false,
+    }
+}
+
+/// Extract a device from its published device keys, provided the keys are
+/// self-signed and belong to the device they claim to.
+pub fn verify_device(user_id: &str, device_id: &str, keys: &Value) -> Option<Device> {
+    if keys["user_id"] != user_id || keys["device_id"] != device_id {
+        return None;
+    }
+
+    let ed_id = format!("ed25519:{}", device_id);
+    let curve25519 = keys["keys"][format!("curve25519:{}", device_id)].as_str()?;
+    let ed25519 = keys["keys"][&ed_id].as_str()?;
+
+    if !verify_signature(keys, user_id, &ed_id, ed25519) {
+        return None;
+    }
+
+    Some(Device {
+        user_id: user_id.to_string(),
+        device_id: device_id.to_string(),
+        curve25519: curve25519.to_string(),
+        ed25519: ed25519.to_string(),
+    })
+}
+
+/// Move the crypto state of another device, e.g. one replaced by logging in
+/// again, out of the way instead of dropping its sessions and room keys.
+fn set_aside_state() -> Result<()> {
+    let stale = state::list_state(CRYPTO_STATE);
+    if stale.is_empty() {
+        return Ok(());
+    }
+
+    let aside = format!("{}-replaced-{}", CRYPTO_STATE, chrono::Utc::now().format("%Y%m%d%H%M%S"));
+    println!("Moving the crypto state of the previous device to {}: {}", aside, stale.join(", "));
+
+    state::rename_state(CRYPTO_STATE, &aside)
+         .map_err(|e| format!("Unable to move the previous crypto state to {}: {}", aside, e))
+}
+
+
+impl Crypto {
+    /// Load the saved crypto state for this device, or create a new identity
+    /// if there is none or it belongs to a different device, in which case the
+    /// old state is moved aside. Fails when the saved state can't be decrypted
+    /// with `pickle_key`, rather than replacing the identity.
+    pub fn load(user_id: &str, device_id: &str, pickle_key: PickleKey) -> Result<Self> {
+        let saved = state::load_state(&Entry::Account.file_name())
+                         .and_then(|s| serde_json::from_str::<AccountState>(&s).ok())
+                         .filter(|a| a.user_id == user_id && a.device_id == device_id);
+
+        let mut crypto = Crypto {
+            user_id: user_id.to_string(),
+            device_id: device_id.to_string(),
+            account: Account::new(),
+            keys_uploaded: false,
+            sessions: HashMap::new(),
+            inbound: HashMap::new(),
+            outbound: HashMap::new(),
+            rooms: HashMap::new(),
+            pickle_key,
+            changed: HashSet::new(),
+        };
+
+        let Some(saved) = saved else {
+            println!("Creating new encryption keys for device {}", device_id);
+            set_aside_state()?;
+            crypto.changed.insert(Entry::Account);
+            return Ok(crypto);
+        };
+
+        let account = AccountPickle::from_encrypted(&saved.account, &crypto.pickle_key.0)
+                                    .map_err(|e| format!("Unable to decrypt the saved encryption keys, \
+                                                          was the pickle key changed? {}", e))?;
+        crypto.account = Account::from_pickle(account);
+        crypto.keys_uploaded = saved.keys_uploaded;
+        crypto.rooms = saved.rooms;
+
+        for name in state::list_state(CRYPTO_STATE) {
+            if let Err(e) = crypto.load_entry(&name) {
+                println!("Ignoring crypto state {}: {}", name, e);
+            }
+        }
+
+        Ok(crypto)
+    }
+
+    fn load_entry(&mut self, name: &str) -> Result<()> {
+        let kind = name.rsplit('/').next().and_then(|f| f.split('-').next());
+        if kind == Some("account") {
+            return Ok(());
+        }
+
+        let data = state::load_state(name).ok_or("Unreadable file")?;
+        let key = &self.pickle_key.0;
+
+        match kind {
+            Some("olm") => {
+                let saved: OlmState = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+                let sessions = saved.sessions.iter()
+                                    .map(|s| SessionPickle::from_encrypted(s, key).map(Session::from_pickle))
+                                    .collect::<result::Result<_, _>>()
+                                    .map_err(|e| e.to_string())?;
+                self.sessions.insert(saved.sender_key, sessions);
+            },
+            Some("inbound") => {
+                let saved: InboundState = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+                let session = InboundGroupSessionPickle::from_encrypted(&saved.session, key)
+                                                        .map_err(|e| e.to_string())?;
+                let session = InboundGroupSession::from_pickle(session);
+                self.inbound.insert((saved.sender_key, session.session_id()), InboundSession {
+                    session,
+                    room_id: saved.room_id,
+                    sender: saved.sender,
+                    seen: saved.seen,
+                });
+            },
+            Some("outbound") => {
+                let saved: OutboundState = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+                let session = GroupSessionPickle::from_encrypted(&saved.session, key).map_err(|e| e.to_string())?;
+                self.outbound.insert(saved.room_id, OutboundSession {
+                    session: GroupSession::from_pickle(session),
+                    shared_with: saved.shared_with,
+                });
+            },
+            _ => return Err("Unknown file".to_string()),
+        }
+
+        Ok(())
+    }
+
+    /// Save the entries which changed since they were last saved.
+    pub fn save(&mut self) {
+        for entry in mem::take(&mut self.changed) {
+            match self.pickle(&entry) {
+                Some(data) => state::save_state(&entry.file_name(), &data.to_string()),
+                None => state::remove_state(&entry.file_name()),
+            }
+        }
+    }
+
+    /// The saved form of an entry, if it still exists.
+    fn pickle(&self, entry: &Entry) -> Option<Value> {
+        let key = &self.pickle_key.0;
+
+        match entry {
+            Entry::Account => Some(json!({
+                "user_id": self.user_id,
+                "device_id": self.device_id,
+                "account": self.account.pickle().encrypt(key),
+                "keys_uploaded": self.keys_uploaded,
+                "rooms": self.rooms,
+            })),
+            Entry::Olm(sender_key) => self.sessions.get(sender_key).map(|sessions| json!({
+                "sender_key": sender_key,
+                "sessions": sessions.iter().map(|s| s.pickle().encrypt(key)).collect::<Vec<_>>(),
+            })),
+            Entry::Inbound((sender_key, session_id)) => {
+                self.inbound.get(&(sender_key.clone(), session_id.clone())).map(|inbound| json!({
+                    "sender_key": sender_key,
+                    "room_id": inbound.room_id,
+                    "sender": inbound.sender,
+                    "session": inbound.session.pickle().encrypt(key),
+                    "seen": inbound.seen,
+                }))
+            },
+            Entry::Outbound(room_id) => self.outbound.get(room_id).map(|outbound| json!({
+                "room_id": room_id,
+                "session": outbound.session.pickle().encrypt(key),
+                "shared_with": outbound.shared_with,
+            })),
+        }
+    }
+
+    pub fn curve25519_key(&self) -> String {
+        self.account.curve25519_key().to_base64()
+    }
+
+    pub fn ed25519_key(&self) -> String {
+        self.account.ed25519_key().to_base64()
+    }
+
+    fn sign(&self, value: &mut Value) {
+        let signature = self.account.sign(canonical_json(value)).to_base64();
+        value["signatures"] = json!({
+            &self.user_id: { format!("ed25519:{}", self.device_id): signature }
+        });
+    }
+
+    /// The signed device keys, if they haven't been uploaded yet.
+    pub fn device_keys(&self) -> Option<Value> {
+        if self.keys_uploaded {
+            return None;
+        }
+
+        let mut keys = json!({
+            "user_id": self.user_id,
+            "device_id": self.device_id,
+            "algorithms": [OLM_ALGORITHM, MEGOLM_ALGORITHM],
+            "keys": {
+                format!("curve25519:{}", self.device_id): self.curve25519_key(),
+                format!("ed25519:{}", self.device_id): self.ed25519_key(),
+            },
+        });
+        self.sign(&mut keys);
+
+        Some(keys)
+    }
+
+    /// Whether the server's count of our one-time keys is low enough that
+    /// more should be uploaded.
+    pub fn needs_one_time_keys(&self, published: u64) -> bool {
+        published < (self.account.max_number_of_one_time_keys() / 2) as u64
+    }
+
+    /// Generate signed one-time keys to top the server back up to half of
+    /// what the account can hold.
+    pub fn one_time_keys(&mut self, published: u64) -> Value {
+        let target = self.account.max_number_of_one_time_keys() / 2;
+        self.account.generate_one_time_keys(target.saturating_sub(published as usize));
+        self.changed.insert(Entry::Account);
+
+        let mut keys = Map::new();
+        for (key_id, key) in self.account.one_time_keys() {
+            let mut signed = json!({ "key": key.to_base64() });
+            self.sign(&mut signed);
+            keys.insert(format!("{}:{}", OTK_ALGORITHM, key_id.to_base64()), signed);
+        }
+
+        Value::Object(keys)
+    }
+
+    pub fn mark_keys_as_published(&mut self) {
+        self.account.mark_keys_as_published();
+        self.keys_uploaded = true;
+        self.changed.insert(Entry::Account);
+    }
+
+    pub fn is_encrypted(&self, room_id: &str) -> Option<bool> {
+        self.rooms.get(room_id).copied()
+    }
+
+    pub fn set_encrypted(&mut self, room_id: &str, encrypted: bool) {
+        if self.rooms.insert(room_id.to_string(), encrypted) != Some(encrypted) {
+            self.changed.insert(Entry::Account);
+        }
+    }
+
+    /// Forget the outbound session of a room so that the next message uses a
+    /// fresh key, e.g. after someone left and must not read future messages.
+    pub fn discard_outbound(&mut self, room_id: &str) {
+        if self.outbound.remove(room_id).is_some() {
+            self.changed.insert(Entry::Outbound(room_id.to_string()));
+        }
+    }
+
+    /// Decrypt an olm encrypted to-device event and act on its payload, which
+    /// must carry the keys of the sending device, one of the sender's known
+    /// `devices`, and of this device. Currently only `m.room_key` payloads are
+    /// of interest.
+    pub fn handle_to_device(&mut self, event: &ToDeviceEvent, devices: &[Device]) -> Result<()> {
+        let content = &event.content;
+        if content["algorithm"] != OLM_ALGORITHM {
+            return Err(format!("Unsupported to-device algorithm {}", content["algorithm"]));
+        }
+
+        let sender_key = content["sender_key"].as_str().ok_or("Missing sender key")?;
+        let device = devices.iter()
+                            .find(|d| d.user_id == event.sender && d.curve25519 == sender_key)
+                            .ok_or("Sent from an unknown device")?;
+        let ciphertext = content["ciphertext"].get(self.curve25519_key())
+                                              .ok_or("Event was not encrypted for this device")?;
+        let message: OlmMessage = serde_json::from_value(ciphertext.clone())
+                                             .map_err(|e| e.to_string())?;
+
+        let plaintext = self.olm_decrypt(sender_key, &message)?;
+        let payload: Value = serde_json::from_slice(&plaintext).map_err(|e| e.to_string())?;
+
+        if payload["sender"] != event.sender.as_str() || payload["recipient"] != self.user_id.as_str() {
+            return Err("Olm payload sender or recipient mismatch".to_string());
+        }
+
+        if payload["keys"]["ed25519"] != device.ed25519.as_str() ||
+           payload["recipient_keys"]["ed25519"] != self.ed25519_key().as_str() {
+            return Err("Olm payload keys don't match the sending or receiving device".to_string());
+        }
+
+        if payload["type"] == "m.room_key" {
+            self.add_room_key(&event.sender, sender_key, &payload["content"])?;
+        }
+
+        Ok(())
+    }
+
+    fn olm_decrypt(&mut self, sender_key: &str, message: &OlmMessage) -> Result<Vec<u8>> {
+        if let Some(sessions) = self.sessions.get_mut(sender_key) {
+            for session in sessions.iter_mut() {
+                if let Ok(plaintext) = session.decrypt(message) {
+                    self.changed.insert(Entry::Olm(sender_key.to_string()));
+                    return Ok(plaintext);
+                }
+            }
+        }
+
+        // Only pre-key messages can establish a new session
+        let OlmMessage::PreKey(pre_key) = message else {
+            return Err("No olm session could decrypt the message".to_string());
+        };
+
+        let identity = Curve25519PublicKey::from_base64(sender_key).map_err(|e| e.to_string())?;
+        let created = self.account.create_inbound_session(identity, pre_key)
+                                  .map_err(|e| e.to_string())?;
+
+        self.sessions.entry(sender_key.to_string()).or_default().push(created.session);
+        // Creating the session used up one of the account's one-time keys
+        self.changed.insert(Entry::Account);
+        self.changed.insert(Entry::Olm(sender_key.to_string()));
+
+        Ok(created.plaintext)
+    }
+
+    /// Add a megolm session shared by `sender`'s device with curve25519 key
+    /// `sender_key`. The first key received for a session is kept, a later
+    /// one can't take its place.
+    fn add_room_key(&mut self, sender: &str, sender_key: &str, content: &Value) -> Result<()> {
+        if content["algorithm"] != MEGOLM_ALGORITHM {
+            return Err(format!("Unsupported room key algorithm {}", content["algorithm"]));
+        }
+
+        let room_id = content["room_id"].as_str().ok_or("Room key without room id")?;
+        let session_id = content["session_id"].as_str().ok_or("Room key without session id")?;
+        let key = content["session_key"].as_str().ok_or("Room key without session key")?;
+        let key = SessionKey::from_base64(key).map_err(|e| e.to_string())?;
+
+        let inbound_key = (sender_key.to_string(), session_id.to_string());
+        if self.inbound.contains_key(&inbound_key) {
+            return Ok(());
+        }
+
+        let session = InboundGroupSession::new(&key, megolm::SessionConfig::version_1());
+        if session.session_id() != session_id {
+            return Err("Room key doesn't belong to its session id".to_string());
+        }
+
+        self.inbound.insert(inbound_key.clone(), InboundSession {
+            session,
+            room_id: room_id.to_string(),
+            sender: sender.to_string(),
+            seen: HashMap::new(),
+        });
+        self.changed.insert(Entry::Inbound(inbound_key));
+        self.set_encrypted(room_id, true);
+
+        Ok(())
+    }
+
+    /// The inbound session a room event was encrypted with. Newer clients
+    /// leave the sender key out of events, the session id alone must then be
+    /// unambiguous.
+    fn find_inbound(&self, sender_key: Option<&str>, session_id: &str) -> Option<InboundKey> {
+        match sender_key {
+            Some(sender_key) => {
+                let key = (sender_key.to_string(), session_id.to_string());
+                self.inbound.contains_key(&key).then_some(key)
+            },
+            None => self.inbound.keys().filter(|(_, id)| id == session_id).exactly_one().ok().cloned(),
+        }
+    }
+
+    /// Replace the content and type of a megolm encrypted room event with the
+    /// decrypted ones. The session must belong to the room and to the user
+    /// who sent the event, and each of its message indices may only be used
+    /// by one event.
+    pub fn decrypt_room_event(&mut self, room_id: &str, event: &mut Event) -> Result<()> {
+        let content = &event.content;
+        if content["algorithm"] != MEGOLM_ALGORITHM {
+            return Err(format!("Unsupported room algorithm {}", content["algorithm"]));
+        }
+
+        let session_id = content["session_id"].as_str().ok_or("Missing session id")?;
+        let key = self.find_inbound(content["sender_key"].as_str(), session_id)
+                      .ok_or("Room key for the event has not been received")?;
+        let inbound = self.inbound.get_mut(&key).unwrap();
+        if inbound.room_id != room_id || inbound.sender != event.sender {
+            return Err(format!("Session {} doesn't belong to {} in this room", session_id, event.sender));
+        }
+
+        let ciphertext = content["ciphertext"].as_str().ok_or("Missing ciphertext")?;
+        let message = MegolmMessage::from_base64(ciphertext).map_err(|e| e.to_string())?;
+        let decrypted = inbound.session.decrypt(&message).map_err(|e| e.to_string())?;
+
+        let payload: Value = serde_json::from_slice(&decrypted.plaintext)
+                                        .map_err(|e| e.to_string())?;
+        if payload["room_id"] != room_id {
+            return Err("Decrypted event belongs to a different room".to_string());
+        }
+
+        let event_id = event.event_id.clone().ok_or("Encrypted event without event id")?;
+        let origin = (event_id, event.origin_server_ts.unwrap_or_default());
+        match inbound.seen.get(&decrypted.message_index) {
+            Some(seen) if *seen != origin => {
+                return Err(format!("Message index {} of session {} was already used by {}",
+                                   decrypted.message_index, session_id, seen.0));
+            },
+            Some(_) => (),
+            None => {
+                inbound.seen.insert(decrypted.message_index, origin);
+                self.changed.insert(Entry::Inbound(key));
+            },
+        }
+
+        let relation = event.content.get("m.relates_to").cloned();
+
+        event.type_ = payload["type"].as_str().ok_or("Decrypted event without type")?.to_string();
+        event.content = payload["content"].clone();
+
+        if let (Some(relation), Some(content)) = (relation, event.content.as_object_mut()) {
+            content.insert("m.relates_to".to_string(), relation);
+        }
+
+        Ok(())
+    }
+
+    fn outbound_session(&mut self, room_id: &str) -> &mut OutboundSession {
+        let expired = self.outbound.get(room_id)
+                          .is_none_or(|o| o.session.message_index() >= MEGOLM_ROTATION_MESSAGES);
+
+        if expired {
+            let session = GroupSession::new(megolm::SessionConfig::version_1());
+
+            // Keep a copy so our own messages can be decrypted too
+            let inbound_key = (self.curve25519_key(), session.session_id());
+            self.inbound.insert(inbound_key.clone(), InboundSession {
+                session: InboundGroupSession::new(&session.session_key(), megolm::SessionConfig::version_1()),
+                room_id: room_id.to_string(),
+                sender: self.user_id.clone(),
+                seen: HashMap::new(),
+            });
+            self.changed.insert(Entry::Inbound(inbound_key));
+
+            self.outbound.insert(room_id.to_string(), OutboundSession {
+                session,
+                shared_with: HashSet::new(),
+            });
+            self.changed.insert(Entry::Outbound(room_id.to_string()));
+        }
+
+        self.outbound.get_mut(room_id).unwrap()
+    }
+
+    /// The outbound session of a room, unless it was replaced by another
+    fn current_outbound(&mut self, room_id: &str, session_id: &str) -> Result<&mut OutboundSession> {
+        self.outbound.get_mut(room_id)
+                     .filter(|o| o.session.session_id() == session_id)
+                     .ok_or_else(|| format!("Session {} of {} was replaced", session_id, room_id))
+    }
+
+    /// The id of the room's current outbound session, along with the devices
+    /// which don't have its key yet. Our own device is never included.
+    pub fn devices_without_key<'d>(&mut self, room_id: &str, devices: &'d [Device]) -> (String, Vec<&'d Device>) {
+        let own_key = self.curve25519_key();
+        let outbound = self.outbound_session(room_id);
+
+        let missing = devices.iter()
+                             .filter(|d| d.curve25519 != own_key && !outbound.shared_with.contains(&d.curve25519))
+                             .collect();
+
+        (outbound.session.session_id(), missing)
+    }
+
+    pub fn has_session(&self, device: &Device) -> bool {
+        self.sessions.get(&device.curve25519).is_some_and(|s| !s.is_empty())
+    }
+
+    /// Create an olm session with a device from one of its claimed one-time
+    /// keys, which must be signed by the device.
+    pub fn create_session(&mut self, device: &Device, key_id: &str, one_time_key: &Value) -> Result<()> {
+        let signer = format!("ed25519:{}", device.device_id);
+        if !verify_signature(one_time_key, &device.user_id, &signer, &device.ed25519) {
+            return Err(format!("Invalid one-time key signature for {} {}", device.user_id, key_id));
+        }
+
+        let key = one_time_key["key"].as_str().ok_or("One-time key missing")?;
+        let identity = Curve25519PublicKey::from_base64(&device.curve25519).map_err(|e| e.to_string())?;
+        let otk = Curve25519PublicKey::from_base64(key).map_err(|e| e.to_string())?;
+
+        let session = self.account.create_outbound_session(olm::SessionConfig::version_1(), identity, otk);
+        self.sessions.entry(device.curve25519.clone()).or_default().push(session);
+        self.changed.insert(Entry::Olm(device.curve25519.clone()));
+
+        Ok(())
+    }
+
+    /// Olm encrypt the key of the room's outbound session `session_id` for a
+    /// device, returning the content of the `m.room.encrypted` to-device event.
+    pub fn encrypt_room_key(&mut self, room_id: &str, session_id: &str, device: &Device) -> Result<Value> {
+        let session_key = {
+            let outbound = self.current_outbound(room_id, session_id)?;
+            json!({
+                "algorithm": MEGOLM_ALGORITHM,
+                "room_id": room_id,
+                "session_id": outbound.session.session_id(),
+                "session_key": outbound.session.session_key().to_base64(),
+            })
+        };
+
+        let payload = json!({
+            "type": "m.room_key",
+            "content": session_key,
+            "sender": self.user_id,
+            "sender_device": self.device_id,
+            "keys": { "ed25519": self.ed25519_key() },
+            "recipient": device.user_id,
+            "recipient_keys": { "ed25519": device.ed25519 },
+        });
+
+        let sender_key = self.curve25519_key();
+        let session = self.sessions.get_mut(&device.curve25519)
+                                   .and_then(|s| s.last_mut())
+                                   .ok_or("No olm session with device")?;
+        let message = session.encrypt(payload.to_string());
+        self.changed.insert(Entry::Olm(device.curve25519.clone()));
+
+        Ok(json!({
+            "algorithm": OLM_ALGORITHM,
+            "sender_key": sender_key,
+            "ciphertext": { &device.curve25519: message },
+        }))
+    }
+
+    pub fn mark_shared(&mut self, room_id: &str, session_id: &str, devices: &[&Device]) {
+        if let Ok(outbound) = self.current_outbound(room_id, session_id) {
+            outbound.shared_with.extend(devices.iter().map(|d| d.curve25519.clone()));
+            self.changed.insert(Entry::Outbound(room_id.to_string()));
+        }
+    }
+
+    /// Megolm encrypt an event with the room's outbound session `session_id`,
+    /// returning the content of the `m.room.encrypted` event to send in its
+    /// place. Fails if the session was replaced since its key was shared.
+    pub fn encrypt_room_event(&mut self, room_id: &str, session_id: &str,
+                              event_type: &str, content: &Value) -> Result<Value> {
+        let payload = json!({
+            "type": event_type,
+            "content": content,
+            "room_id": room_id,
+        });
+
+        let sender_key = self.curve25519_key();
+        let device_id = self.device_id.clone();
+        let outbound = self.current_outbound(room_id, session_id)?;
+        let message = outbound.session.encrypt(payload.to_string());
+        self.changed.insert(Entry::Outbound(room_id.to_string()));
+
+        let mut encrypted = json!({
+            "algorithm": MEGOLM_ALGORITHM,
+            "sender_key": sender_key,
+            "ciphertext": message.to_base64(),
+            "session_id": session_id,
+            "device_id": device_id,
+        });
+
+        // Relations (replies, threads) are left unencrypted so the server can
+        // aggregate them
+        if let Some(relation) = content.get("m.relates_to") {
+            encrypted["m.relates_to"] = relation.clone();
+        }
+
+        Ok(encrypted)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{message_event, test_state, TEST_ROOM, TEST_SENDER, TEST_USER};
+
+    fn crypto(user_id: &str, device_id: &str) -> Crypto {
+        Crypto::load(user_id, device_id, PickleKey([7; 32])).unwrap()
+    }
+
+    fn device(crypto: &Crypto) -> Device {
+        verify_device(&crypto.user_id, &crypto.device_id, &crypto.device_keys().unwrap()).unwrap()
+    }
+
+    /// Have `sender` share its room key with `bot`, which knows `known` as the
+    /// sender's devices. Returns the id of the shared session.
+    fn share_key(sender: &mut Crypto, bot: &mut Crypto, known: &[Device]) -> Result<String> {
+        let bot_device = device(bot);
+        let one_time_keys = bot.one_time_keys(0);
+        let (key_id, key) = one_time_keys.as_object().unwrap().iter().next().unwrap();
+        sender.create_session(&bot_device, key_id, key)?;
+
+        let (session_id, _) = sender.devices_without_key(TEST_ROOM, std::slice::from_ref(&bot_device));
+        let event = ToDeviceEvent {
+            content: sender.encrypt_room_key(TEST_ROOM, &session_id, &bot_device)?,
+            sender: sender.user_id.clone(),
+            type_: "m.room.encrypted".to_string(),
+        };
+        bot.handle_to_device(&event, known)?;
+
+        Ok(session_id)
+    }
+
+    #[test]
+    fn room_keys_only_decrypt_their_senders_events() {
+        let _state = test_state();
+        let mut bot = crypto(TEST_USER, "BOT");
+        let mut alice = crypto(TEST_SENDER, "ALICE");
+        let alice_device = device(&alice);
+        let session_id = share_key(&mut alice, &mut bot, &[alice_device]).unwrap();
+
+        let content = json!({ "msgtype": "m.text", "body": "hi" });
+        let encrypted = alice.encrypt_room_event(TEST_ROOM, &session_id, "m.room.message", &content).unwrap();
+        let mut event = message_event(TEST_SENDER, "");
+        event.type_ = "m.room.encrypted".to_string();
+        event.content = encrypted;
+
+        let mut forged = event.clone();
+        forged.sender = "@mallory:mock.server".to_string();
+        assert!(bot.decrypt_room_event(TEST_ROOM, &mut forged).is_err());
+        assert!(bot.decrypt_room_event("!elsewhere:mock.server", &mut event.clone()).is_err());
+
+        bot.decrypt_room_event(TEST_ROOM, &mut event).unwrap();
+        assert_eq!(event.type_, "m.room.message");
+        assert_eq!(event.content, content);
+    }
+
+    #[test]
+    fn room_keys_must_come_from_a_known_device() {
+        let _state = test_state();
+        let mut bot = crypto(TEST_USER, "BOT");
+        let mut alice = crypto(TEST_SENDER, "ALICE");
+        let impostor = crypto(TEST_SENDER, "IMPOSTOR");
+
+        assert!(share_key(&mut alice, &mut bot, &[]).is_err());
+        assert!(share_key(&mut alice, &mut bot, &[device(&impostor)]).is_err());
+        assert!(bot.inbound.is_empty());
+    }
+
+    #[test]
+    fn replayed_room_events_are_rejected() {
+        let _state = test_state();
+        let mut bot = crypto(TEST_USER, "BOT");
+        let mut alice = crypto(TEST_SENDER, "ALICE");
+        let alice_device = device(&alice);
+        let session_id = share_key(&mut alice, &mut bot, &[alice_device]).unwrap();
+
+        let content = json!({ "msgtype": "m.text", "body": "!roulette" });
+        let encrypted = alice.encrypt_room_event(TEST_ROOM, &session_id, "m.room.message", &content).unwrap();
+        let mut event = message_event(TEST_SENDER, "");
+        event.type_ = "m.room.encrypted".to_string();
+        event.content = encrypted;
+
+        let mut replayed = event.clone();
+        replayed.event_id = Some("$replayed".to_string());
+
+        bot.decrypt_room_event(TEST_ROOM, &mut event.clone()).unwrap();
+        assert!(bot.decrypt_room_event(TEST_ROOM, &mut replayed).is_err());
+        // The same event may still be decrypted again, e.g. when synced twice
+        bot.decrypt_room_event(TEST_ROOM, &mut event).unwrap();
+        assert_eq!(event.content, content);
+    }
+}
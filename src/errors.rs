@@ -1,9 +1,43 @@
+use std::fmt;
+
+use reqwest::StatusCode;
+
+
 #[derive(Debug)]
 pub enum Error {
     Serde(serde_json::Error),
     Reqwest(reqwest::Error),
     Generic(String),
     UrlParse(url::ParseError),
+    /// An error response returned by the matrix server
+    Matrix { status: StatusCode, errcode: String, error: String },
+}
+
+impl Error {
+    /// The matrix error code, if this error came from the matrix server
+    pub fn errcode(&self) -> Option<&str> {
+        match self {
+            Error::Matrix { errcode, .. } => Some(errcode),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Serde(e) => write!(f, "{}", e),
+            Error::Reqwest(e) => write!(f, "{}", e),
+            Error::Generic(s) => write!(f, "{}", s),
+            Error::UrlParse(e) => write!(f, "{}", e),
+            Error::Matrix { status, errcode, error } if error.is_empty() => {
+                write!(f, "{} ({})", errcode, status)
+            },
+            Error::Matrix { status, errcode, error } => {
+                write!(f, "{} ({}, {})", error, errcode, status)
+            },
+        }
+    }
 }
 
 impl From<reqwest::Error> for Error {
@@ -30,7 +64,7 @@ impl From<String> for Error {
     }
 }
 
-impl<'a> From<&'a str> for Error {
+impl From<&str> for Error {
     fn from(err: &str) -> Error {
         Error::Generic(err.into())
     }
@@ -0,0 +1,274 @@
+//! Export of the node graph as Graphviz DOT or Mermaid, for graphs too big to
+//! read as the tree `structure` replies with. Nodes are shown along with their
+//! type, description and what their filter lets through.
+
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+use crate::bot::NodeInfo;
+use crate::config::Config;
+use crate::filters::{filter_status, ReactionFilter};
+use crate::graph::{self, Changes, ChannelFilterConfig, Context, NodeSpec, ReactionFilterConfig, UserFilterConfig};
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Dot,
+    Mermaid,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "dot" => Some(Format::Dot),
+            "mermaid" => Some(Format::Mermaid),
+            _ => None,
+        }
+    }
+
+    /// Name of the file an export is uploaded as
+    pub fn filename(&self) -> &'static str {
+        match self {
+            Format::Dot => "rustix-graph.dot",
+            Format::Mermaid => "rustix-graph.mmd",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Dot => "text/vnd.graphviz",
+            Format::Mermaid => "text/plain",
+        }
+    }
+}
+
+/// A node as shown in an export
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportNode {
+    pub name: String,
+    pub type_name: Option<String>,
+    pub parent: Option<String>,
+    pub enabled: bool,
+    pub description: Option<String>,
+    /// Summary of the node's settings, see `Node::status`
+    pub status: Option<String>,
+}
+
+impl ExportNode {
+    fn label(&self) -> Vec<String> {
+        let mut lines = vec![self.name.clone()];
+        if let Some(type_name) = &self.type_name {
+            lines.push(format!("type: {}", type_name));
+        }
+        for text in self.status.iter().chain(&self.description) {
+            lines.extend(text.lines().map(String::from));
+        }
+        if !self.enabled {
+            lines.push("(disabled)".to_string());
+        }
+
+        lines
+    }
+}
+
+impl From<&NodeInfo> for ExportNode {
+    fn from(node: &NodeInfo) -> Self {
+        ExportNode {
+            name: node.name.clone(),
+            type_name: node.type_name.clone(),
+            parent: node.parent.clone(),
+            enabled: !node.disabled,
+            description: node.description.clone(),
+            status: node.status.clone(),
+        }
+    }
+}
+
+
+pub fn render(nodes: &[ExportNode], format: Format) -> String {
+    match format {
+        Format::Dot => dot(nodes),
+        Format::Mermaid => mermaid(nodes),
+    }
+}
+
+fn dot(nodes: &[ExportNode]) -> String {
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+    let mut out = vec!["digraph rustix {".to_string(), "    node [shape=box];".to_string()];
+
+    for node in nodes {
+        let label: String = node.label().iter().map(|l| escape(l) + "\\l").collect();
+        let style = if node.enabled { "" } else { ", style=dashed" };
+        out.push(format!("    \"{}\" [label=\"{}\"{}];", escape(&node.name), label, style));
+    }
+
+    for node in nodes {
+        if let Some(parent) = &node.parent {
+            out.push(format!("    \"{}\" -> \"{}\";", escape(parent), escape(&node.name)));
+        }
+    }
+
+    out.push("}\n".to_string());
+    out.join("\n")
+}
+
+fn mermaid(nodes: &[ExportNode]) -> String {
+    // Mermaid reads html in labels, and `#` starts an entity
+    let escape = |s: &str| s.replace('#', "#35;").replace('"', "#34;").replace('<', "#60;").replace('>', "#62;");
+    let ids: HashMap<&str, String> = nodes.iter().enumerate()
+                                          .map(|(i, n)| (n.name.as_str(), format!("n{}", i)))
+                                          .collect();
+    let mut out = vec!["flowchart TD".to_string()];
+
+    for node in nodes {
+        let label = node.label().iter().map(|l| escape(l)).join("<br/>");
+        out.push(format!("    {}[\"{}\"]", ids[node.name.as_str()], label));
+    }
+
+    for node in nodes {
+        if let Some(parent) = node.parent.as_deref().and_then(|p| ids.get(p)) {
+            out.push(format!("    {} --> {}", parent, ids[node.name.as_str()]));
+        }
+    }
+
+    let disabled: Vec<&str> = nodes.iter()
+                                   .filter(|n| !n.enabled)
+                                   .map(|n| ids[n.name.as_str()].as_str())
+                                   .collect();
+    if !disabled.is_empty() {
+        out.push("    classDef disabled stroke-dasharray: 5 5".to_string());
+        out.push(format!("    class {} disabled", disabled.join(",")));
+    }
+
+    out.join("\n") + "\n"
+}
+
+
+/// The nodes of a graph as configured, without building it. Only what's in
+/// the config is known this way, so there are no descriptions, and filters
+/// show the settings they start with.
+pub fn from_specs(ctx: &Context, nodes: &[NodeSpec]) -> Vec<ExportNode> {
+    nodes.iter().map(|spec| ExportNode {
+        name: spec.name.clone(),
+        type_name: Some(spec.type_name().to_string()),
+        parent: spec.parent.clone(),
+        enabled: spec.enabled,
+        description: None,
+        status: configured_status(ctx, spec),
+    }).collect()
+}
+
+/// The graph rustix runs with for `config`, including changes made at runtime
+pub fn configured(config: &Config) -> Vec<ExportNode> {
+    let mut nodes = graph::resolve(config);
+    Changes::load().apply(&mut nodes);
+
+    let ctx = Context {
+        config,
+        user_id: &config.connection.username,
+    };
+    from_specs(&ctx, &nodes)
+}
+
+fn configured_status(ctx: &Context, spec: &NodeSpec) -> Option<String> {
+    match spec.type_name() {
+        "user_filter" => {
+            let cfg: UserFilterConfig = graph::parse(&spec.config).ok()?;
+            Some(filter_status(cfg.allow, &cfg.users.resolve(ctx).ok()?))
+        },
+        "channel_filter" => {
+            let cfg: ChannelFilterConfig = graph::parse(&spec.config).ok()?;
+            Some(filter_status(cfg.allow, &cfg.channels))
+        },
+        "reaction_filter" => {
+            let cfg: ReactionFilterConfig = graph::parse(&spec.config).ok()?;
+            Some(ReactionFilter::describe(&cfg.keys))
+        },
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TEST_USER;
+
+    fn nodes() -> Vec<ExportNode> {
+        vec![
+            ExportNode {
+                name: "prefix".to_string(),
+                type_name: Some("prefix".to_string()),
+                parent: None,
+                enabled: true,
+                description: None,
+                status: None,
+            },
+            ExportNode {
+                name: "search".to_string(),
+                type_name: Some("web_search".to_string()),
+                parent: Some("prefix".to_string()),
+                enabled: false,
+                description: Some("s <query> - Search for \"#rust\"".to_string()),
+                status: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn renders_dot() {
+        assert_eq!(render(&nodes(), Format::Dot), "\
+digraph rustix {
+    node [shape=box];
+    \"prefix\" [label=\"prefix\\ltype: prefix\\l\"];
+    \"search\" [label=\"search\\ltype: web_search\\ls <query> - Search for \\\"#rust\\\"\\l(disabled)\\l\", style=dashed];
+    \"prefix\" -> \"search\";
+}
+");
+    }
+
+    #[test]
+    fn renders_mermaid() {
+        assert_eq!(render(&nodes(), Format::Mermaid), "\
+flowchart TD
+    n0[\"prefix<br/>type: prefix\"]
+    n1[\"search<br/>type: web_search<br/>s #60;query#62; - Search for #34;#35;rust#34;<br/>(disabled)\"]
+    n0 --> n1
+    classDef disabled stroke-dasharray: 5 5
+    class n1 disabled
+");
+    }
+
+    #[test]
+    fn shows_configured_filters() {
+        let config: Config = toml::from_str(r#"
+            [connection]
+            server = "http://localhost"
+            username = "rustix"
+
+            [bot]
+            display_name = "rustix"
+            prefix = "!"
+            rooms = []
+            admins = ["@b:mock.server", "@a:mock.server"]
+            ignore = []
+        "#).unwrap();
+        let ctx = Context { config: &config, user_id: TEST_USER };
+
+        let specs = [
+            NodeSpec::new("admins_only", "user_filter", None).with("users", "admins").with("allow", true),
+            NodeSpec::new("not_here", "channel_filter", Some("admins_only")).with("channels", vec!["!x:mock.server"]),
+            NodeSpec::new("votes", "reaction_filter", Some("admins_only")),
+            NodeSpec::new("echo", "echo", Some("votes")),
+        ];
+
+        let status: Vec<_> = from_specs(&ctx, &specs).into_iter().map(|n| n.status).collect();
+        assert_eq!(status, [
+            Some("allow: @a:mock.server, @b:mock.server".to_string()),
+            Some("deny: !x:mock.server".to_string()),
+            Some("allow: any reaction".to_string()),
+            None,
+        ]);
+    }
+}
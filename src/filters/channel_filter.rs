@@ -1,12 +1,16 @@
 use std::{collections::HashSet, iter::FromIterator};
 use itertools::Itertools;
+use toml::value::Table;
 
-use crate::{state, utils::TrimMatch, bot::{Bot, Node, RoomEvent}};
+use crate::{state, utils::TrimMatch, bot::{Bot, Node, RoomEvent}, graph::{self, ChannelFilterConfig, Context}};
+use super::filter_status;
 
 
 pub struct ChannelFilter<'a> {
     children: Vec<&'a str>,
     channels: HashSet<String>,
+    /// Channels which came from the config, rather than being added at runtime
+    configured: Vec<String>,
     allow: bool,
 }
 
@@ -15,6 +19,7 @@ impl<'a> ChannelFilter<'a> {
         Self {
             children: Vec::new(),
             channels: HashSet::from_iter(channels.iter().cloned()),
+            configured: channels,
             allow,
         }
     }
@@ -29,8 +34,12 @@ impl<'a> Node<'a> for ChannelFilter<'a> {
         self.children.push(name);
     }
 
+    fn unregister_child(&mut self, name: &str) {
+        self.children.retain(|c| *c != name);
+    }
+
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
-        let contains = self.channels.contains(&event.room_id.to_string());
+        let contains = self.channels.contains(event.room_id);
 
         if !(self.allow ^ contains) {
             self.propagate_event(bot, &event);
@@ -49,9 +58,7 @@ impl<'a> Node<'a> for ChannelFilter<'a> {
             }
             self.channels.remove(rm_args);
         } else if command.starts_with("status") {
-            let chan_list = self.channels.iter().join(", ");
-            let allow = match self.allow { true => "allow", false => "deny" };
-            bot.reply(&event, &format!("{allow}: {chan_list}")).ok();
+            bot.reply(&event, &filter_status(self.allow, &self.channels)).ok();
         } else if let Some(arg) = command.trim_match(&["allow", "deny"]) {
             match arg {
                 "allow" => self.allow = true,
@@ -61,6 +68,10 @@ impl<'a> Node<'a> for ChannelFilter<'a> {
         }
     }
 
+    fn status(&self) -> Option<String> {
+        Some(filter_status(self.allow, &self.channels))
+    }
+
     fn configure_description(&self) -> Option<String> {
         Some("add <\"here\" | channel id> - add channel to filter list\n\
               rm  <\"here\" | channel id> - remove channel from filter list\n\
@@ -91,4 +102,16 @@ impl<'a> Node<'a> for ChannelFilter<'a> {
     fn on_exit(&self, service_name: &str) {
         state::save_state(service_name, &format!("{}|{}", self.allow, self.channels.iter().join(",")));
     }
+
+    fn reload_config(&mut self, _: &Context, config: &Table) -> Result<(), String> {
+        let cfg: ChannelFilterConfig = graph::parse(config)?;
+
+        for channel in &self.configured {
+            self.channels.remove(channel);
+        }
+        self.channels.extend(cfg.channels.iter().cloned());
+        self.configured = cfg.channels;
+
+        Ok(())
+    }
 }
@@ -27,6 +27,10 @@ impl<'a> Node<'a> for ForwardFilter<'a> {
         self.children.push(name);
     }
 
+    fn unregister_child(&mut self, name: &str) {
+        self.children.retain(|c| *c != name);
+    }
+
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let last_channel_time = *self.channels.get(event.room_id).unwrap_or(&0);
         if let Some(event_time) = event.raw_event.origin_server_ts {
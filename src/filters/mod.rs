@@ -1,11 +1,22 @@
+use itertools::Itertools;
+
 pub mod self_filter;
 pub mod user_filter;
 pub mod message_type_filter;
 pub mod channel_filter;
 pub mod forward_filter;
+pub mod reaction_filter;
 
 pub use self_filter::SelfFilter;
 pub use user_filter::UserFilter;
 pub use message_type_filter::MessageTypeFilter;
 pub use channel_filter::ChannelFilter;
 pub use forward_filter::ForwardFilter;
+pub use reaction_filter::ReactionFilter;
+
+
+/// How a filter describes what it lets through, e.g. `deny: a, b`
+pub(crate) fn filter_status<'s>(allow: bool, items: impl IntoIterator<Item = &'s String>) -> String {
+    let mode = if allow { "allow" } else { "deny" };
+    format!("{}: {}", mode, items.into_iter().sorted().join(", "))
+}
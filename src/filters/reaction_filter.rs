@@ -0,0 +1,113 @@
+use toml::value::Table;
+
+use crate::{state, bot::{Bot, Node, RoomEvent}, graph::{self, Context, ReactionFilterConfig}};
+
+pub struct ReactionFilter<'a> {
+    children: Vec<&'a str>,
+    keys: Vec<String>,
+    /// Keys which came from the config, rather than being added at runtime
+    configured: Vec<String>,
+}
+
+impl<'a> ReactionFilter<'a> {
+    /// Only reactions are propagated, and only those using one of `keys`. An
+    /// empty list of keys allows any reaction through.
+    pub fn new(keys: Vec<String>) -> Self {
+        Self {
+            children: Vec::new(),
+            keys: keys.clone(),
+            configured: keys,
+        }
+    }
+
+    fn add_key(&mut self, key: String) {
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+    }
+
+    /// The reactions let through by a filter for `keys`
+    pub(crate) fn describe(keys: &[String]) -> String {
+        if keys.is_empty() {
+            "allow: any reaction".to_string()
+        } else {
+            format!("allow: {}", keys.join(", "))
+        }
+    }
+}
+
+impl<'a> Node<'a> for ReactionFilter<'a> {
+    fn children(&self) -> Option<&Vec<&'a str>> {
+        Some(&self.children)
+    }
+
+    fn register_child(&mut self, name: &'a str) {
+        self.children.push(name);
+    }
+
+    fn unregister_child(&mut self, name: &str) {
+        self.children.retain(|c| *c != name);
+    }
+
+    fn event_types(&self) -> &[&str] {
+        &["m.reaction"]
+    }
+
+    fn handle(&mut self, bot: &Bot, event: RoomEvent) {
+        if let Some(reaction) = event.reaction() {
+            if self.keys.is_empty() || self.keys.iter().any(|k| k == reaction.key) {
+                self.propagate_event(bot, &event);
+            }
+        }
+    }
+
+    fn configure(&mut self, bot: &Bot, command: &str, event: RoomEvent) {
+        if let Some(key) = command.strip_prefix("add ") {
+            self.add_key(key.trim().to_string());
+        } else if let Some(key) = command.strip_prefix("rm ") {
+            self.keys.retain(|k| k != key.trim());
+        } else if command.starts_with("status") {
+            bot.reply(&event, &Self::describe(&self.keys)).ok();
+        }
+    }
+
+    fn status(&self) -> Option<String> {
+        Some(Self::describe(&self.keys))
+    }
+
+    fn configure_description(&self) -> Option<String> {
+        Some("add <key> - allow reactions with this key\n\
+              rm  <key> - stop allowing reactions with this key\n\
+              status    - view the allowed keys, with none any reaction is allowed".to_string())
+    }
+
+    fn on_load(&mut self, service_name: &str) -> Result<(), String> {
+        if let Some(state) = state::load_state(service_name) {
+            let keys: Vec<String> = serde_json::from_str(&state)
+                                               .map_err(|e| format!("Reaction filter state should be a list of keys: {}", e))?;
+            for key in keys {
+                self.add_key(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_exit(&self, service_name: &str) {
+        // Keys are arbitrary text, so there is no safe separator to join them with
+        state::save_state(service_name, &serde_json::to_string(&self.keys).unwrap());
+    }
+
+    fn reload_config(&mut self, _: &Context, config: &Table) -> Result<(), String> {
+        let cfg: ReactionFilterConfig = graph::parse(config)?;
+
+        let configured = &self.configured;
+        self.keys.retain(|k| !configured.contains(k));
+        for key in &cfg.keys {
+            self.add_key(key.clone());
+        }
+        self.configured = cfg.keys;
+
+        Ok(())
+    }
+}
@@ -24,6 +24,10 @@ impl<'a> Node<'a> for SelfFilter<'a> {
         self.children.push(name);
     }
 
+    fn unregister_child(&mut self, name: &str) {
+        self.children.retain(|c| *c != name);
+    }
+
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let revent = &event.raw_event;
 
@@ -1,12 +1,16 @@
 use std::{collections::HashSet, iter::FromIterator};
 
 use itertools::Itertools;
+use toml::value::Table;
 
-use crate::{bot::{Bot, Node, RoomEvent}, utils::TrimMatch, state};
+use crate::{bot::{Bot, Node, RoomEvent}, graph::{self, Context, UserFilterConfig}, utils::TrimMatch, state};
+use super::filter_status;
 
 pub struct UserFilter<'a> {
     children: Vec<&'a str>,
     users: HashSet<String>,
+    /// Users which came from the config, rather than being added at runtime
+    configured: Vec<String>,
     allow: bool,
 }
 
@@ -15,6 +19,7 @@ impl<'a> UserFilter<'a> {
         Self {
             children: Vec::new(),
             users: HashSet::from_iter(users.iter().cloned()),
+            configured: users,
             allow,
         }
     }
@@ -29,6 +34,10 @@ impl<'a> Node<'a> for UserFilter<'a> {
         self.children.push(name);
     }
 
+    fn unregister_child(&mut self, name: &str) {
+        self.children.retain(|c| *c != name);
+    }
+
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let contains = self.users.contains(&event.raw_event.sender);
 
@@ -53,9 +62,7 @@ impl<'a> Node<'a> for UserFilter<'a> {
                 self.users.remove(user_id);
             }
         } else if command.starts_with("status") {
-            let user_list = self.users.iter().join(", ");
-            let allow = match self.allow { true => "allow", false => "deny" };
-            bot.reply(&event, &format!("{allow}: {user_list}")).ok();
+            bot.reply(&event, &filter_status(self.allow, &self.users)).ok();
         } else if let Some(arg) = command.trim_match(&["allow", "deny"]) {
             match arg {
                 "allow" => self.allow = true,
@@ -65,6 +72,10 @@ impl<'a> Node<'a> for UserFilter<'a> {
         }
     }
 
+    fn status(&self) -> Option<String> {
+        Some(filter_status(self.allow, &self.users))
+    }
+
     fn configure_description(&self) -> Option<String> {
         Some("add <user id> - add user to filter list\n\
               rm  <user id> - remove user from filter list\n\
@@ -95,4 +106,17 @@ impl<'a> Node<'a> for UserFilter<'a> {
     fn on_exit(&self, service_name: &str) {
         state::save_state(service_name, &format!("{}|{}", self.allow, self.users.iter().join(",")));
     }
+
+    fn reload_config(&mut self, ctx: &Context, config: &Table) -> Result<(), String> {
+        let cfg: UserFilterConfig = graph::parse(config)?;
+        let users = cfg.users.resolve(ctx)?;
+
+        for user in &self.configured {
+            self.users.remove(user);
+        }
+        self.users.extend(users.iter().cloned());
+        self.configured = users;
+
+        Ok(())
+    }
 }
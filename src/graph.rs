@@ -0,0 +1,933 @@
+//! The node graph as data. Each node is described by a `NodeSpec`, giving its
+//! name, type, parent and config, and built by the constructor registered for
+//! its type. Without a `[graph]` config section the built in graph is used,
+//! which the section may also extend or change.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{LazyLock, Mutex};
+
+use serde::de::DeserializeOwned;
+use toml::{value::Table, Value};
+
+use crate::bot::{Bot, Node};
+use crate::config::{Config, RemovalMode};
+use crate::state;
+use crate::services::{
+    admin::Admin,
+    bf::BFLang,
+    bonequest::Bonequest,
+    choose::Choose,
+    crypto_coin::CryptoCoin,
+    csv_quote::ReadQuote,
+    duel::Duel,
+    echo::Echo,
+    factoid::{DelFactoid, Factoid, ListAllFactoid},
+    get_joined::GetJoined,
+    help::Help,
+    karma::{KarmaTracker, RankKarma, ShowKarma},
+    logging::Logger,
+    membership::{AcceptInvite, EmptyCleanup, Join, Leave},
+    nodectrl::Configure,
+    openai::gpt::GPT,
+    prefix::Prefix,
+    quote::{DelQuote, EditQuote, Quotes},
+    reload::Reload,
+    roll::Roll,
+    roulette::Roulette,
+    structure::Structure,
+    tryfile::TryFile,
+    voteremove::Voteremove,
+    websearch::WebSearch,
+};
+use crate::filters::{
+    ChannelFilter,
+    ForwardFilter,
+    MessageTypeFilter,
+    ReactionFilter,
+    SelfFilter,
+    UserFilter,
+};
+
+
+/// The `[graph]` config section
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Graph {
+    /// Whether `nodes` change the built in graph rather than replace it
+    #[serde(default = "default_true")]
+    pub extend_default: bool,
+    #[serde(default)]
+    pub nodes: Vec<NodeSpec>,
+}
+
+/// A node of the graph
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeSpec {
+    pub name: String,
+    /// Type of the node, as registered with the `Registry`. Defaults to the
+    /// name of the node.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_: Option<String>,
+    /// Parent of the node, or none for a root node
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// Disabled nodes are part of the graph, but they and the nodes under them
+    /// don't get any events
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Everything else is config for the node
+    #[serde(flatten)]
+    pub config: Table,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl NodeSpec {
+    pub fn new(name: &str, type_: &str, parent: Option<&str>) -> Self {
+        NodeSpec {
+            name: name.to_string(),
+            type_: Some(type_.to_string()),
+            parent: parent.map(String::from),
+            enabled: true,
+            config: Table::new(),
+        }
+    }
+
+    /// Set a config value of the node
+    pub fn with(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.config.insert(key.to_string(), value.into());
+        self
+    }
+
+    pub fn type_name(&self) -> &str {
+        self.type_.as_deref().unwrap_or(&self.name)
+    }
+}
+
+
+/// What node constructors get to work with besides the node's own config
+pub struct Context<'c> {
+    pub config: &'c Config,
+    /// User id of the bot
+    pub user_id: &'c str,
+}
+
+impl<'c> Context<'c> {
+    /// Config for a node of a service which may also be configured in the
+    /// `[services]` section. The node's own config takes precedence.
+    pub fn service_config(&self, service: &str, node_config: &Table) -> Option<Value> {
+        if !node_config.is_empty() {
+            return Some(Value::Table(node_config.clone()));
+        }
+
+        self.config.services.as_ref().and_then(|s| s.get(service)).cloned()
+    }
+
+    pub fn require_service_config(&self, service: &str, node_config: &Table) -> Result<Value, String> {
+        self.service_config(service, node_config)
+            .ok_or_else(|| format!("No config given for the node or in [services.{}]", service))
+    }
+
+    /// Parse the config of a service, found as with `require_service_config`
+    pub fn parse_service_config<T: DeserializeOwned>(&self, service: &str, node_config: &Table) -> Result<T, String> {
+        match self.require_service_config(service, node_config)? {
+            Value::Table(config) => parse(&config),
+            _ => Err(format!("[services.{}] should be a table", service)),
+        }
+    }
+}
+
+
+type Constructor<'a> = dyn Fn(&Context, &Table) -> Result<Box<dyn Node<'a> + 'a>, String> + 'a;
+
+/// Constructors of nodes by type name
+#[derive(Default)]
+pub struct Registry<'a> {
+    constructors: HashMap<&'static str, Box<Constructor<'a>>>,
+}
+
+impl<'a> Registry<'a> {
+    pub fn new() -> Self {
+        Registry {
+            constructors: HashMap::new(),
+        }
+    }
+
+    /// A registry of every node type rustix comes with
+    pub fn with_builtins() -> Self {
+        let mut r = Registry::new();
+
+        // Filters
+        r.register("self_filter", |ctx, _| Ok(Box::new(SelfFilter::new(ctx.user_id.to_string()))));
+        r.register("user_filter", |ctx, cfg| {
+            let cfg: UserFilterConfig = parse(cfg)?;
+            Ok(Box::new(UserFilter::new(cfg.users.resolve(ctx)?, cfg.allow)))
+        });
+        r.register("channel_filter", |_, cfg| {
+            let cfg: ChannelFilterConfig = parse(cfg)?;
+            Ok(Box::new(ChannelFilter::new(cfg.channels, cfg.allow)))
+        });
+        r.register("reaction_filter", |_, cfg| {
+            let cfg: ReactionFilterConfig = parse(cfg)?;
+            Ok(Box::new(ReactionFilter::new(cfg.keys)))
+        });
+        r.register("forward_filter", |_, _| Ok(Box::new(ForwardFilter::new())));
+        r.register("message_type_filter", |_, _| Ok(Box::new(MessageTypeFilter::new())));
+        r.register("prefix", |ctx, cfg| {
+            let cfg: PrefixConfig = parse(cfg)?;
+            Ok(Box::new(Prefix::new(cfg.prefix.unwrap_or_else(|| ctx.config.bot.prefix.clone()),
+                                    cfg.edits.unwrap_or(ctx.config.bot.edits))))
+        });
+        r.register("admin", |ctx, cfg| {
+            let cfg: AdminConfig = parse(cfg)?;
+            Ok(Box::new(Admin::new(cfg.users.unwrap_or_else(|| ctx.config.bot.admins.clone()))))
+        });
+
+        // Services
+        r.register("accept_invite", |_, _| Ok(Box::new(AcceptInvite::new())));
+        r.register("karma_tracker", |ctx, cfg| {
+            let karma = ctx.service_config("karma", cfg);
+            Ok(Box::new(KarmaTracker::new(ctx.config.bot.prefix.clone(), karma.as_ref())))
+        });
+        r.register("logging", |_, _| Ok(Box::new(Logger::new())));
+        r.register("show_karma", |_, _| Ok(Box::new(ShowKarma::new())));
+        r.register("rank_karma", |_, _| Ok(Box::new(RankKarma::new())));
+        r.register("echo", |_, _| Ok(Box::new(Echo::new())));
+        r.register("structure", |_, _| Ok(Box::new(Structure::new())));
+        r.register("quotes", |_, _| Ok(Box::new(Quotes::new())));
+        r.register("edit_quote", |_, _| Ok(Box::new(EditQuote::new())));
+        r.register("del_quote", |_, _| Ok(Box::new(DelQuote::new())));
+        r.register("choose", |_, _| Ok(Box::new(Choose::new())));
+        r.register("roulette", |_, cfg| {
+            let cfg: RemovalConfig = parse(cfg)?;
+            Ok(Box::new(Roulette::new(cfg.mode)))
+        });
+        r.register("duel", |_, cfg| {
+            let cfg: RemovalConfig = parse(cfg)?;
+            Ok(Box::new(Duel::new(cfg.mode)))
+        });
+        r.register("voteremove", |_, cfg| {
+            let cfg: VoteConfig = parse(cfg)?;
+            Ok(Box::new(Voteremove::new(cfg.votes, cfg.wait_minutes, cfg.mode)))
+        });
+        r.register("crypto_coin", |_, _| Ok(Box::new(CryptoCoin::new())));
+        r.register("roll", |_, _| Ok(Box::new(Roll::new())));
+        r.register("bflang", |_, cfg| {
+            let cfg: BfConfig = parse(cfg)?;
+            Ok(Box::new(BFLang::new(cfg.cycle_limit, cfg.debug)))
+        });
+        r.register("help", |_, _| Ok(Box::new(Help::new())));
+        r.register("join", |_, _| Ok(Box::new(Join::new())));
+        r.register("leave", |_, _| Ok(Box::new(Leave::new())));
+        r.register("emptycleanup", |_, _| Ok(Box::new(EmptyCleanup::new())));
+        r.register("get_joined", |_, _| Ok(Box::new(GetJoined::new())));
+        r.register("nodectl", |_, _| Ok(Box::new(Configure::new())));
+        r.register("reload", |_, _| Ok(Box::new(Reload::new())));
+        r.register("del_factoid", |_, _| Ok(Box::new(DelFactoid::new())));
+        r.register("list_factoids", |_, _| Ok(Box::new(ListAllFactoid::new())));
+
+        // Services which can't do without config
+        r.register("bonequest", |ctx, cfg| {
+            Ok(Box::new(Bonequest::new(&ctx.require_service_config("bonequest", cfg)?)))
+        });
+        r.register("csv_quote", |ctx, cfg| {
+            Ok(Box::new(ReadQuote::new(&ctx.require_service_config("csv_quote", cfg)?)))
+        });
+        r.register("try_file", |ctx, cfg| {
+            Ok(Box::new(TryFile::new(&ctx.require_service_config("try_file", cfg)?)))
+        });
+        r.register("web_search", |ctx, cfg| {
+            Ok(Box::new(WebSearch::new(&ctx.require_service_config("web_search", cfg)?)))
+        });
+        r.register("openai", |ctx, cfg| {
+            Ok(Box::new(GPT::new(&ctx.require_service_config("openai", cfg)?)))
+        });
+        r.register("factoid", |ctx, cfg| {
+            Ok(Box::new(Factoid::new(&ctx.require_service_config("factoid", cfg)?)))
+        });
+
+        r
+    }
+
+    /// Register the constructor for nodes of type `type_name`, replacing any
+    /// previous one
+    pub fn register<F>(&mut self, type_name: &'static str, constructor: F)
+        where F: Fn(&Context, &Table) -> Result<Box<dyn Node<'a> + 'a>, String> + 'a
+    {
+        self.constructors.insert(type_name, Box::new(constructor));
+    }
+
+    pub fn construct(&self, ctx: &Context, spec: &NodeSpec) -> Result<Box<dyn Node<'a> + 'a>, String> {
+        let constructor = self.constructors.get(spec.type_name())
+                              .ok_or_else(|| format!("Unknown node type `{}`", spec.type_name()))?;
+
+        constructor(ctx, &spec.config)
+    }
+
+    pub fn type_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = self.constructors.keys().copied().collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Users {
+    /// `"admins"` or `"ignore"`, for the users listed in the `[bot]` section
+    Named(String),
+    List(Vec<String>),
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Users::List(Vec::new())
+    }
+}
+
+impl Users {
+    pub(crate) fn resolve(self, ctx: &Context) -> Result<Vec<String>, String> {
+        match self {
+            Users::Named(n) if n == "admins" => Ok(ctx.config.bot.admins.clone()),
+            Users::Named(n) if n == "ignore" => Ok(ctx.config.bot.ignore.clone()),
+            Users::Named(n) => Err(format!("Unknown user list `{}`, expected \"admins\" or \"ignore\"", n)),
+            Users::List(users) => Ok(users),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct UserFilterConfig {
+    pub(crate) users: Users,
+    pub(crate) allow: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct ChannelFilterConfig {
+    pub(crate) channels: Vec<String>,
+    pub(crate) allow: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct ReactionFilterConfig {
+    pub(crate) keys: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct PrefixConfig {
+    pub(crate) prefix: Option<String>,
+    pub(crate) edits: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct AdminConfig {
+    pub(crate) users: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct RemovalConfig {
+    mode: RemovalMode,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct VoteConfig {
+    pub(crate) votes: usize,
+    pub(crate) wait_minutes: u64,
+    pub(crate) mode: RemovalMode,
+}
+
+impl Default for VoteConfig {
+    fn default() -> Self {
+        VoteConfig {
+            votes: 4,
+            wait_minutes: 5,
+            mode: RemovalMode::Kick,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct BfConfig {
+    cycle_limit: u32,
+    debug: bool,
+}
+
+impl Default for BfConfig {
+    fn default() -> Self {
+        BfConfig {
+            cycle_limit: 5000,
+            debug: false,
+        }
+    }
+}
+
+/// Parse the config of a node
+pub(crate) fn parse<T: DeserializeOwned>(config: &Table) -> Result<T, String> {
+    Value::Table(config.clone()).try_into()
+                                .map_err(|e: toml::de::Error| e.to_string().trim().replace('\n', " "))
+}
+
+
+/// The nodes making up the graph for `config`: the built in graph, changed or
+/// replaced by the `[graph]` section. A node named like a built in node takes
+/// its place.
+pub fn resolve(config: &Config) -> Vec<NodeSpec> {
+    let Some(graph) = &config.graph else {
+        return default_graph(config);
+    };
+
+    let mut nodes = if graph.extend_default { default_graph(config) } else { Vec::new() };
+    for spec in &graph.nodes {
+        upsert(&mut nodes, spec.clone());
+    }
+
+    nodes
+}
+
+/// Replace the node named like `spec`, or add it if there is none
+fn upsert(nodes: &mut Vec<NodeSpec>, spec: NodeSpec) {
+    match nodes.iter_mut().find(|n| n.name == spec.name) {
+        Some(existing) => *existing = spec,
+        None => nodes.push(spec),
+    }
+}
+
+/// Names of the nodes above `name`, nearest first
+pub fn ancestors<'n>(nodes: &'n [NodeSpec], name: &str) -> Vec<&'n str> {
+    let mut found: Vec<&str> = Vec::new();
+    let mut current = name;
+
+    while let Some(parent) = nodes.iter().find(|n| n.name == current).and_then(|n| n.parent.as_deref()) {
+        if parent == name || found.contains(&parent) {
+            break;
+        }
+
+        found.push(parent);
+        current = parent;
+    }
+
+    found
+}
+
+/// The graph rustix runs with unless configured otherwise. Services which need
+/// config are only included when their `[services]` section is present.
+pub fn default_graph(config: &Config) -> Vec<NodeSpec> {
+    let service = |name: &str| config.services.as_ref().and_then(|s| s.get(name));
+    let node = NodeSpec::new;
+
+    let mut nodes = vec![
+        node("self_filter", "self_filter", None),
+        node("user_filter", "user_filter", Some("self_filter")).with("users", "ignore"),
+        node("forward_filter", "forward_filter", Some("user_filter")),
+        node("accept_invite", "accept_invite", Some("forward_filter")),
+        node("reaction_filter", "reaction_filter", Some("forward_filter")),
+        node("message_type_filter", "message_type_filter", Some("forward_filter")),
+        node("karma_tracker", "karma_tracker", Some("message_type_filter")),
+        node("prefix", "prefix", Some("message_type_filter")),
+        node("logging", "logging", Some("prefix")),
+    ];
+
+    let pf = Some("prefix");
+    nodes.extend([
+        node("show_karma",  "show_karma",  pf),
+        node("rank_karma",  "rank_karma",  pf),
+        node("echo",        "echo",        pf),
+        node("structure",   "structure",   pf),
+        node("read_quote",  "quotes",      pf),
+        node("choose",      "choose",      pf),
+        node("roulette",    "roulette",    pf).with("mode", "kick"),
+        node("rroulette",   "roulette",    pf).with("mode", "ban"),
+        node("duel",        "duel",        pf).with("mode", "kick"),
+        node("dduel",       "duel",        pf).with("mode", "ban"),
+        node("crypto_coin", "crypto_coin", pf),
+        node("votekick",    "voteremove",  pf).with("votes", 4).with("wait_minutes", 5).with("mode", "kick"),
+        node("voteban",     "voteremove",  pf).with("votes", 9).with("wait_minutes", 4).with("mode", "ban"),
+        node("roll",        "roll",        pf),
+        node("bflang",      "bflang",      pf),
+    ]);
+
+    if service("bonequest").is_some() {
+        nodes.push(node("bq_channel_filter", "channel_filter", pf));
+        nodes.push(node("bonequest", "bonequest", Some("bq_channel_filter")));
+    }
+
+    // Optional configurable services
+    for (name, type_) in [("csv_quotes", "csv_quote"), ("try_file", "try_file"),
+                          ("web_search", "web_search"), ("openai", "openai")] {
+        if service(type_).is_some() {
+            nodes.push(node(name, type_, pf));
+        }
+    }
+
+    if let Some(factoid) = service("factoid") {
+        nodes.push(node("factoid", "factoid", Some("message_type_filter")));
+        nodes.push(node("del_factoid", "del_factoid", pf));
+
+        if let Some(channels) = factoid.get("list_all_channels") {
+            nodes.push(node("all_factoids_channel_filter", "channel_filter", pf)
+                           .with("channels", channels.clone())
+                           .with("allow", true));
+            nodes.push(node("list_factoids", "list_factoids", Some("all_factoids_channel_filter")));
+        }
+    }
+
+    nodes.push(node("help", "help", pf));
+
+    nodes.push(node("edit_quote_filter", "user_filter", pf).with("users", "admins").with("allow", true));
+    nodes.push(node("edit_quote", "edit_quote", Some("edit_quote_filter")));
+
+    let adm = Some("admin");
+    nodes.extend([
+        node("admin",        "admin",        pf),
+        node("join",         "join",         adm),
+        node("leave",        "leave",        adm),
+        node("emptycleanup", "emptycleanup", adm),
+        node("del_quote",    "del_quote",    adm),
+        node("get_joined",   "get_joined",   adm),
+        node("nodectl",      "nodectl",      adm),
+        node("reload",       "reload",       adm),
+    ]);
+
+    nodes
+}
+
+
+/// A change to the graph, requested at runtime
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphEdit {
+    Add { type_name: String, name: String, parent: String },
+    Remove(String),
+    Move { name: String, parent: String },
+    Enable(String),
+    Disable(String),
+}
+
+// State the runtime changes to the graph are saved to
+const GRAPH_STATE: &str = "graph";
+
+/// Changes made to the graph at runtime, kept on top of the configured graph
+/// across restarts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Changes {
+    /// Configured nodes which were removed
+    #[serde(default)]
+    pub removed: Vec<String>,
+    /// Nodes which were added or changed
+    #[serde(default)]
+    pub nodes: Vec<NodeSpec>,
+}
+
+impl Changes {
+    pub fn load() -> Self {
+        let Some(saved) = state::load_state(GRAPH_STATE) else {
+            return Changes::default()
+        };
+
+        toml::from_str(&saved).unwrap_or_else(|e| {
+            println!("Ignoring unreadable graph changes: {}", e);
+            Changes::default()
+        })
+    }
+
+    pub fn save(&self) {
+        match toml::to_string(self) {
+            Ok(saved) => state::save_state(GRAPH_STATE, &saved),
+            Err(e) => println!("Unable to save graph changes: {}", e),
+        }
+    }
+
+    pub fn apply(&self, nodes: &mut Vec<NodeSpec>) {
+        nodes.retain(|n| !self.removed.contains(&n.name));
+        for spec in &self.nodes {
+            upsert(nodes, spec.clone());
+        }
+    }
+
+    /// Record a node being added or changed
+    pub fn set(&mut self, spec: NodeSpec) {
+        self.removed.retain(|n| *n != spec.name);
+        upsert(&mut self.nodes, spec);
+    }
+
+    /// Record a node being removed. Only `configured` nodes need to be
+    /// remembered as removed, others are simply no longer added.
+    pub fn remove(&mut self, name: &str, configured: bool) {
+        self.nodes.retain(|n| n.name != name);
+        if configured && !self.removed.iter().any(|n| n == name) {
+            self.removed.push(name.to_string());
+        }
+    }
+}
+
+/// Everything needed to change the graph at runtime: how to build nodes, and
+/// what the graph currently looks like
+pub struct Topology<'a> {
+    pub registry: Registry<'a>,
+    pub config: Config,
+    pub user_id: String,
+    pub nodes: Vec<NodeSpec>,
+    pub changes: Changes,
+}
+
+impl<'a> Topology<'a> {
+    /// The graph for `config` with the changes saved by previous runs
+    pub fn load(registry: Registry<'a>, config: Config, user_id: &str) -> Self {
+        let mut nodes = resolve(&config);
+        let changes = Changes::load();
+        changes.apply(&mut nodes);
+
+        Topology {
+            registry,
+            config,
+            user_id: user_id.to_string(),
+            nodes,
+            changes,
+        }
+    }
+
+    pub fn context(&self) -> Context<'_> {
+        Context {
+            config: &self.config,
+            user_id: &self.user_id,
+        }
+    }
+
+    pub fn spec_mut(&mut self, name: &str) -> Option<&mut NodeSpec> {
+        self.nodes.iter_mut().find(|n| n.name == name)
+    }
+
+    /// Whether the node is part of the configured graph
+    pub fn is_configured(&self, name: &str) -> bool {
+        resolve(&self.config).iter().any(|n| n.name == name)
+    }
+}
+
+
+/// Construct the nodes and register them with the bot. Parents don't need to
+/// come before their children. Disabled nodes are registered, but don't get
+/// any events until enabled.
+pub fn build<'a>(bot: &mut Bot<'a, '_>, registry: &Registry<'a>, ctx: &Context, nodes: &[NodeSpec]) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for spec in nodes {
+        if !names.insert(spec.name.as_str()) {
+            return Err(format!("Node `{}` is defined more than once", spec.name));
+        }
+    }
+
+    for spec in nodes {
+        if let Some(parent) = &spec.parent {
+            if !names.contains(parent.as_str()) {
+                return Err(format!("Node `{}` has an unknown parent `{}`", spec.name, parent));
+            }
+        }
+    }
+
+    // Walk down from the roots, so siblings keep the order they're listed in
+    let children = |parent: Option<&str>| -> Vec<&NodeSpec> {
+        nodes.iter().filter(|n| n.parent.as_deref() == parent).rev().collect()
+    };
+    let mut stack = children(None);
+    let mut placed = 0;
+
+    while let Some(spec) = stack.pop() {
+        if let Some(parent) = &spec.parent {
+            if !bot.takes_children(parent) {
+                return Err(format!("Node `{}` can't be placed under `{}`, which takes no nodes under it",
+                                   spec.name, parent));
+            }
+        }
+
+        let node = registry.construct(ctx, spec)
+                           .map_err(|e| format!("Unable to create node `{}`: {}", spec.name, e))?;
+        bot.register_service(node_name(&spec.name), spec.parent.as_deref(), node);
+        bot.set_node_enabled(&spec.name, spec.enabled);
+        placed += 1;
+
+        stack.extend(children(Some(&spec.name)));
+    }
+
+    // Whatever can't be reached from a root is part of a cycle
+    if placed < nodes.len() {
+        let unreachable: Vec<&str> = nodes.iter()
+                                          .map(|n| n.name.as_str())
+                                          .filter(|n| bot.get_service(n).is_none())
+                                          .collect();
+        return Err(format!("Nodes {} form a cycle", unreachable.join(", ")));
+    }
+
+    Ok(())
+}
+
+/// Node names are borrowed by the bot and its nodes for as long as they run.
+/// They are leaked to get there, but only once for each distinct name, so
+/// adding and removing nodes at runtime doesn't leak more and more.
+pub fn node_name<'a>(name: &str) -> &'a str {
+    static NAMES: LazyLock<Mutex<HashSet<&'static str>>> = LazyLock::new(Default::default);
+
+    let mut names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    match names.get(name) {
+        Some(interned) => interned,
+        None => {
+            let leaked: &'static str = Box::leak(name.to_string().into_boxed_str());
+            names.insert(leaked);
+            leaked
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    const BASE_CONFIG: &str = r#"
+        [connection]
+        server = "http://localhost"
+        username = "rustix"
+
+        [bot]
+        display_name = "rustix"
+        prefix = "!"
+        rooms = []
+        admins = ["@admin:mock.server"]
+        ignore = []
+    "#;
+
+    fn config(graph: &str) -> Config {
+        toml::from_str(&format!("{}\n{}", BASE_CONFIG, graph)).unwrap()
+    }
+
+    fn children(bot: &Bot, name: &str) -> Vec<String> {
+        bot.get_service(name).unwrap()
+           .children().into_iter().flatten()
+           .map(|c| c.to_string())
+           .collect()
+    }
+
+    #[test]
+    fn config_nodes_change_default_graph() {
+        let config = config(r#"
+            [[graph.nodes]]
+            name = "roulette"
+            parent = "games"
+
+            [[graph.nodes]]
+            name = "games"
+            type = "channel_filter"
+            parent = "prefix"
+            channels = ["!games:mock.server"]
+            allow = true
+        "#);
+
+        let default = default_graph(&config);
+        let nodes = resolve(&config);
+        assert_eq!(nodes.len(), default.len() + 1);
+
+        let position = |nodes: &[NodeSpec], name: &str| nodes.iter().position(|n| n.name == name);
+        assert_eq!(position(&nodes, "roulette"), position(&default, "roulette"));
+
+        let roulette = nodes.iter().find(|n| n.name == "roulette").unwrap();
+        assert_eq!(roulette.type_name(), "roulette");
+        assert_eq!(roulette.parent.as_deref(), Some("games"));
+
+        let games = nodes.last().unwrap();
+        assert_eq!(games.type_name(), "channel_filter");
+        assert_eq!(games.config["channels"], Value::from(vec!["!games:mock.server"]));
+    }
+
+    #[test]
+    fn builds_configured_graph() {
+        let _state = test_state();
+        let config = config(r#"
+            [graph]
+            extend_default = false
+
+            [[graph.nodes]]
+            name = "echo"
+            parent = "games"
+
+            [[graph.nodes]]
+            name = "games"
+            type = "channel_filter"
+            parent = "prefix"
+            channels = ["!room:mock.server"]
+            allow = true
+
+            [[graph.nodes]]
+            name = "prefix"
+            prefix = "?"
+
+            [[graph.nodes]]
+            name = "off"
+            type = "channel_filter"
+            parent = "prefix"
+            enabled = false
+
+            [[graph.nodes]]
+            name = "roll"
+            parent = "off"
+        "#);
+
+        let nodes = resolve(&config);
+        let mock = MockServer::start();
+        let mut bot = Bot::new(mock.client());
+        let ctx = Context { config: &config, user_id: TEST_USER };
+        build(&mut bot, &Registry::with_builtins(), &ctx, &nodes).unwrap();
+
+        assert_eq!(bot.get_root_services(), &["prefix"]);
+        assert_eq!(children(&bot, "prefix"), ["games", "off"]);
+        assert_eq!(children(&bot, "games"), ["echo"]);
+        assert!(bot.is_node_disabled("off") && !bot.is_node_disabled("roll"));
+
+        bot.propagate_event(&text("?echo configured"));
+        bot.propagate_event(&text("?roll 1"));
+        assert_eq!(mock.replies(), ["configured"]);
+    }
+
+    #[test]
+    fn nodectl_edits_and_persists_graph() {
+        let config = config(r#"
+            [graph]
+            extend_default = false
+
+            [[graph.nodes]]
+            name = "prefix"
+
+            [[graph.nodes]]
+            name = "nodectl"
+            parent = "prefix"
+
+            [[graph.nodes]]
+            name = "echo"
+            parent = "prefix"
+        "#);
+        let _state = test_state();
+        Changes::default().save();
+
+        let mock = MockServer::start();
+        let mut bot = Bot::new(mock.client());
+        bot.load_graph(Registry::with_builtins(), config.clone(), TEST_USER).unwrap();
+
+        let commands = ["!node add channel_filter games under prefix", "!node move echo under games",
+                        "!node rm games", "!node disable prefix", "!node rm", "!node disable games",
+                        "!node add echo parrot under nodectl", "!node move echo under nodectl"];
+        let events: Vec<_> = commands.iter().map(|c| message_event(TEST_SENDER, c)).collect();
+        mock.queue_sync(sync_response("s1", &events));
+        bot.sync_once("s0").unwrap();
+
+        assert_eq!(mock.replies(), [
+            "Usage: node rm <name>",
+            "Added `games` under `prefix`",
+            "Moved `echo` under `games`",
+            "`games` still has nodes under it, move or remove them first",
+            "`prefix` is needed to reach `nodectl`",
+            "Disabled `games`",
+            "`nodectl` takes no nodes under it",
+            "`nodectl` takes no nodes under it",
+        ]);
+        assert_eq!(children(&bot, "prefix"), ["nodectl", "games"]);
+        assert_eq!(children(&bot, "games"), ["echo"]);
+
+        let mut games = NodeSpec::new("games", "channel_filter", Some("prefix"));
+        games.enabled = false;
+        let mut echo = NodeSpec::new("echo", "echo", Some("games"));
+        echo.type_ = None;
+        assert_eq!(Changes::load(), Changes { removed: vec![], nodes: vec![games, echo] });
+
+        // The changes are kept when starting again
+        let mut restarted = Bot::new(mock.client());
+        restarted.load_graph(Registry::with_builtins(), config, TEST_USER).unwrap();
+        assert_eq!(children(&restarted, "prefix"), ["nodectl", "games"]);
+        assert_eq!(children(&restarted, "games"), ["echo"]);
+        assert!(restarted.is_node_disabled("games"));
+
+        Changes::default().save();
+    }
+
+    #[test]
+    fn reload_updates_running_nodes() {
+        let config = |prefix: &str, admin: &str, extra: &str| config(&format!(r#"
+            [graph]
+            extend_default = false
+
+            [[graph.nodes]]
+            name = "prefix"
+            prefix = {}
+
+            [[graph.nodes]]
+            name = "admin"
+            parent = "prefix"
+            users = ["{}"]
+
+            [[graph.nodes]]
+            name = "echo"
+            parent = "admin"
+            {}
+        "#, prefix, admin, extra));
+        let _state = test_state();
+        Changes::default().save();
+
+        let mock = MockServer::start();
+        let mut bot = Bot::new(mock.client());
+        bot.load_graph(Registry::with_builtins(), config("'!'", "@admin:mock.server", ""), TEST_USER).unwrap();
+        bot.propagate_event(&text("!echo not an admin"));
+
+        assert_eq!(bot.update_config(config("'?'", TEST_SENDER, "")), "Reloaded config.toml");
+        bot.propagate_event(&text("!echo old prefix"));
+        bot.propagate_event(&text("?echo reloaded"));
+
+        // New nodes wait for a restart, and nodes keep their settings when
+        // the new ones are broken
+        let report = bot.update_config(config("5", TEST_SENDER, r#"
+            [[graph.nodes]]
+            name = "roll"
+            parent = "prefix"
+        "#));
+        assert!(report.starts_with("Reloaded config.toml, but:\n"));
+        assert!(report.contains("Changes to the node graph take effect on restart"));
+        assert!(report.contains("`prefix` kept its old settings: invalid type: integer `5`"));
+        assert!(bot.get_service("roll").is_none());
+
+        bot.propagate_event(&text("?echo kept"));
+        assert_eq!(mock.replies(), ["reloaded", "kept"]);
+    }
+
+    #[test]
+    fn rejects_broken_graphs() {
+        let mock = MockServer::start();
+        let config = config("");
+        let ctx = Context { config: &config, user_id: TEST_USER };
+
+        let broken = [
+            (vec![NodeSpec::new("echo", "echo", Some("nowhere"))],
+             "Node `echo` has an unknown parent `nowhere`"),
+            (vec![NodeSpec::new("a", "prefix", Some("b")), NodeSpec::new("b", "prefix", Some("a"))],
+             "Nodes a, b form a cycle"),
+            (vec![NodeSpec::new("echo", "echo", None), NodeSpec::new("echo", "roll", None)],
+             "Node `echo` is defined more than once"),
+            (vec![NodeSpec::new("x", "no_such_type", None)],
+             "Unable to create node `x`: Unknown node type `no_such_type`"),
+            (vec![NodeSpec::new("kick", "roulette", None).with("mode", "boot")],
+             "Unable to create node `kick`: unknown variant `boot`, expected `kick` or `ban` in `mode`"),
+            (vec![NodeSpec::new("roll", "roll", None), NodeSpec::new("echo", "echo", Some("roll"))],
+             "Node `echo` can't be placed under `roll`, which takes no nodes under it"),
+        ];
+
+        let registry = Registry::with_builtins();
+        for (nodes, error) in &broken {
+            let mut bot = Bot::new(mock.client());
+            assert_eq!(build(&mut bot, &registry, &ctx, nodes).unwrap_err(), *error);
+        }
+    }
+}
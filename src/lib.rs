@@ -7,6 +7,7 @@ extern crate rand;
 extern crate chrono;
 extern crate http;
 extern crate url;
+extern crate tiny_http;
 
 #[macro_use]
 extern crate serde_derive;
@@ -19,10 +20,18 @@ mod matrix_types;
 mod errors;
 mod utils;
 mod state;
+mod crypto;
+mod room_state;
+mod workers;
+#[cfg(test)]
+mod test_support;
 
 pub mod config;
 pub mod client;
 pub mod bot;
+pub mod appservice;
+pub mod graph;
+pub mod export;
 
 pub mod services;
 pub mod filters;
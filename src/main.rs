@@ -1,160 +1,85 @@
 use std::sync::{Arc, RwLock};
 use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 
 use signal_hook::consts::signal::{SIGINT, SIGTERM};
 
 use rustix::{
+    appservice,
     bot,
     config,
     client::MatrixClient,
-    services::{
-        echo::Echo,
-        karma::{KarmaTracker, ShowKarma, RankKarma},
-        quote::{Quotes, DelQuote, EditQuote},
-        prefix::Prefix,
-        choose::Choose,
-        roulette::Roulette,
-        crypto_coin::CryptoCoin,
-        tryfile::TryFile,
-        membership::{Join, Leave, EmptyCleanup, AcceptInvite},
-        admin::Admin,
-        get_joined::GetJoined,
-        csv_quote::ReadQuote,
-        help::Help,
-        logging::Logger,
-        websearch::WebSearch,
-        openai::gpt::GPT,
-        factoid::{Factoid, DelFactoid, ListAllFactoid},
-        structure::Structure,
-        nodectrl::Configure,
-        bonequest::Bonequest,
-        voteremove::Voteremove,
-        roll::Roll,
-        bf::BFLang,
-        duel::Duel,
-    },
-    filters::{
-        SelfFilter,
-        UserFilter,
-        MessageTypeFilter,
-        ChannelFilter,
-        ForwardFilter,
-    }
+    export::{self, Format},
+    graph::Registry,
 };
 
-
 fn main() {
     // Load config
-    let config = config::load_config("config.toml");
+    let config = config::load_config(config::CONFIG_FILE);
+
+    // Write an appservice registration file instead of running when asked to
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+    if command.as_deref() == Some("--generate-registration") {
+        let path = args.next().unwrap_or_else(|| "registration.yaml".to_string());
+        let as_config = config.appservice.as_ref().expect("Missing [appservice] config section");
+        let as_token = as_config.as_token.clone().unwrap_or_else(appservice::generate_token);
+        let hs_token = as_config.hs_token.clone().unwrap_or_else(appservice::generate_token);
+
+        let registration = appservice::registration(&config.connection.username, as_config, &as_token, &hs_token);
+        std::fs::write(&path, registration).expect("Unable to write registration file");
+        println!("Wrote appservice registration to {}", path);
+
+        if as_config.as_token.is_none() || as_config.hs_token.is_none() {
+            println!("Add the generated tokens to the [appservice] section of config.toml:");
+            println!("as_token = \"{}\"\nhs_token = \"{}\"", as_token, hs_token);
+        }
+        return;
+    }
+
+    // Print the node graph instead of running when asked to
+    if command.as_deref() == Some("--export-graph") {
+        let format = args.next().unwrap_or_else(|| "dot".to_string());
+        let format = Format::parse(&format).expect("Graph format should be dot or mermaid");
+        print!("{}", export::render(&export::configured(&config), format));
+        return;
+    }
 
     // Set up a matrix HTTP client
     let m = Arc::new(RwLock::new(MatrixClient::new(&config.connection.server)));
 
-    m.write().unwrap()
-     .login(&config.connection.username,
-            &config.connection.password).expect("login failed!");
+    let connected = match &config.appservice {
+        Some(as_config) => m.write().unwrap().connect_appservice(as_config),
+        None => m.write().unwrap().connect(&config.connection),
+    };
+    connected.expect("login failed!");
     // Collect the fully qualified username e.g. rustix@matrix.example.com which the server returns at login
     let fq_username = m.read().unwrap().get_user_id().expect("Successful login should return a user id").to_string();
 
     // Create a new bot
     let mut b = bot::Bot::new(Arc::clone(&m));
-    b.set_displayname(&config.bot.display_name).unwrap();
-
-    // Register services with the bot
-    let sf = b.register_service("self_filter", None,
-                                Box::new(SelfFilter::new(fq_username)));
-
-    let uf = b.register_service("user_filter", sf,
-                                Box::new(UserFilter::new(config.bot.ignore.clone(), false)));
-
-    let ff = b.register_service("forward_filter", uf, Box::new(ForwardFilter::new()));
-
-    b.register_service("accept_invite", ff, Box::new(AcceptInvite::new()));
-
-    let mt = b.register_service("message_type_filter", ff,
-                                Box::new(MessageTypeFilter::new()));
-
-    let karma_config = config.services.as_ref().and_then(|s| s.get("karma"));
-    b.register_service("karma_tracker", mt,
-                       Box::new(KarmaTracker::new(config.bot.prefix.clone(), karma_config)));
-
-    let pf = b.register_service("prefix", mt,
-                                Box::new(Prefix::new(config.bot.prefix.clone())));
-
-    b.register_service("logging", pf, Box::new(Logger::new()));
-
-    b.register_service("show_karma",  pf, Box::new(ShowKarma::new()));
-    b.register_service("rank_karma",  pf, Box::new(RankKarma::new()));
-    b.register_service("echo",        pf, Box::new(Echo::new()));
-    b.register_service("structure",   pf, Box::new(Structure::new()));
-    b.register_service("read_quote",  pf, Box::new(Quotes::new()));
-    b.register_service("choose",      pf, Box::new(Choose::new()));
-    b.register_service("roulette",    pf, Box::new(Roulette::new(config::RemovalMode::Kick)));
-    b.register_service("rroulette",   pf, Box::new(Roulette::new(config::RemovalMode::Ban)));
-    b.register_service("duel",        pf, Box::new(Duel::new(config::RemovalMode::Kick)));
-    b.register_service("dduel",       pf, Box::new(Duel::new(config::RemovalMode::Ban)));
-    b.register_service("crypto_coin", pf, Box::new(CryptoCoin::new()));
-    b.register_service("votekick",    pf, Box::new(Voteremove::new(4, 5, config::RemovalMode::Kick)));
-    b.register_service("voteban",     pf, Box::new(Voteremove::new(9, 4, config::RemovalMode::Ban)));
-    b.register_service("roll",        pf, Box::new(Roll::new()));
-    b.register_service("bflang",      pf, Box::new(BFLang::default()));
-
-    if let Some(bq_profanity) = config.services.as_ref().and_then(|s| s.get("bonequest")) {
-        let bq_cf = b.register_service("bq_channel_filter", pf, Box::new(ChannelFilter::new(vec![], false)));
-        b.register_service("bonequest", bq_cf, Box::new(Bonequest::new(bq_profanity)));
+    // Not fatal, the display name on the server just stays as it was
+    if let Err(e) = b.set_displayname(&config.bot.display_name) {
+        println!("Could not set display name: {}", e);
     }
-
-    // Optional configurable services
-    if let Some(csv_quote_cfg) = config.services.as_ref().and_then(|s| s.get("csv_quote")) {
-        b.register_service("csv_quotes", pf, Box::new(ReadQuote::new(csv_quote_cfg)));
+    b.set_backlog(config.bot.backlog);
+    if config.bot.notify_panics {
+        b.set_panic_admins(config.bot.admins.clone());
     }
-    if let Some(try_file_cfg) = config.services.as_ref().and_then(|s| s.get("try_file")) {
-        b.register_service("try_file", pf, Box::new(TryFile::new(try_file_cfg)));
+    if let Some(timeout) = config.connection.sync_timeout {
+        b.set_sync_timeout(Duration::from_secs(timeout));
     }
-    if let Some(ws_cfg) = config.services.as_ref().and_then(|s| s.get("web_search")) {
-        b.register_service("web_search", pf, Box::new(WebSearch::new(ws_cfg)));
-    }
-    if let Some(oa_cfg) = config.services.as_ref().and_then(|s| s.get("openai")) {
-        /*
-        let users = oa_cfg.get("whitelist").unwrap()
-                          .as_array().unwrap()
-                          .iter().map(|x| x.as_str().unwrap().to_string())
-                          .collect();
-        let allowed = b.register_service("openai_priv", pf, Box::new(Admin::new(users)));
-        */
-        b.register_service("openai", pf, Box::new(GPT::new(oa_cfg)));
-    }
-    if let Some(f_cfg) = config.services.as_ref().and_then(|s| s.get("factoid")) {
-        b.register_service("factoid", mt, Box::new(Factoid::new(f_cfg)));
-        b.register_service("del_factoid", pf, Box::new(DelFactoid::new()));
 
-        if let Some(lc_cfg) = f_cfg.get("list_all_channels") {
-            let channels: Vec<String> = lc_cfg.clone().try_into().expect("Invalid factoids list_all_channels config");
-            let cf = b.register_service("all_factoids_channel_filter", pf, Box::new(ChannelFilter::new(channels, true)));
-            b.register_service("list_factoids", cf, Box::new(ListAllFactoid::new()));
-        }
+    // Register services with the bot
+    if let Err(e) = b.load_graph(Registry::with_builtins(), config.clone(), &fq_username) {
+        panic!("Bad node graph: {}", e);
     }
 
-    b.register_service("help", pf, Box::new(Help::new()));
-
-    let eq_f = b.register_service("edit_quote_filter", pf,
-                                  Box::new(UserFilter::new(config.bot.admins.clone(), true)));
-    b.register_service("edit_quote",   eq_f, Box::new(EditQuote::new()));
-
-    let adm = b.register_service("admin", pf, Box::new(Admin::new(config.bot.admins)));
-    b.register_service("join",         adm, Box::new(Join::new()));
-    b.register_service("leave",        adm, Box::new(Leave::new()));
-    b.register_service("emptycleanup", adm, Box::new(EmptyCleanup::new()));
-    b.register_service("del_quote",    adm, Box::new(DelQuote::new()));
-    b.register_service("get_joined",   adm, Box::new(GetJoined::new()));
-    b.register_service("nodectl",      adm, Box::new(Configure::new()));
-
-
     // Join bot to initial rooms
     for room in &config.bot.rooms {
         println!("Joining {}", &room);
-        if b.join_public(room).is_err() {
-            println!("Could not join room");
+        if let Err(e) = b.join_room(room, &[]) {
+            println!("Could not join room: {}", e);
         }
     }
 
@@ -166,5 +91,8 @@ fn main() {
                        .expect("Failed to setup SIGTERM handler.");
 
     // Start bot main loop
-    b.run(&term);
+    match &config.appservice {
+        Some(as_config) => b.run_appservice(as_config, &term),
+        None => b.run(&term),
+    }
 }
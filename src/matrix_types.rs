@@ -24,26 +24,73 @@ pub struct Login<'a> {
     pub type_: &'a str,
     pub identifier: LoginIdentifier<'a>,
     pub password: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<&'a str>,
+    pub initial_device_display_name: &'a str,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Init {
     pub user_id: String,
     pub access_token: String,
-    pub home_server: String,
+    pub home_server: Option<String>,
     pub device_id: String,
 }
 
+/// Standard error body returned by the matrix API for non-2xx responses
+#[derive(Deserialize, Debug)]
+pub struct MatrixError {
+    pub errcode: String,
+    pub error: Option<String>,
+    pub retry_after_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WhoAmI {
+    pub user_id: String,
+    pub device_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Session {
+    pub user_id: String,
+    pub access_token: String,
+    pub device_id: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MatrixSync {
     /*
     account_data: Events,
-    device_lists: HashMap,
     */
     pub next_batch: String,
     //presence: HashMap,
     pub rooms: Option<Rooms>,
-    //to_device: HashMap,
+    pub to_device: Option<ToDevice>,
+    pub device_one_time_keys_count: Option<HashMap<String, u64>>,
+    pub device_lists: Option<DeviceLists>,
+}
+
+/// Users whose devices changed, or who no longer share an encrypted room
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeviceLists {
+    #[serde(default)]
+    pub changed: Vec<String>,
+    #[serde(default)]
+    pub left: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ToDevice {
+    pub events: Vec<ToDeviceEvent>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ToDeviceEvent {
+    pub content: Value,
+    pub sender: String,
+    #[serde(rename="type")]
+    pub type_: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -110,15 +157,29 @@ pub struct Event {
     //membership: String,
     pub origin_server_ts: Option<u64>,
     pub sender: String,
-    //state_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_key: Option<String>,
     #[serde(rename="type")]
     pub type_: String,
     pub unsigned: Option<Value>
 }
 
+/// Events pushed to an application service by the homeserver
+#[derive(Deserialize, Debug)]
+pub struct Transaction {
+    pub events: Vec<TransactionEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TransactionEvent {
+    pub room_id: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PublicRooms {
-    pub total_room_count_estimate: u32,
+    pub total_room_count_estimate: Option<u32>,
     pub next_batch: Option<String>,
     pub chunk: Vec<PublicRoom>,
 }
@@ -126,7 +187,7 @@ pub struct PublicRooms {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PublicRoom {
     pub canonical_alias: Option<String>,
-    pub name: String,
+    pub name: Option<String>,
     pub world_readable: bool,
     pub topic: Option<String>,
     pub num_joined_members: u32,
@@ -152,11 +213,37 @@ pub struct RoomName {
     pub name: String,
 }
 
+#[derive(Serialize, Default, Debug)]
+pub struct CreateRoom<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<&'a str>,
+    /// One of `private_chat`, `trusted_private_chat` or `public_chat`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub invite: Vec<&'a str>,
+    pub is_direct: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub initial_state: Vec<Value>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CreatedRoom {
     pub room_id: String,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct RoomId {
+    pub room_id: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EventId {
+    pub event_id: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UserDirectory {
     pub limited: bool,
@@ -165,8 +252,8 @@ pub struct UserDirectory {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DirectoryProfile {
-    pub avatar_url: String,
-    pub display_name: String,
+    pub avatar_url: Option<String>,
+    pub display_name: Option<String>,
     pub user_id: String,
 }
 
@@ -178,6 +265,105 @@ pub struct RoomChunks {
     pub state: Option<Vec<Event>>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct KeysUploadResponse {
+    #[serde(default)]
+    pub one_time_key_counts: HashMap<String, u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeysQueryResponse {
+    #[serde(default)]
+    pub device_keys: HashMap<String, HashMap<String, Value>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeysClaimResponse {
+    #[serde(default)]
+    pub one_time_keys: HashMap<String, HashMap<String, HashMap<String, Value>>>,
+}
+
+/// A parsed `mxc://<server name>/<media id>` content repository uri
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mxc<'a> {
+    pub server_name: &'a str,
+    pub media_id: &'a str,
+}
+
+impl<'a> Mxc<'a> {
+    pub fn parse(uri: &'a str) -> Option<Self> {
+        let (server_name, media_id) = uri.strip_prefix("mxc://")?.split_once('/')?;
+        if server_name.is_empty() || media_id.is_empty() || media_id.contains('/') {
+            return None;
+        }
+
+        Some(Mxc { server_name, media_id })
+    }
+}
+
+/// A file downloaded from the content repository
+#[derive(Debug)]
+pub struct Media {
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// Content of an `m.room.power_levels` event, with the defaults the spec
+/// gives for missing keys
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PowerLevels {
+    pub users: HashMap<String, i64>,
+    pub users_default: i64,
+    pub events: HashMap<String, i64>,
+    pub events_default: i64,
+    pub state_default: i64,
+    pub ban: i64,
+    pub kick: i64,
+    pub redact: i64,
+    pub invite: i64,
+}
+
+impl PowerLevels {
+    /// Parse the content of a power levels event. Older rooms may give levels
+    /// as strings, which are accepted as long as they hold an integer.
+    pub fn from_content(content: &Value) -> serde_json::Result<Self> {
+        fn integers_from_strings(value: &mut Value) {
+            match value {
+                Value::String(s) => if let Ok(n) = s.trim().parse::<i64>() {
+                    *value = Value::from(n);
+                },
+                Value::Object(map) => map.values_mut().for_each(integers_from_strings),
+                _ => (),
+            }
+        }
+
+        let mut content = content.clone();
+        integers_from_strings(&mut content);
+        serde_json::from_value(content)
+    }
+
+    pub fn user_level(&self, user_id: &str) -> i64 {
+        self.users.get(user_id).copied().unwrap_or(self.users_default)
+    }
+}
+
+impl Default for PowerLevels {
+    fn default() -> Self {
+        PowerLevels {
+            users: HashMap::new(),
+            users_default: 0,
+            events: HashMap::new(),
+            events_default: 0,
+            state_default: 50,
+            ban: 50,
+            kick: 50,
+            redact: 50,
+            invite: 0,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct RoomMembers {
     pub joined: HashMap<String, Value>,
@@ -0,0 +1,214 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::matrix_types::{Event, PowerLevels, Rooms};
+
+
+/// A room member as described by their latest `m.room.member` event
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub membership: String,
+    pub displayname: Option<String>,
+}
+
+/// The parts of a room's state the bot keeps track of
+#[derive(Debug, Clone, Default)]
+pub struct RoomState {
+    pub name: Option<String>,
+    pub canonical_alias: Option<String>,
+    pub alt_aliases: Vec<String>,
+    pub members: HashMap<String, Member>,
+    pub power_levels: Option<PowerLevels>,
+    // Sender of the `m.room.create` event, who holds all power in rooms
+    // without power levels
+    pub creator: Option<String>,
+}
+
+impl RoomState {
+    pub fn from_events(events: &[Event]) -> Self {
+        let mut state = RoomState::default();
+        for event in events {
+            state.apply(event);
+        }
+
+        state
+    }
+
+    /// Update the state with a state event. Anything else is ignored.
+    pub fn apply(&mut self, event: &Event) {
+        let Some(state_key) = &event.state_key else {
+            return
+        };
+
+        let content = &event.content;
+        match event.type_.as_str() {
+            "m.room.name" => {
+                self.name = content["name"].as_str()
+                                           .filter(|n| !n.is_empty())
+                                           .map(String::from);
+            },
+            "m.room.canonical_alias" => {
+                self.canonical_alias = content["alias"].as_str().map(String::from);
+                self.alt_aliases = content["alt_aliases"].as_array().into_iter().flatten()
+                                                         .filter_map(|a| a.as_str())
+                                                         .map(String::from)
+                                                         .collect();
+            },
+            "m.room.member" => {
+                let member = Member {
+                    membership: content["membership"].as_str().unwrap_or("leave").to_string(),
+                    displayname: content["displayname"].as_str().map(String::from),
+                };
+                self.members.insert(state_key.clone(), member);
+            },
+            "m.room.power_levels" => {
+                self.power_levels = match PowerLevels::from_content(content) {
+                    Ok(levels) => Some(levels),
+                    Err(e) => {
+                        println!("Ignoring power levels {} which don't parse: {}",
+                                 event.event_id.as_deref().unwrap_or_default(), e);
+                        None
+                    },
+                };
+            },
+            "m.room.create" => {
+                self.creator = Some(event.sender.clone());
+            },
+            _ => (),
+        }
+    }
+
+    /// The power levels in effect in the room. Without a power levels event
+    /// the room's creator has power level 100, and anyone may send state
+    /// events, as the spec has it.
+    pub fn levels(&self) -> PowerLevels {
+        if let Some(levels) = &self.power_levels {
+            return levels.clone();
+        }
+
+        let mut levels = PowerLevels {
+            state_default: 0,
+            ..PowerLevels::default()
+        };
+        if let Some(creator) = &self.creator {
+            levels.users.insert(creator.clone(), 100);
+        }
+
+        levels
+    }
+
+    /// Ids of the users currently joined to the room
+    pub fn joined_members(&self) -> impl Iterator<Item=&str> {
+        self.members.iter()
+                    .filter(|(_, m)| m.membership == "join")
+                    .map(|(id, _)| id.as_str())
+    }
+
+    /// The canonical alias followed by the alternative aliases
+    pub fn aliases(&self) -> impl Iterator<Item=&str> {
+        self.canonical_alias.iter().chain(self.alt_aliases.iter()).map(|a| a.as_str())
+    }
+}
+
+/// Room state seen in syncs. Rooms are only tracked once their full state
+/// has been loaded, after which sync keeps them up to date.
+#[derive(Default)]
+pub struct RoomStateCache {
+    joined: Option<HashSet<String>>,
+    rooms: HashMap<String, RoomState>,
+    // Direct message room with each user, kept until either side leaves it
+    direct: HashMap<String, String>,
+}
+
+impl RoomStateCache {
+    pub fn joined(&self) -> Option<&HashSet<String>> {
+        self.joined.as_ref()
+    }
+
+    pub fn set_joined(&mut self, rooms: impl IntoIterator<Item=String>) {
+        self.joined = Some(rooms.into_iter().collect());
+    }
+
+    pub fn get(&self, room_id: &str) -> Option<&RoomState> {
+        self.rooms.get(room_id)
+    }
+
+    pub fn insert(&mut self, room_id: &str, state: RoomState) {
+        self.rooms.insert(room_id.to_string(), state);
+    }
+
+    pub fn direct_room(&self, user_id: &str) -> Option<&str> {
+        self.direct.get(user_id).map(|r| r.as_str())
+    }
+
+    pub fn set_direct_room(&mut self, user_id: &str, room_id: &str) {
+        self.direct.insert(user_id.to_string(), room_id.to_string());
+    }
+
+    /// Forget everything about a room the bot left
+    pub fn remove(&mut self, room_id: &str) {
+        if let Some(joined) = &mut self.joined {
+            joined.remove(room_id);
+        }
+        self.rooms.remove(room_id);
+        self.direct.retain(|_, r| r != room_id);
+    }
+
+    /// Forget the direct message room of a user who left it
+    fn apply_direct(&mut self, room_id: &str, event: &Event) {
+        let left = event.type_ == "m.room.member" &&
+                   matches!(event.content["membership"].as_str(), Some("leave") | Some("ban"));
+
+        if let (true, Some(user_id)) = (left, &event.state_key) {
+            if self.direct.get(user_id).is_some_and(|r| r == room_id) {
+                self.direct.remove(user_id);
+            }
+        }
+    }
+
+    /// Apply a single event of a room, as pushed to an application service.
+    /// The bot's own membership events keep the set of joined rooms current.
+    pub fn apply(&mut self, room_id: &str, event: &Event, own_user_id: &str) {
+        if event.type_ == "m.room.member" && event.state_key.as_deref() == Some(own_user_id) {
+            match event.content["membership"].as_str() {
+                Some("join") => {
+                    if let Some(joined) = &mut self.joined {
+                        joined.insert(room_id.to_string());
+                    }
+                },
+                Some("leave") | Some("ban") => {
+                    self.remove(room_id);
+                    return;
+                },
+                _ => (),
+            }
+        }
+
+        self.apply_direct(room_id, event);
+        if let Some(state) = self.rooms.get_mut(room_id) {
+            state.apply(event);
+        }
+    }
+
+    /// Apply the state changes and room membership of a sync response
+    pub fn update(&mut self, rooms: &Rooms) {
+        for (room_id, room) in rooms.join.iter().flatten() {
+            if let Some(joined) = &mut self.joined {
+                joined.insert(room_id.clone());
+            }
+
+            for event in room.state.events.iter().chain(&room.timeline.events) {
+                self.apply_direct(room_id, event);
+            }
+
+            if let Some(state) = self.rooms.get_mut(room_id) {
+                for event in room.state.events.iter().chain(&room.timeline.events) {
+                    state.apply(event);
+                }
+            }
+        }
+
+        for room_id in rooms.leave.iter().flatten().map(|(id, _)| id) {
+            self.remove(room_id);
+        }
+    }
+}
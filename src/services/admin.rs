@@ -1,4 +1,7 @@
+use toml::value::Table;
+
 use crate::bot::{Bot, Node, RoomEvent};
+use crate::graph::{self, AdminConfig, Context};
 
 pub struct Admin<'a> {
     children: Vec<&'a str>,
@@ -23,9 +26,20 @@ impl<'a> Node<'a> for Admin<'a> {
         self.children.push(name);
     }
 
+    fn unregister_child(&mut self, name: &str) {
+        self.children.retain(|c| *c != name);
+    }
+
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         if self.hard_admins.contains(&event.raw_event.sender) {
             self.propagate_event(bot, &event);
         }
     }
+
+    fn reload_config(&mut self, ctx: &Context, config: &Table) -> Result<(), String> {
+        let cfg: AdminConfig = graph::parse(config)?;
+        self.hard_admins = cfg.users.unwrap_or_else(|| ctx.config.bot.admins.clone());
+
+        Ok(())
+    }
 }
@@ -47,14 +47,14 @@ impl<'a> Node<'a> for BFLang {
                     let r = run_bf(&t_code, String::new().as_bytes(), &mut out, t_debug, Some(t_timeout));
                     match r {
                         Err(e) => {
-                            let mut client = t_client.write().unwrap();
+                            let client = t_client.read().unwrap();
                             let raw = e.trim_start_matches('\n');
                             let message = codeblock_format(raw);
                             client.send_msg_fmt(&t_room_id, &message, raw).ok();
                         },
                         Ok(_) => {
                             if let Ok(s) = String::from_utf8(out.buffer().to_vec()) {
-                                let mut client = t_client.write().unwrap();
+                                let client = t_client.read().unwrap();
                                 client.send_msg(&t_room_id, &s).ok();
                             }
                         },
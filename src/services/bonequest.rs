@@ -3,9 +3,9 @@ use std::{collections::HashMap, time::Duration};
 use itertools::Itertools;
 use reqwest;
 use rand::seq::SliceRandom;
-use toml::Value;
+use toml::{value::Table, Value};
 
-use crate::{bot::{Bot, Node, RoomEvent}, utils::codeblock_format};
+use crate::{bot::{Bot, Node, RoomEvent}, graph::Context, utils::codeblock_format};
 
 const BQ_BASE_URL: &str = "https://www.bonequest.com";
 
@@ -25,6 +25,7 @@ struct BqDialog {
 }
 
 
+#[derive(Clone)]
 pub struct Bonequest {
     profanity: Vec<String>
 }
@@ -76,59 +77,78 @@ impl Bonequest {
                         .send()?
                         .json::<BqRandom>()?;
 
-        Ok(res.episodes.get(0).map(|e| e.quote.to_string()).unwrap_or("Random quote API failed.".to_string()))
+        Ok(res.episodes.first().map(|e| e.quote.to_string()).unwrap_or("Random quote API failed.".to_string()))
+    }
+
+    /// Fetch a line, of `character` if given, retrying when it contains
+    /// profanity. Gives up with `None` if every attempt did.
+    fn clean_line(&self, character: Option<&str>) -> Result<Option<String>, reqwest::Error> {
+        for _ in 0..10 {
+            let line = match character {
+                Some(c) => self.rand_character(c)?,
+                None => self.get_line()?,
+            };
+
+            let lowered = line.to_lowercase();
+            if !self.profanity.iter().any(|word| lowered.contains(word)) {
+                return Ok(Some(line));
+            }
+        }
+
+        Ok(None)
     }
 }
 
 impl<'a> Node<'a> for Bonequest {
+    fn reload_config(&mut self, ctx: &Context, config: &Table) -> Result<(), String> {
+        let cfg: Config = ctx.parse_service_config("bonequest", config)?;
+        self.profanity = cfg.profanity.iter().map(|p| p.to_lowercase()).collect();
+
+        Ok(())
+    }
+
    fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let revent = &event.raw_event;
         let body = &revent.content["body"].as_str().unwrap();
 
         if body.eq(&"bqactors") {
-            if let Ok(c) = self.actors() {
-                bot.client().indicate_typing(event.room_id, Some(Duration::from_secs(10))).ok();
-
+            bot.client().indicate_typing(event.room_id, Some(Duration::from_secs(10))).ok();
 
-                let message = codeblock_format(&c);
-                bot.reply_fmt(&event, &message, &c).ok();
+            let bq = self.clone();
+            bot.spawn(&event, move || bq.actors(), |bot, event, actors| {
+                if let Ok(c) = actors {
+                    let message = codeblock_format(&c);
+                    bot.reply_fmt(event, &message, &c).ok();
+                } else {
+                    bot.reply(event, "Failed to fetch actor list.").ok();
+                }
 
                 bot.client().indicate_typing(event.room_id, None).ok();
-            } else {
-                bot.reply(&event, "Failed to fetch actor list.").ok();
-            }
+            });
         } else if let Some(p) = body.strip_prefix("bq") {
             bot.client().indicate_typing(event.room_id, Some(Duration::from_secs(10))).ok();
 
-            let line = if p.starts_with(' ') && p.trim().len() > 0 {
-                self.rand_character(p.trim())
-            } else {
-                self.get_line()
-            };
+            let bq = self.clone();
+            let character = Some(p.trim()).filter(|c| p.starts_with(' ') && !c.is_empty())
+                                          .map(String::from);
 
-            'attempts: for _ in 0..10 {
+            bot.spawn(&event, move || bq.clean_line(character.as_deref()), |bot, event, line| {
                 match line {
-                    Ok(ref l) => {
-                        let lowered = l.to_lowercase();
-                        for word in self.profanity.iter() {
-                            if lowered.contains(word) {
-                                continue 'attempts;
-                            }
-                        }
-                        bot.reply(&event, &l).ok();
+                    Ok(Some(l)) => {
+                        bot.reply(event, &l).ok();
                     },
+                    Ok(None) => (),
                     Err(e) => {
                         if e.is_timeout() {
-                            bot.reply(&event, "bq timed out").ok();
+                            bot.reply(event, "bq timed out").ok();
                         } else {
                             println!("{:?}", e);
                         }
                     }
                 }
-                break;
-            }
 
-            bot.client().indicate_typing(event.room_id, None).ok();
+                bot.client().indicate_typing(event.room_id, None).ok();
+            });
         }
     }
 
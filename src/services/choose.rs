@@ -16,7 +16,7 @@ impl<'a> Node<'a> for Choose {
         if let Some(raw_choices) = body.strip_prefix("choose ") {
             let mut rng = rand::thread_rng();
             if let Some(choice) = raw_choices.split(',').map(|c| c.trim()).choose(&mut rng) {
-                bot.reply(&event, choice).ok();
+                bot.reply_in_thread(&event, choice).ok();
             }
         }
     }
@@ -25,3 +25,24 @@ impl<'a> Node<'a> for Choose {
         Some("choose <optional item 1>, <optional item 2>, <optional item N> - Randomly selects from a comma separated list of items.".to_string())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{text, MockServer};
+
+    #[test]
+    fn chooses_one_of_the_items() {
+        let mock = MockServer::start();
+        let bot = Bot::new(mock.client());
+        let mut choose = Choose::new();
+
+        choose.handle(&bot, text("choose tea"));
+        choose.handle(&bot, text("choose  tea , coffee"));
+
+        let replies = mock.replies();
+        assert_eq!(replies[0], "tea");
+        assert!(replies[1] == "tea" || replies[1] == "coffee");
+    }
+}
@@ -54,10 +54,7 @@ fn get_ticker(sym: &str) -> Option<Vec<f32>> {
 
     let client = reqwest::blocking::Client::new();
     match client.request(Method::GET, url).send() {
-        Ok(resp) => match resp.json() {
-            Ok(v) => Some(v),
-            Err(_) => None,
-        },
+        Ok(resp) => resp.json().ok(),
         Err(_) => None,
     }
 }
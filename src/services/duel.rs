@@ -27,20 +27,36 @@ impl<'a> Node<'a> for Duel {
             if (self.mode == RemovalMode::Ban && body.starts_with("dduel")) ||
                (self.mode == RemovalMode::Kick && body.starts_with("duel")) {
 
+                // Both duelists must be fair game before anyone is shot
+                if let Err(e) = bot.check_removal(event.room_id, &self.mode, &revent.sender) {
+                    bot.reply(&event, &format!("No duel: {}", e)).ok();
+                    return;
+                }
+
                 match self.duels.get(event.room_id) {
                     Some(d) => {
+                        // The challenger may have been promoted while waiting
+                        if let Err(e) = bot.check_removal(event.room_id, &self.mode, d) {
+                            bot.reply(&event, &format!("No duel: {}", e)).ok();
+                            self.duels.remove(event.room_id);
+                            return;
+                        }
+
                         let mut rng = rand::thread_rng();
                         let loser = match rng.gen_bool(0.55) {
                             true => d,
                             false => &revent.sender,
                         };
 
-                        match &self.mode {
+                        let removal = match &self.mode {
                             RemovalMode::Kick => bot.client().kick(event.room_id, loser, Some("Bang!")),
                             RemovalMode::Ban =>  bot.client().ban(event.room_id, loser, Some("Bang!")),
-                        }.ok();
+                        };
 
-                        bot.reply(&event, "Bang!").ok();
+                        match removal {
+                            Ok(_) => bot.reply(&event, "Bang!").ok(),
+                            Err(e) => bot.reply(&event, &format!("Bang! (but unable to {} {}: {})", self.mode.as_str(), loser, e)).ok(),
+                        };
                         println!("{} lost the duel", loser);
                         self.duels.remove(event.room_id);
                     },
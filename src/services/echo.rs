@@ -21,10 +21,14 @@ impl<'a> Node<'a> for Echo<'a> {
         self.children.push(name);
     }
 
+    fn unregister_child(&mut self, name: &str) {
+        self.children.retain(|c| *c != name);
+    }
+
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         if event.is_normal() {
             if let Some(content) = event.body().unwrap().strip_prefix("echo ") {
-                bot.reply(&event, content).ok();
+                bot.reply_in_thread(&event, content).ok();
             }
         }
 
@@ -35,3 +39,21 @@ impl<'a> Node<'a> for Echo<'a> {
         Some("echo <any message> - Replys with the argument passed.".to_string())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{text, MockServer};
+
+    #[test]
+    fn echoes_argument() {
+        let mock = MockServer::start();
+        let bot = Bot::new(mock.client());
+
+        Echo::new().handle(&bot, text("echo hello there"));
+        Echo::new().handle(&bot, text("echoes"));
+
+        assert_eq!(mock.replies(), ["hello there"]);
+    }
+}
@@ -39,7 +39,9 @@ impl<'a> Node<'a> for ListAllFactoid {
             if response.len() > 1 {
                 let raw = response.join("\n");
                 let message = codeblock_format(&raw);
-                bot.reply_fmt(&event, &message, &raw).ok();
+                if let Err(e) = bot.reply_private_fmt(&event, &message, &raw) {
+                    bot.reply(&event, &format!("Unable to send you the factoids: {}", e)).ok();
+                }
             }
         }
     }
@@ -2,6 +2,7 @@ mod service;
 mod backend;
 mod del_factoid;
 mod list_all_factoid;
+#[allow(non_local_definitions)]
 pub(in crate::services) mod models;
 
 // Re-export
@@ -2,9 +2,10 @@ use dotenv::dotenv;
 use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
 use regex::Regex;
-use toml::Value;
+use toml::{value::Table, Value};
 
 use crate::bot::Node;
+use crate::graph::Context;
 use crate::utils::codeblock_format;
 
 use super::models;
@@ -29,21 +30,36 @@ impl Factoid {
         dotenv().ok();
 
         let leader = cfg.factoid_leader;
-        let set_pattern =
-            Regex::new(&format!("^{}\\s?(.+?) is (<reply>|<action>) (.+)", &leader)).unwrap();
 
         Self {
             backend: Backend::new(),
             rng: SmallRng::from_entropy(),
+            set_pattern: set_pattern(&leader).expect("Bad factoid leader"),
             leader,
-            set_pattern,
         }
     }
 
 }
 
+/// Matches messages setting a factoid
+fn set_pattern(leader: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^{}\\s?(.+?) is (<reply>|<action>) (.+)", leader))
+}
+
 impl<'a> Node<'a> for Factoid {
+    fn reload_config(&mut self, ctx: &Context, config: &Table) -> Result<(), String> {
+        let cfg: Config = ctx.parse_service_config("factoid", config)?;
+        self.set_pattern = set_pattern(&cfg.factoid_leader).map_err(|e| format!("Bad factoid leader: {}", e))?;
+        self.leader = cfg.factoid_leader;
+
+        Ok(())
+    }
+
     fn handle(&mut self, bot: &crate::bot::Bot, event: crate::bot::RoomEvent) {
+        if event.is_edit() {
+            return;
+        }
+
         let revent = &event.raw_event;
         let body = revent.content["body"].as_str().unwrap();
 
@@ -17,26 +17,29 @@ impl<'a> Node<'a> for GetJoined {
         if event.is_normal() {
             let body = &event.raw_event.content["body"].as_str().unwrap();
             if body.starts_with("joined") {
-                let joined = bot.client().get_joined();
+                let joined = bot.joined_rooms();
                 match joined {
                     Ok(rooms) => {
-                        let room_names = rooms.joined_rooms.iter().map(|r|{
-                            let room_name = bot.client().get_room_name(r);
-                            match room_name {
-                                Ok(name) => name,
-                                Err(_) => {
-                                    let members = bot.client().get_members(r).unwrap();
-                                    format!("{} ({})", r, members.join(", "))
+                        let room_names = rooms.iter().map(|r|{
+                            match bot.room_name(r) {
+                                Ok(Some(name)) => name,
+                                // Rooms without a name are described by their members
+                                Ok(None) => match bot.room_members(r) {
+                                    Ok(members) => format!("{} ({})", r, members.iter().sorted().join(", ")),
+                                    Err(e) => format!("{} (unable to get members: {})", r, e),
                                 },
+                                Err(e) => format!("{} ({})", r, e),
                             }
                         }).sorted().join("\n");
 
                         let resp = format!("Currently in rooms:\n{}", room_names);
                         let fmt_resp = codeblock_format(&resp);
-                        bot.reply_fmt(&event, &fmt_resp, &resp).ok();
+                        if let Err(e) = bot.reply_private_fmt(&event, &fmt_resp, &resp) {
+                            bot.reply(&event, &format!("Unable to send you the joined rooms: {}", e)).ok();
+                        }
                     }
                     Err(e) => {
-                        let resp = format!("{:?}", e);
+                        let resp = format!("Unable to list joined rooms: {}", e);
                         bot.reply(&event, &resp).ok();
                     }
                 };
@@ -1,58 +1,44 @@
-use std::any::Any;
-
 use crate::bot::{Bot, Node, RoomEvent};
 
 use crate::utils::codeblock_format;
 
-pub struct Help {
-    reply_id: Option<String>,
-}
-
+pub struct Help;
 impl Help {
     pub fn new() -> Self {
-        Self { reply_id: None }
+        Help
     }
 }
 
 impl<'a> Node<'a> for Help {
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let body = &event.raw_event.content["body"].as_str().unwrap();
-        if let Some(mut service_name) = body.strip_prefix("help") {
-            service_name = service_name.trim();
-            let mut target = None;
-            if !service_name.is_empty() {
-                target = Some(service_name.to_string());
-            }
-
-            // Save the last event so it can be used to reply with the help text
-            self.reply_id = Some(event.room_id.to_string());
-            bot.delay_service_query("help", target, |_, s| Box::new(s.description()));
-        }
-    }
-
-    fn recieve_all_node_post(&mut self, bot: &Bot, result: Vec<(&str, Box<dyn Any>)>) {
-        let mut help_strings: Vec<String> = Vec::new();
-
-        for (_, value) in result {
-            let mut opt_v = value.downcast::<Option<String>>().unwrap();
-            if let Some(v) = opt_v.take() {
-                help_strings.push(v);
-            }
-        }
-
-        help_strings.sort();
-
-        if let Some(ref e) = self.reply_id {
-            // Exit early. Avoids sending useless response.
-            if help_strings.is_empty() {
-                bot.client().send_msg(e, "No help found.").ok();
-                return;
-            }
-
-            let response = help_strings.join("\n");
-            let message = codeblock_format(&response);
-            bot.client().send_msg_fmt(e, &message, &response).ok();
-            self.reply_id = None;
+        if let Some(service_name) = body.strip_prefix("help") {
+            let target = service_name.trim().to_string();
+            let room_id = event.room_id.to_string();
+
+            // The nodes above this one can only be reached once they're done
+            bot.after_event(move |bot| {
+                let mut help_strings: Vec<String> = if target.is_empty() {
+                    bot.inspect_graph()
+                       .map(|nodes| nodes.into_iter().filter_map(|n| n.description).collect())
+                       .unwrap_or_default()
+                } else {
+                    bot.with_node(&target, |n| n.description().into_iter().collect())
+                       .unwrap_or_default()
+                };
+
+                help_strings.sort();
+
+                // Exit early. Avoids sending useless response.
+                if help_strings.is_empty() {
+                    bot.client().send_msg(&room_id, "No help found.").ok();
+                    return;
+                }
+
+                let response = help_strings.join("\n");
+                let message = codeblock_format(&response);
+                bot.client().send_msg_fmt(&room_id, &message, &response).ok();
+            });
         }
     }
 
@@ -84,10 +84,10 @@ impl<'a> Node<'a> for RankKarma {
                 let user_query = match captures.get(1) {
                     Some(query) => {
                         let q = query.as_str().trim();
-                        match bot.uid_from_displayname(q) {
+                        match bot.resolve_user(&event, q) {
                             Ok(r) => r,
-                            Err(crate::errors::Error::Generic(_)) => {
-                                bot.reply(&event, &format!("Unable to determine user id for: {}", q)).ok();
+                            Err(crate::errors::Error::Generic(e)) => {
+                                bot.reply(&event, &e).ok();
                                 return;
                             },
                             Err(e) => {
@@ -117,10 +117,10 @@ impl<'a> Node<'a> for RankKarma {
                 let user_query = match captures.get(1) {
                     Some(query) => {
                         let q = query.as_str().trim();
-                        match bot.uid_from_displayname(q) {
+                        match bot.resolve_user(&event, q) {
                             Ok(r) => r,
-                            Err(crate::errors::Error::Generic(_)) => {
-                                bot.reply(&event, &format!("Unable to determine user id for: {}", q)).ok();
+                            Err(crate::errors::Error::Generic(e)) => {
+                                bot.reply(&event, &e).ok();
                                 return;
                             },
                             Err(e) => {
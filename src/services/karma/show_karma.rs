@@ -36,7 +36,7 @@ impl<'a> Node<'a> for ShowKarma {
                     Ok(None) => format!("Karma for '{}': Net karma: 0 (+0/-0 0% like it)", query),
                     _ => format!("Error querying karma for '{}'", query),
                 };
-                bot.reply(&event, &res).ok();
+                bot.reply_in_thread(&event, &res).ok();
             }
         }
     }
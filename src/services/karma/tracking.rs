@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 
 use regex::Regex;
-use toml::Value;
+use toml::{value::Table, Value};
 
 use crate::bot::{Bot, Node, RoomEvent};
+use crate::graph::Context;
 
 use super::backend::Backend;
 
@@ -12,6 +13,8 @@ struct Config {
     max_per_message: i32,
 }
 
+const DEFAULT_MAX_PER_MESSAGE: i32 = 10;
+
 pub struct KarmaTracker {
     vote_db: Backend,
     re: Regex,
@@ -21,7 +24,7 @@ pub struct KarmaTracker {
 
 impl KarmaTracker {
     pub fn new(bot_prefix: String, config: Option<&Value>) -> Self {
-        let mut max_per_message = 10;
+        let mut max_per_message = DEFAULT_MAX_PER_MESSAGE;
         if let Some(value) = config {
             let cfg: Config = value.clone().try_into().expect("Bad karma config");
             max_per_message = cfg.max_per_message;
@@ -42,7 +45,22 @@ struct VoteCount {
 }
 
 impl<'a> Node<'a> for KarmaTracker {
+    fn reload_config(&mut self, ctx: &Context, config: &Table) -> Result<(), String> {
+        self.max_per_message = match ctx.service_config("karma", config) {
+            Some(_) => ctx.parse_service_config::<Config>("karma", config)?.max_per_message,
+            None => DEFAULT_MAX_PER_MESSAGE,
+        };
+        self.bot_prefix = ctx.config.bot.prefix.clone();
+
+        Ok(())
+    }
+
     fn handle(&mut self, _bot: &Bot, event: RoomEvent) {
+        // Votes in an edited message were already counted in the original
+        if event.is_edit() {
+            return;
+        }
+
         let event = event.raw_event;
         let body = event.content["body"].as_str().unwrap();
 
@@ -12,16 +12,24 @@ impl Join {
 impl<'a> Node<'a> for Join {
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let body = &event.raw_event.content["body"].as_str().unwrap();
-        if let Some(room_name) = body.strip_prefix("join ") {
-            if bot.join_public(room_name).is_err() {
-                let resp = format!("Could not join: {}", room_name);
+        if let Some(args) = body.strip_prefix("join ") {
+            let mut args = args.split_whitespace();
+            let room = match args.next() {
+                Some(room) => room,
+                None => return,
+            };
+            let servers: Vec<&str> = args.collect();
+
+            if let Err(e) = bot.join_room(room, &servers) {
+                let resp = format!("Could not join {}: {}", room, e);
                 bot.reply(&event, &resp).ok();
             }
         }
     }
 
     fn description(&self) -> Option<String> {
-        Some("join <room name> - Command the bot to join a room.".to_string())
+        Some("join <room id|alias|name> [servers...] - Command the bot to join a room, \
+              optionally via other servers.".to_string())
     }
 }
 
@@ -39,19 +47,21 @@ impl<'a> Node<'a> for Leave {
         let body = &event.raw_event.content["body"].as_str().unwrap();
         if let Some(channel) = body.strip_prefix("leave") {
             let room_name = &channel.trim_start();
-            if room_name.is_empty() {
-                bot.client().leave(event.room_id).ok();
-            }
-
-            if bot.leave_public(room_name).is_err() {
-                let resp = format!("Could not leave room: {}", room_name);
+            let result = if room_name.is_empty() {
+                bot.client().leave(event.room_id)
+            } else {
+                bot.leave_room(room_name)
+            };
+
+            if let Err(e) = result {
+                let resp = format!("Could not leave room {}: {}", room_name, e);
                 bot.reply(&event, &resp).ok();
             }
         }
     }
 
     fn description(&self) -> Option<String> {
-        Some("leave <optional room name> - Command the bot to leave a room.".to_string())
+        Some("leave <optional room id|alias|name> - Command the bot to leave a room.".to_string())
     }
 }
 
@@ -68,14 +78,11 @@ impl<'a> Node<'a> for EmptyCleanup {
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let body = &event.raw_event.content["body"].as_str().unwrap();
         if body.starts_with("emptycleanup") {
-            if let Ok(joined) = bot.client().get_joined() {
-                for room_id in joined.joined_rooms {
-                    bot.client().get_members(&room_id)
-                       .map(|m| {
-                            if m.len() == 1 {
-                                bot.client().leave(&room_id).ok();
-                            }
-                       }).ok();
+            if let Ok(joined) = bot.joined_rooms() {
+                for room_id in joined {
+                    if bot.room_members(&room_id).is_ok_and(|m| m.len() == 1) {
+                        bot.client().leave(&room_id).ok();
+                    }
                 }
             }
         }
@@ -102,7 +109,9 @@ impl<'a> Node<'a> for AcceptInvite {
             if let Some(value) = revent.content.get("membership") {
                 if value.is_string() && value.as_str().unwrap() == "invite" {
                     println!("Joining room {} via invitation from {}", &event.room_id, revent.sender);
-                    bot.client().join(event.room_id).ok();
+                    if let Err(e) = bot.client().join(event.room_id, &[]) {
+                        println!("Unable to accept invite to {}: {}", &event.room_id, e);
+                    }
                 }
             }
         }
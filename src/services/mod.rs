@@ -23,5 +23,8 @@ pub mod voteremove;
 pub mod roll;
 pub mod bf;
 pub mod duel;
+pub mod reload;
 
+// The impls diesel 1 derives for models and tables are defined in functions
+#[allow(non_local_definitions)]
 mod db;
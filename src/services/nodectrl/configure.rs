@@ -1,73 +1,107 @@
-use std::any::Any;
-
-use crate::{bot::{Bot, Node, RoomEvent}, utils::codeblock_format};
+use crate::{bot::{Bot, Node, RoomEvent}, graph::GraphEdit, utils::codeblock_format};
 
 pub struct Configure {
-    reply_id: Option<String>,
+    name: String,
 }
 
 impl Configure {
     pub fn new() -> Self {
         Self {
-            reply_id: None
+            name: String::new(),
         }
     }
 }
 
+/// Parse the commands changing the node graph, giving the usage on mistakes
+fn parse_edit(body: &str) -> Option<Result<GraphEdit, &'static str>> {
+    let rest = body.strip_prefix("node ")?;
+    let (command, args) = rest.split_once(' ').unwrap_or((rest, ""));
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let name = |n: &str| n.to_string();
+
+    let edit = match (command, args.as_slice()) {
+        ("add", [type_name, n, "under", parent]) => Ok(GraphEdit::Add {
+            type_name: name(type_name),
+            name: name(n),
+            parent: name(parent),
+        }),
+        ("add", _) => Err("Usage: node add <type> <name> under <parent>"),
+        ("rm", [n]) => Ok(GraphEdit::Remove(name(n))),
+        ("rm", _) => Err("Usage: node rm <name>"),
+        ("move", [n, "under", parent]) => Ok(GraphEdit::Move {
+            name: name(n),
+            parent: name(parent),
+        }),
+        ("move", _) => Err("Usage: node move <name> under <parent>"),
+        ("enable", [n]) => Ok(GraphEdit::Enable(name(n))),
+        ("enable", _) => Err("Usage: node enable <name>"),
+        ("disable", [n]) => Ok(GraphEdit::Disable(name(n))),
+        ("disable", _) => Err("Usage: node disable <name>"),
+        _ => return None,
+    };
+
+    Some(edit)
+}
+
 impl<'a> Node<'a> for Configure {
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let body = &event.raw_event.content["body"].as_str().unwrap();
 
+        match parse_edit(body) {
+            Some(Ok(edit)) => bot.edit_graph(&self.name, event.room_id, edit),
+            Some(Err(usage)) => { bot.reply(&event, usage).ok(); },
+            None => (),
+        }
+
+        // The target may be above this node, so it can only be reached once
+        // the event has been handled
         if let Some(args) = body.strip_prefix("node config") {
             if let Some((target, command)) = args.trim().split_once(' ') {
+                let target = target.to_string();
                 let cmd = command.to_string();
                 let room_id = event.room_id.to_string();
                 let from = event.from.to_string();
                 let rev = event.raw_event.clone();
-                bot.delay_service_query("nodectl",
-                                        Some(target.to_string()),
-                                        move |b, n| {
-                                            let ev = RoomEvent {
-                                                room_id: &room_id,
-                                                from: &from,
-                                                raw_event: rev.clone()
-                                            };
-                                            n.configure(b, &cmd, ev);
-                                            Box::new(0)
-                                        });
+
+                bot.after_event(move |bot| {
+                    let ev = RoomEvent {
+                        room_id: &room_id,
+                        from: &from,
+                        raw_event: rev,
+                    };
+
+                    if let Err(e) = bot.with_node(&target, |n| n.configure(bot, &cmd, ev)) {
+                        bot.client().send_msg(&room_id, &e).ok();
+                    }
+                });
             }
         }
 
         if let Some(command) = body.strip_prefix("node help") {
-            let target = command.trim();
-            bot.delay_service_query("nodectl",
-                                    Some(target.to_string()),
-                                    move |_, n| {
-                                        Box::new(n.configure_description())
-                                    });
-            self.reply_id = Some(event.room_id.to_string());
-        }
-    }
+            let target = command.trim().to_string();
+            let room_id = event.room_id.to_string();
 
-    fn recieve_all_node_post(&mut self, bot: &Bot, result: Vec<(&str, Box<dyn Any>)>) {
-        if let Some(ref rid) = self.reply_id {
-            if let Some((_, value)) = result.into_iter().next() {
-                let mut opt_v = value.downcast::<Option<String>>().unwrap();
-                if let Some(v) = opt_v.take() {
-                    bot.client().send_msg_fmt(rid, &codeblock_format(&v), &v).ok();
-                } else {
-                    bot.client().send_msg(rid, "No config help found.").ok();
-                }
-            } else {
-                bot.client().send_msg(rid, "Node not found.").ok();
-            }
+            bot.after_event(move |bot| {
+                match bot.with_node(&target, |n| n.configure_description()) {
+                    Ok(Some(v)) => bot.client().send_msg_fmt(&room_id, &codeblock_format(&v), &v),
+                    Ok(None) => bot.client().send_msg(&room_id, "No config help found."),
+                    Err(e) => bot.client().send_msg(&room_id, &e),
+                }.ok();
+            });
         }
-
-        self.reply_id = None;
     }
 
     fn description(&self) -> Option<String> {
         Some("node config <node name> <command> - send command to a specific node\n\
-              node help <node name> - get help for configuring node".to_string())
+              node help <node name> - get help for configuring node\n\
+              node add <type> <name> under <parent> - add a node to the graph\n\
+              node rm <node name> - remove a node without children from the graph\n\
+              node move <node name> under <parent> - move a node and its children\n\
+              node disable|enable <node name> - stop or resume passing events to a node".to_string())
+    }
+
+    fn on_load(&mut self, service_name: &str) -> Result<(), String> {
+        self.name = service_name.to_string();
+        Ok(())
     }
 }
\ No newline at end of file
@@ -1,13 +1,14 @@
-use std::{time::Duration, io::{prelude::*,BufReader}};
+use std::{time::Duration, io::{self, prelude::*, BufReader}};
 use std::fs::File;
+use std::sync::{Arc, Mutex, RwLock};
 
 use reqwest;
 use rust_tokenizers::tokenizer::{TruncationStrategy, Gpt2Tokenizer, Tokenizer};
 use sha3::Digest;
-use toml::Value;
+use toml::{value::Table, Value};
 use serde::Deserialize;
 
-use crate::{bot::{Bot, Node, RoomEvent}, state};
+use crate::{bot::{Bot, Node, RoomEvent}, client::MatrixClient, graph::Context, state};
 use super::types::*;
 
 const BASE_URL: &str = "https://api.openai.com/v1/completions";
@@ -22,14 +23,31 @@ struct Config {
 }
 
 pub struct GPT {
+    api: Arc<Api>,
+    budget: Arc<Mutex<Budget>>,
+    tokens_per_second: f64,
+    last_query: std::time::Instant,
+}
+
+/// Everything needed to query the completions API, shared with the worker
+/// threads chats run on
+struct Api {
     secret: String,
-    tokenizer: Gpt2Tokenizer,
+    tokenizer: Arc<Gpt2Tokenizer>,
     current_model: ModelType,
     backstory: String,
+}
+
+struct Budget {
     used_tokens: u64,
     token_budget: f64,
-    tokens_per_second: f64,
-    last_query: std::time::Instant,
+}
+
+/// How a chat turned out
+enum Chat {
+    Reply(String),
+    Notice(String),
+    Failed,
 }
 
 
@@ -37,39 +55,54 @@ impl GPT {
     pub fn new(config: &Value) -> Self {
         let cfg: Config = config.clone().try_into().expect("Bad openai config.");
 
-        // Load backstory
-        let backstory_file = File::open(cfg.backstory_file).expect("Unable to open backstory file.");
-        let mut buf_reader = BufReader::new(backstory_file);
-        let mut contents = String::new();
-        buf_reader.read_to_string(&mut contents).expect("Error reading backstory contents.");
+        let contents = read_backstory(&cfg.backstory_file).expect("Unable to read backstory file.");
 
         // Files retrieved from here:
         // https://huggingface.co/gpt2/tree/main
         let tokenizer = Gpt2Tokenizer::from_file("vocab.json", "merges.txt", false).unwrap();
 
-        let daily = cfg.monthly_budget / 30.0;
-        let model_cost = 0.02;
-        let daily_tokens = daily / model_cost * 1000.0;
-        let tokens_per_second = daily_tokens / 24.0 / 60.0/ 60.0;
-
         Self {
-            secret: cfg.secret,
-            tokenizer,
-            current_model: ModelType::Davinci,
-            backstory: contents,
-            used_tokens: 0,
-            token_budget: cfg.starting_tokens.unwrap_or(0) as f64,
-            tokens_per_second,
+            api: Arc::new(Api {
+                secret: cfg.secret,
+                tokenizer: Arc::new(tokenizer),
+                current_model: ModelType::Davinci,
+                backstory: contents,
+            }),
+            budget: Arc::new(Mutex::new(Budget {
+                used_tokens: 0,
+                token_budget: cfg.starting_tokens.unwrap_or(0) as f64,
+            })),
+            tokens_per_second: tokens_per_second(cfg.monthly_budget),
             last_query: std::time::Instant::now(),
         }
     }
+}
+
+fn read_backstory(filename: &str) -> io::Result<String> {
+    let mut buf_reader = BufReader::new(File::open(filename)?);
+    let mut contents = String::new();
+    buf_reader.read_to_string(&mut contents)?;
+
+    Ok(contents)
+}
+
+/// The rate tokens are added to the budget at, for a monthly budget in dollars
+fn tokens_per_second(monthly_budget: f64) -> f64 {
+    let daily = monthly_budget / 30.0;
+    let model_cost = 0.02;
+    let daily_tokens = daily / model_cost * 1000.0;
+    daily_tokens / 24.0 / 60.0/ 60.0
+}
+
+
+impl Api {
 
     fn count_tokens(&self, message: &str) -> u32 {
         let res = self.tokenizer.encode(message, None, 8000, &TruncationStrategy::DoNotTruncate, 0);
         res.token_ids.len() as u32
     }
 
-    fn complete(&mut self, message: &str, userid: &str) -> Result<Response, reqwest::Error> {
+    fn complete(&self, budget: &Mutex<Budget>, message: &str, userid: &str) -> Result<Response, reqwest::Error> {
         let msg_tokens = self.count_tokens(message);
         let hash = sha3::Sha3_256::new_with_prefix(userid).finalize();
         let hashed_userid = base16ct::lower::encode_string(&hash);
@@ -93,12 +126,12 @@ impl GPT {
                         .send()?
                         .json::<Response>();
 
-        self.used_tokens += msg_tokens as u64;
+        budget.lock().unwrap().used_tokens += msg_tokens as u64;
 
         res.map(|r| {
             match r {
                 Response::Success(s) => {
-                    self.used_tokens += self.count_tokens(&s.choices[0].text) as u64;
+                    budget.lock().unwrap().used_tokens += self.count_tokens(&s.choices[0].text) as u64;
                     Response::Success(s)
                 },
                 Response::Error(e) => Response::Error(e)
@@ -106,7 +139,7 @@ impl GPT {
         })
     }
 
-    fn build_context(&self, bot: &Bot, room_id: &str, username: &str, message: &str) -> (String, u32) {
+    fn build_context(&self, client: &RwLock<MatrixClient>, room_id: &str, username: &str, message: &str, display_name: &str) -> (String, u32) {
         let mut to_complete = String::new();
         to_complete += &self.backstory;
 
@@ -115,7 +148,9 @@ impl GPT {
 
         let mut messages = Vec::<String>::new();
 
-        if let Ok(e) = bot.client().get_room_events(room_id, 100, None) {
+        // Only lock the client for the request, the completion takes much longer
+        let events = client.read().unwrap().get_room_events(room_id, 100, None);
+        if let Ok(e) = events {
             for event in e.chunk.iter().skip(1) {
                 if !(event.type_ == "m.room.message" && event.content["msgtype"] == "m.text") {
                     continue;
@@ -139,13 +174,52 @@ impl GPT {
         messages.reverse();
         to_complete += &messages.join("\n");
 
-        let final_line = format!("\n<{}> {}\n<{}> ", username, message, bot.get_displayname());
+        let final_line = format!("\n<{}> {}\n<{}> ", username, message, display_name);
         context_tokens += self.count_tokens(&final_line);
 
         to_complete += &final_line;
 
         (to_complete, context_tokens)
     }
+
+    /// Answer `message`, with the recent messages of the room as context
+    fn chat(&self, budget: &Mutex<Budget>, client: &RwLock<MatrixClient>, room_id: &str,
+            sender: &str, message: &str, display_name: &str) -> Chat {
+        let uname = trim_name(sender);
+        let (context, _) = self.build_context(client, room_id, uname, message, display_name);
+
+        let count = self.count_tokens(&context);
+        let token_budget = budget.lock().unwrap().token_budget;
+        if  count as f64 > token_budget {
+            return Chat::Notice(format!("Sorry. Rate limited. :(\n{} tokens > token budget of {:.0}", count, token_budget));
+        }
+
+        match self.complete(budget, &context, sender) {
+            Ok(r) => {
+                match r {
+                    Response::Error(e) => {
+                        println!("{:?}", e);
+                        Chat::Failed
+                    },
+                    Response::Success(s) => {
+                        let txt = s.choices[0].text.trim();
+                        budget.lock().unwrap().token_budget -= s.usage.total_tokens as f64;
+                        println!("total tokens: {}", s.usage.total_tokens);
+                        //println!("-----------\n{}{}\ntotal tokens: {}\n-----------", &context, txt, s.usage.total_tokens);
+                        Chat::Reply(txt.to_string())
+                    }
+                }
+            },
+            Err(e) => {
+                if e.is_timeout() {
+                    Chat::Notice("Chat response timed out.".to_string())
+                } else {
+                    println!("{:?}", e);
+                    Chat::Failed
+                }
+            }
+        }
+    }
 }
 
 fn trim_name(name: &str) -> &str{
@@ -153,6 +227,28 @@ fn trim_name(name: &str) -> &str{
 }
 
 impl<'a> Node<'a> for GPT {
+    fn reload_config(&mut self, ctx: &Context, config: &Table) -> Result<(), String> {
+        let cfg: Config = ctx.parse_service_config("openai", config)?;
+        let backstory = read_backstory(&cfg.backstory_file)
+            .map_err(|e| format!("Unable to read backstory file: {}", e))?;
+
+        // Chats already running keep the settings they started with
+        self.api = Arc::new(Api {
+            secret: cfg.secret,
+            tokenizer: Arc::clone(&self.api.tokenizer),
+            current_model: self.api.current_model,
+            backstory,
+        });
+
+        // Tokens earned so far still count at the old rate
+        let dt = self.last_query.elapsed().as_secs_f64();
+        self.budget.lock().unwrap().token_budget += self.tokens_per_second * dt;
+        self.last_query = std::time::Instant::now();
+        self.tokens_per_second = tokens_per_second(cfg.monthly_budget);
+
+        Ok(())
+    }
+
    fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let revent = &event.raw_event;
 
@@ -160,50 +256,37 @@ impl<'a> Node<'a> for GPT {
             let body = &revent.content["body"].as_str().unwrap();
 
             let dt = self.last_query.elapsed().as_secs_f64();
-            self.token_budget += self.tokens_per_second * dt;
+            self.budget.lock().unwrap().token_budget += self.tokens_per_second * dt;
             self.last_query = std::time::Instant::now();
 
             if let Some(message) = body.strip_prefix("chat ") {
                 bot.client().indicate_typing(event.room_id, Some(Duration::from_secs(60))).ok();
 
-                let uname = trim_name(&revent.sender);
-                let (context, _) = self.build_context(bot, event.room_id, uname, message);
+                let api = Arc::clone(&self.api);
+                let budget = Arc::clone(&self.budget);
+                let client = bot.arc_client();
+                let room_id = event.room_id.to_string();
+                let sender = revent.sender.clone();
+                let message = message.to_string();
+                let display_name = bot.get_displayname().to_string();
 
-                let count = self.count_tokens(&context);
-                if  count as f64 > self.token_budget {
+                let job = move || api.chat(&budget, &client, &room_id, &sender, &message, &display_name);
+
+                bot.spawn(&event, job, |bot, event, chat| {
                     bot.client().indicate_typing(event.room_id, None).ok();
-                    bot.reply(&event, &format!("Sorry. Rate limited. :(\n{} tokens > token budget of {:.0}", count, self.token_budget)).ok();
-                    return;
-                }
 
-                match self.complete(&context, &revent.sender) {
-                    Ok(r) => {
-                        match r {
-                            Response::Error(e) => println!("{:?}", e),
-                            Response::Success(s) => {
-                                let txt = s.choices[0].text.trim();
-                                self.token_budget -= s.usage.total_tokens as f64;
-                                println!("total tokens: {}", s.usage.total_tokens);
-                                //println!("-----------\n{}{}\ntotal tokens: {}\n-----------", &context, txt, s.usage.total_tokens);
-                                bot.reply(&event, txt).ok();
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        if e.is_timeout() {
-                            bot.reply(&event, "Chat response timed out.").ok();
-                        } else {
-                            println!("{:?}", e);
-                        }
+                    match chat {
+                        Chat::Reply(txt) => { bot.reply_to(event, &txt).ok(); },
+                        Chat::Notice(msg) => { bot.reply(event, &msg).ok(); },
+                        Chat::Failed => (),
                     }
-                }
-
-                bot.client().indicate_typing(event.room_id, None).ok();
+                });
             }
 
             if body.starts_with("budget") {
+                let budget = self.budget.lock().unwrap();
                 bot.reply(&event, &format!("tokens used: {}\ntokens/second: {:.4}\ntoken budget: {:.0}",
-                                           self.used_tokens, self.tokens_per_second, self.token_budget)).ok();
+                                           budget.used_tokens, self.tokens_per_second, budget.token_budget)).ok();
             }
         }
     }
@@ -219,14 +302,14 @@ impl<'a> Node<'a> for GPT {
 
             if let Some(s) = parsed.next() {
                 match s.parse() {
-                    Ok(v) => self.token_budget = v,
+                    Ok(v) => self.budget.lock().unwrap().token_budget = v,
                     Err(_) => return Err("Token budget should parse to f64 from save state".to_string()),
                 };
             }
 
             if let Some(s) = parsed.next() {
                 match s.parse() {
-                    Ok(v) => self.used_tokens = v,
+                    Ok(v) => self.budget.lock().unwrap().used_tokens = v,
                     Err(_) => return Err("Used tokens should parse to u64 from save state".to_string()),
                 };
             }
@@ -236,6 +319,7 @@ impl<'a> Node<'a> for GPT {
     }
 
     fn on_exit(&self, service_name: &str) {
-        state::save_state(service_name, &format!("{} {}", self.token_budget, self.used_tokens));
+        let budget = self.budget.lock().unwrap();
+        state::save_state(service_name, &format!("{} {}", budget.token_budget, budget.used_tokens));
     }
 }
\ No newline at end of file
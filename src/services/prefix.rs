@@ -1,20 +1,26 @@
 use serde_json::value::Value;
+use toml::value::Table;
 
 use crate::bot::{Bot, Node, RoomEvent};
+use crate::graph::{self, Context, PrefixConfig};
 
 pub struct Prefix<'a> {
     children: Vec<&'a str>,
     prefix: String,
     prefix_n: usize,
+    edits: bool,
 }
 
 impl<'a> Prefix<'a> {
-    pub fn new(prefix: String) -> Self {
+    /// When `edits` is set, editing a message into a command triggers the
+    /// command. Otherwise edited messages are ignored.
+    pub fn new(prefix: String, edits: bool) -> Self {
         let len = prefix.len();
         Self {
             children: Vec::new(),
             prefix,
             prefix_n: len,
+            edits,
         }
     }
 }
@@ -28,9 +34,14 @@ impl<'a> Node<'a> for Prefix<'a> {
         self.children.push(name);
     }
 
+    fn unregister_child(&mut self, name: &str) {
+        self.children.retain(|c| *c != name);
+    }
+
     fn handle(&mut self, bot: &Bot, mut event: RoomEvent) {
         if event.raw_event.type_ == "m.room.message" &&
            event.raw_event.content["msgtype"] == "m.text" &&
+           (self.edits || !event.is_edit()) &&
            event.body().unwrap().starts_with(&self.prefix)
         {
             event.raw_event.content["body"] =
@@ -38,4 +49,30 @@ impl<'a> Node<'a> for Prefix<'a> {
             self.propagate_event(bot, &event);
         }
     }
+
+    fn reload_config(&mut self, ctx: &Context, config: &Table) -> Result<(), String> {
+        let cfg: PrefixConfig = graph::parse(config)?;
+        self.prefix = cfg.prefix.unwrap_or_else(|| ctx.config.bot.prefix.clone());
+        self.prefix_n = self.prefix.len();
+        self.edits = cfg.edits.unwrap_or(ctx.config.bot.edits);
+
+        Ok(())
+    }
+
+    fn configure(&mut self, bot: &Bot, command: &str, event: RoomEvent) {
+        if let Some(mode_args) = command.strip_prefix("edits ") {
+            match mode_args {
+                "on" => self.edits = true,
+                "off" => self.edits = false,
+                x => { bot.reply(&event, &format!("Invalid mode passed to edits: {}", x)).ok(); },
+            }
+        } else if command.starts_with("status") {
+            bot.reply(&event, &format!("edits: {}", if self.edits { "on" } else { "off" })).ok();
+        }
+    }
+
+    fn configure_description(&self) -> Option<String> {
+        Some("edits  <on|off> - Whether edited messages can trigger commands.\n\
+              status          - View the current configuration of the node.".to_string())
+    }
 }
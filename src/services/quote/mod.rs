@@ -1,4 +1,5 @@
 mod backend;
+#[allow(non_local_definitions)]
 mod models;
 
 mod quotes;
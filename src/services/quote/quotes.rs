@@ -86,7 +86,7 @@ impl<'a> Node<'a> for Quotes {
 
             bot.reply(&event, &match query.is_empty() {
                 true => "Please specify a user".to_string(),
-                false => match bot.uid_from_displayname(query) {
+                false => match bot.resolve_user(&event, query) {
                     Ok(uid) => {
                         if let Ok((quoter, quote)) = self.quote_db.quote_by(&uid) {
                             render_quote(&quote, &quoter)
@@ -94,6 +94,7 @@ impl<'a> Node<'a> for Quotes {
                             "No quotes found.".to_string()
                         }
                     },
+                    Err(crate::errors::Error::Generic(e)) => e,
                     Err(_) => "Unable to identify user.".to_string(),
                 },
             }).ok();
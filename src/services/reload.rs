@@ -0,0 +1,20 @@
+use crate::bot::{Bot, Node, RoomEvent};
+
+pub struct Reload;
+impl Reload {
+    pub fn new() -> Self {
+        Reload
+    }
+}
+
+impl<'a> Node<'a> for Reload {
+    fn handle(&mut self, bot: &Bot, event: RoomEvent) {
+        if event.is_normal() && event.body() == Some("reload") {
+            bot.reload_config(event.room_id);
+        }
+    }
+
+    fn description(&self) -> Option<String> {
+        Some("reload - Reload config.toml and apply the changed settings".to_string())
+    }
+}
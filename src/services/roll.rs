@@ -16,13 +16,13 @@ impl<'a> Node<'a> for Roll {
             let n: u32 = match raw_n.parse() {
                 Ok(v) => v,
                 Err(e) => {
-                    bot.reply(&event, &format!("{}", e)).ok();
+                    bot.reply_in_thread(&event, &format!("{}", e)).ok();
                     return
                 },
             };
             let mut rng = rand::thread_rng();
             let value = rng.gen_range(1..=n);
-            bot.reply(&event, &format!("Roll {}: {}", n, value)).ok();
+            bot.reply_in_thread(&event, &format!("Roll {}: {}", n, value)).ok();
         }
     }
 
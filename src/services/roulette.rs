@@ -48,16 +48,24 @@ impl<'a> Node<'a> for Roulette {
 
             if (self.mode == RemovalMode::Ban && body.starts_with("rroulette")) ||
                (self.mode == RemovalMode::Kick && body.starts_with("roulette")) {
+                if let Err(e) = bot.check_removal(event.room_id, &self.mode, &revent.sender) {
+                    bot.reply(&event, &format!("Not playing: {}", e)).ok();
+                    return;
+                }
+
                 println!("Found roulette state: {}, rounds: {:?}", self.state, self.rounds);
 
                 match self.fire() {
                     true => {
                         self.reset();
-                        match &self.mode {
+                        let removal = match &self.mode {
                             RemovalMode::Kick => bot.client().kick(event.room_id, &revent.sender, Some("Bang!")),
                             RemovalMode::Ban => bot.client().ban(event.room_id, &revent.sender, Some("Bang!")),
-                        }.ok();
-                        bot.reply(&event, "Bang!").ok()
+                        };
+                        match removal {
+                            Ok(_) => bot.reply(&event, "Bang!").ok(),
+                            Err(e) => bot.reply(&event, &format!("Bang! (but unable to {}: {})", self.mode.as_str(), e)).ok(),
+                        }
                     },
                     false => bot.reply(&event, "Click.").ok(),
                 };
@@ -1,23 +1,20 @@
 use std::collections::HashMap;
 
 use crate::{
-    bot::{Bot, Node, RoomEvent},
+    bot::{Bot, Node, NodeInfo, RoomEvent},
+    export::{self, ExportNode, Format},
     utils::codeblock_format
 };
 
 
-pub struct Structure {
-    reply_room: Option<String>,
-}
+pub struct Structure;
 
 impl Structure {
     pub fn new() -> Self {
-        Self {
-            reply_room: None
-        }
+        Structure
     }
 
-    pub fn structure(&self, bot: &Bot, children: &HashMap<&str, Vec<String>>) -> String {
+    pub fn structure(bot: &Bot, children: &HashMap<&str, Vec<String>>) -> String {
         let mut out_lines = Vec::new();
 
         let mut at_depth_remaining = HashMap::new();
@@ -60,7 +57,8 @@ impl Structure {
                 };
             }
 
-            out_lines.push(format!("{}+- {}", lines, service));
+            let disabled = if bot.is_node_disabled(service) { " (disabled)" } else { "" };
+            out_lines.push(format!("{}+- {}{}", lines, service, disabled));
 
             *at_depth_remaining.get_mut(&depth).unwrap() -= 1;
 
@@ -80,46 +78,55 @@ impl Structure {
     }
 }
 
-fn query(_: &Bot, n: &mut dyn Node) -> Box<dyn std::any::Any> {
-    let mut kids = Box::new(Vec::with_capacity(n.children().map_or(0, |c| c.len())));
-
-    if let Some(children) = n.children() {
-        for c in children.iter() {
-            kids.push(c.to_string());
-        }
-    }
-
-    kids
-}
-
 impl<'a> Node<'a> for Structure {
-    fn handle<'b>(&mut self, bot: &Bot, event: RoomEvent) {
+    fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let body = &event.raw_event.content["body"].as_str().unwrap();
 
-        if body.starts_with("structure") {
-            self.reply_room = Some(event.room_id.to_string());
-            bot.delay_service_query("structure", None, query);
-        }
-    }
-
-    fn recieve_all_node_post(&mut self, bot: &Bot, result: Vec<(&str, Box<dyn std::any::Any>)>) {
-        let mut children = HashMap::new();
-
-        for (node, value) in result {
-            let node_children = value.downcast::<Vec<String>>().unwrap();
-            children.insert(node, *node_children);
-        }
-
-        if let Some(ref room_id) = self.reply_room {
-            let raw_msg = self.structure(bot, &children);
-            let msg = codeblock_format(&raw_msg);
-            bot.client().send_msg_fmt(room_id, &msg, &raw_msg).ok();
+        if let Some(args) = body.strip_prefix("structure") {
+            let format = match args.trim() {
+                "" => None,
+                format => match Format::parse(format) {
+                    Some(format) => Some(format),
+                    None => {
+                        bot.reply(&event, "Usage: structure [dot|mermaid]").ok();
+                        return;
+                    },
+                },
+            };
+            let room_id = event.room_id.to_string();
+
+            // The nodes above this one can only be reached once they're done
+            bot.after_event(move |bot| {
+                let nodes = match bot.inspect_graph() {
+                    Ok(nodes) => nodes,
+                    Err(e) => {
+                        bot.client().send_msg(&room_id, &format!("Unable to look at the graph: {}", e)).ok();
+                        return;
+                    },
+                };
 
-            self.reply_room = None;
+                if let Some(format) = format {
+                    let exported: Vec<ExportNode> = nodes.iter().map(ExportNode::from).collect();
+                    let exported = export::render(&exported, format);
+                    let sent = bot.client().send_file(&room_id, format.filename(), exported.as_bytes(), format.content_type());
+                    if let Err(e) = sent {
+                        bot.client().send_msg(&room_id, &format!("Unable to upload the graph: {}", e)).ok();
+                    }
+                } else {
+                    let raw_msg = Structure::structure(bot, &children(&nodes));
+                    let msg = codeblock_format(&raw_msg);
+                    bot.client().send_msg_fmt(&room_id, &msg, &raw_msg).ok();
+                }
+            });
         }
     }
 
     fn description(&self) -> Option<String> {
-        Some("structure - outputs ascii visualization of all the configured bot services".to_string())
+        Some("structure [dot|mermaid] - outputs ascii visualization of all the configured bot services, \
+              or uploads it as a Graphviz or Mermaid file".to_string())
     }
 }
+
+fn children(nodes: &[NodeInfo]) -> HashMap<&str, Vec<String>> {
+    nodes.iter().map(|n| (n.name.as_str(), n.children.clone())).collect()
+}
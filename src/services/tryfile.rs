@@ -1,5 +1,6 @@
+use std::collections::HashMap;
 use std::fs::{self, File};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::{BufReader, BufRead};
 
 use rand::SeedableRng;
@@ -8,13 +9,17 @@ use regex::Regex;
 use toml::value::Value;
 
 use crate::bot::{Bot, Node, RoomEvent};
-use crate::utils::reservoir_sample;
+use crate::utils::{guess_mimetype, media_info, reservoir_sample};
 
 
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
+
 pub struct TryFile {
     safe_re: Regex,
     rng: SmallRng,
     directory: PathBuf,
+    // Content uri and info of images which have already been uploaded
+    uploaded: HashMap<PathBuf, (String, serde_json::Value)>,
 }
 
 
@@ -32,8 +37,49 @@ impl TryFile {
             safe_re: Regex::new(r"[a-zA-Z]").unwrap(),
             rng: SmallRng::from_entropy(),
             directory: path,
+            uploaded: HashMap::new(),
+        }
+    }
+
+    /// Build path to file to try. canonicalize normalizes the path and
+    /// validates that it exists.
+    fn find(&self, name: &str, extension: &str) -> Option<PathBuf> {
+        let path = self.directory.join(name).with_extension(extension).canonicalize().ok()?;
+
+        // Make sure any accessed file is a child of the config directory
+        match path.starts_with(&self.directory) {
+            true => Some(path),
+            false => None,
         }
     }
+
+    /// Images are only uploaded the first time, after that the uploaded copy
+    /// is reused.
+    fn send_image(&mut self, bot: &Bot, event: &RoomEvent, path: &Path) {
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+
+        if !self.uploaded.contains_key(path) {
+            let data = match fs::read(path) {
+                Ok(d) => d,
+                Err(e) => {
+                    println!("Unable to read {}: {}", path.display(), e);
+                    return;
+                },
+            };
+
+            let mimetype = guess_mimetype(path);
+            match bot.client().upload(&data, mimetype, Some(&filename)) {
+                Ok(mxc) => self.uploaded.insert(path.to_path_buf(), (mxc, media_info(&data, mimetype))),
+                Err(e) => {
+                    println!("Unable to upload {}: {}", path.display(), e);
+                    return;
+                },
+            };
+        }
+
+        let (mxc, info) = &self.uploaded[path];
+        bot.client().send_media(event.room_id, "m.image", &filename, mxc, info).ok();
+    }
 }
 
 
@@ -41,25 +87,19 @@ impl<'a> Node<'a> for TryFile {
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let body = event.raw_event.content["body"].as_str().unwrap();
 
-        if self.safe_re.is_match(body) {
-            // Build path to file to try
-            // canonicalize normalizes the path and validates that it exists
-            let path = match (self.directory).join(body).with_extension("txt").canonicalize() {
-                Ok(p) => p,
-                Err(_) => return,
-            };
-
-            // Make sure any accessed file is a child of the config directory
-            if !path.starts_with(&self.directory) {
-                return;
-            }
+        if !self.safe_re.is_match(body) {
+            return;
+        }
 
+        if let Some(path) = self.find(body, "txt") {
             if let Ok(d) = File::open(path) {
                 let reader = BufReader::new(d);
                 if let Ok(v) = reservoir_sample(reader.lines(), &mut self.rng) {
                     bot.reply(&event, &v).ok();
                 }
             };
+        } else if let Some(path) = IMAGE_EXTENSIONS.iter().find_map(|ext| self.find(body, ext)) {
+            self.send_image(bot, &event, &path);
         }
     }
 
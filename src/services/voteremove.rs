@@ -6,9 +6,11 @@ use std::{
 };
 
 use regex::Regex;
+use toml::value::Table;
 
 use crate::{bot::{Bot, Node, RoomEvent}, state};
 use crate::config::RemovalMode;
+use crate::graph::{self, Context, VoteConfig};
 
 struct Vote {
     start: Instant,
@@ -57,7 +59,7 @@ impl Voteremove {
                 thread::sleep(t_sleep);
                 let mut votes_map = t_votes.lock().expect("Poisoned");
                 if votes_map.remove(&t_target).is_some() {
-                    let mut client = t_client.write().unwrap();
+                    let client = t_client.read().unwrap();
                     client.send_msg(&t_room_id, &format!("Vote to {} {} expired.", &t_mode, &t_target)).ok();
                 }
             });
@@ -80,6 +82,15 @@ impl Voteremove {
 }
 
 impl<'a> Node<'a> for Voteremove {
+    fn reload_config(&mut self, _: &Context, config: &Table) -> Result<(), String> {
+        let cfg: VoteConfig = graph::parse(config)?;
+        self.votes_required = cfg.votes;
+        self.timeout = Duration::new(cfg.wait_minutes * 60, 0);
+        self.mode = cfg.mode;
+
+        Ok(())
+    }
+
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let revent = &event.raw_event;
         if event.is_normal() {
@@ -89,10 +100,10 @@ impl<'a> Node<'a> for Voteremove {
                 match captures.get(1) {
                     Some(query) => {
                         let query_str = query.as_str().trim();
-                        let uid = match bot.uid_from_displayname(query_str) {
+                        let uid = match bot.resolve_user(&event, query_str) {
                             Ok(r) => r,
-                            Err(crate::errors::Error::Generic(_)) => {
-                                bot.reply(&event, &format!("Unable to determine user id for: {}", query_str)).ok();
+                            Err(crate::errors::Error::Generic(e)) => {
+                                bot.reply(&event, &e).ok();
                                 return;
                             },
                             Err(e) => {
@@ -101,6 +112,11 @@ impl<'a> Node<'a> for Voteremove {
                             }
                         };
 
+                        if let Err(e) = bot.check_removal(event.room_id, &self.mode, &uid) {
+                            bot.reply(&event, &e).ok();
+                            return;
+                        }
+
                         self.vote_user(bot, &event, &revent.sender, &uid);
                         let mut vl = self.votes.lock().expect("Poisoned");
                         if let Some(vote_res) = vl.get(&uid) {
@@ -109,10 +125,14 @@ impl<'a> Node<'a> for Voteremove {
 
                             if cur_votes == self.votes_required {
                                 vl.remove(&uid);
-                                match self.mode {
+                                let removal = match self.mode {
                                     RemovalMode::Kick => bot.client().kick(event.room_id, &uid, Some("Votekicked")),
                                     RemovalMode::Ban => bot.client().ban(event.room_id, &uid, Some("Votebanned"))
-                                }.ok();
+                                };
+
+                                if let Err(e) = removal {
+                                    bot.reply(&event, &format!("Unable to {} {}: {}", self.mode.as_str(), uid, e)).ok();
+                                }
                             } else {
                                 let mode = match self.mode {
                                     RemovalMode::Kick => "Votekick",
@@ -215,4 +235,27 @@ fn render_dur(duration: Duration) -> String{
     let render_seconds = seconds % 60;
 
     format!("{:02}:{:02}", minutes, render_seconds)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::*;
+
+    #[test]
+    fn refuses_to_target_moderators() {
+        let mock = MockServer::start();
+        set_room_state(&mock, vec![
+            state_event("m.room.member", "@mod:mock.server", json!({ "membership": "join", "displayname": "Mod" })),
+            state_event("m.room.power_levels", "", json!({ "users": { TEST_USER: 100, "@mod:mock.server": 50 } })),
+        ]);
+        let bot = Bot::new(mock.client());
+
+        Voteremove::new(1, 1, RemovalMode::Kick).handle(&bot, text("votekick Mod"));
+
+        assert_eq!(mock.replies(), ["I won't kick @mod:mock.server, they are a room moderator"]);
+        assert!(!mock.requests().iter().any(|r| r.path.ends_with("/kick")));
+    }
+}
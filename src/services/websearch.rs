@@ -1,7 +1,8 @@
 use reqwest;
-use toml::Value;
+use toml::{value::Table, Value};
 
 use crate::bot::{Bot, Node, RoomEvent};
+use crate::graph::Context;
 
 
 const BASE_URL: &str = "https://www.googleapis.com/customsearch/v1";
@@ -25,7 +26,7 @@ struct Spelling {
     correctedQuery: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct WebSearch {
     key: String,
     seid: String,
@@ -46,9 +47,39 @@ impl WebSearch {
 
         query.send().and_then(|o| o.json())
     }
+
+    fn first_result(&self, query: &str) -> Option<String> {
+        let mut res = self.search(query);
+
+        let mut tries: u32 = 0;
+        while res.is_ok() && tries < 2 {
+            if let Ok(sr) = res.as_ref() {
+                // Might need to re-search due to spelling issue
+                if let (None, Some(spelling)) = (&sr.items, &sr.spelling) {
+                    res = self.search(&spelling.correctedQuery);
+                    tries += 1;
+                    continue;
+                }
+
+                if let Some(f) = sr.items.as_ref().and_then(|i| i.first()) {
+                    return Some(format!("{} - {}", f.title, f.link));
+                }
+            }
+
+            break;
+        }
+
+        None
+    }
 }
 
 impl<'a> Node<'a> for WebSearch {
+    fn reload_config(&mut self, ctx: &Context, config: &Table) -> Result<(), String> {
+        *self = ctx.parse_service_config("web_search", config)?;
+
+        Ok(())
+    }
+
     fn handle(&mut self, bot: &Bot, event: RoomEvent) {
         let revent = &event.raw_event;
 
@@ -56,24 +87,14 @@ impl<'a> Node<'a> for WebSearch {
             let body = &revent.content["body"].as_str().unwrap();
 
             if let Some(query) = body.strip_prefix("s ") {
-                let mut res = self.search(query);
-
-                let mut tries: u32 = 0;
-                while res.is_ok() && tries < 2 {
-                    if let Ok(sr) = res.as_ref() {
-                        // Might need to re-search due to spelling issue
-                        if sr.items.is_none() && sr.spelling.is_some() {
-                            res = self.search(&sr.spelling.as_ref().unwrap().correctedQuery);
-                            tries += 1;
-                            continue;
-                        }
-
-                        if let Some(f) = sr.items.as_ref().and_then(|i| i.first()) {
-                            bot.reply(&event, &format!("{} - {}", f.title, f.link)).ok();
-                            return;
-                        }
+                let search = self.clone();
+                let query = query.to_string();
+
+                bot.spawn(&event, move || search.first_result(&query), |bot, event, result| {
+                    if let Some(result) = result {
+                        bot.reply(event, &result).ok();
                     }
-                }
+                });
             }
         }
     }
@@ -1,25 +1,49 @@
+#[cfg(test)]
+use std::cell::RefCell;
 use std::path::PathBuf;
 use std::fs;
 use std::fs::File;
 use std::io::{prelude::*, BufReader};
 
+#[cfg(test)]
+thread_local! {
+    // State directory of the test running on this thread
+    pub(crate) static TEST_STATE_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Directory the state files are kept in
+#[cfg(not(test))]
+fn state_dir() -> PathBuf {
+    PathBuf::from(".rustix")
+}
+
+/// Each test keeps its state in a directory of its own, see
+/// `test_support::test_state`
+#[cfg(test)]
+fn state_dir() -> PathBuf {
+    TEST_STATE_DIR.with(|dir| dir.borrow().clone())
+                  .expect("Tests using saved state must hold a test_support::test_state()")
+}
+
+/// Save the state of a service. The name may contain a directory, for services
+/// which keep their state in several files.
 pub fn save_state(service_name: &str, value: &str) {
-    let mut path = PathBuf::from(".rustix");
+    let mut path = state_dir();
+    path.push(service_name);
 
-    if let Err(e) = fs::create_dir(&path) {
+    if let Err(e) = fs::create_dir_all(path.parent().unwrap()) {
         if e.kind() != std::io::ErrorKind::AlreadyExists {
             println!("Unable to create .rustix save state directory");
             panic!("{:?}", e);
         }
     }
 
-    path.push(service_name);
     let mut f = File::create(path).unwrap_or_else(|_| panic!("Unable to create save state file for {}.", service_name));
     f.write_all(value.as_bytes()).expect("Failed to write state to save file.");
 }
 
 pub fn load_state(service_name: &str) -> Option<String> {
-    let mut path = PathBuf::from(".rustix");
+    let mut path = state_dir();
     path.push(service_name);
 
     if let Ok(f) = File::open(path) {
@@ -33,4 +57,33 @@ pub fn load_state(service_name: &str) -> Option<String> {
     } else {
         None
     }
-}
\ No newline at end of file
+}
+
+/// Names of the state files kept in a directory of the state directory, in a
+/// form `load_state` accepts.
+pub fn list_state(dir: &str) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(state_dir().join(dir)) else {
+        return Vec::new()
+    };
+
+    entries.filter_map(|e| e.ok())
+           .filter(|e| e.path().is_file())
+           .filter_map(|e| e.file_name().into_string().ok())
+           .map(|name| format!("{}/{}", dir, name))
+           .collect()
+}
+
+pub fn remove_state(service_name: &str) {
+    let path = state_dir().join(service_name);
+
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            println!("Unable to remove state save file {}: {:?}", service_name, e);
+        }
+    }
+}
+
+/// Move a state file or directory to another name in the state directory
+pub fn rename_state(from: &str, to: &str) -> std::io::Result<()> {
+    fs::rename(state_dir().join(from), state_dir().join(to))
+}
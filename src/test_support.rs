@@ -0,0 +1,289 @@
+//! Helpers for testing nodes without a homeserver. `MockServer` stands in for
+//! the homeserver on a local port, recording the requests the bot makes and
+//! answering them with scripted or default responses.
+
+use std::collections::{HashMap, VecDeque};
+use std::{env, fs, process};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::bot::RoomEvent;
+use crate::client::MatrixClient;
+use crate::matrix_types::Event;
+use crate::state;
+
+
+pub const TEST_USER: &str = "@rustix:mock.server";
+pub const TEST_ROOM: &str = "!room:mock.server";
+pub const TEST_SENDER: &str = "@alice:mock.server";
+
+// Prefix of the client-server API paths, left out of recorded paths
+const CLIENT_API: &str = "/_matrix/client/v3";
+
+
+/// A request the bot made to the mock homeserver
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    pub method: String,
+    /// Path without the `/_matrix/client/v3` prefix
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub body: Value,
+}
+
+/// An event the bot sent to a room
+#[derive(Clone, Debug, PartialEq)]
+pub struct SentEvent {
+    pub room_id: String,
+    pub event_type: String,
+    pub content: Value,
+}
+
+#[derive(Default)]
+struct MockState {
+    requests: Vec<Recorded>,
+    responses: HashMap<(String, String), (u16, Value)>,
+    syncs: VecDeque<Value>,
+    event_count: u64,
+}
+
+pub struct MockServer {
+    url: String,
+    server: Arc<Server>,
+    state: Arc<Mutex<MockState>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        let server = Arc::new(Server::http("127.0.0.1:0").expect("Unable to start mock homeserver"));
+        let url = format!("http://{}/", server.server_addr().to_ip().expect("Mock homeserver should use tcp"));
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let t_server = Arc::clone(&server);
+        let t_state = Arc::clone(&state);
+        let thread = thread::spawn(move || {
+            for request in t_server.incoming_requests() {
+                handle_request(&t_state, request);
+            }
+        });
+
+        MockServer {
+            url,
+            server,
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    /// A client logged in to the mock homeserver as `TEST_USER`
+    pub fn client(&self) -> Arc<RwLock<MatrixClient>> {
+        let mut client = MatrixClient::new(&self.url);
+        client.login("rustix", "password", None).expect("Mock login should succeed");
+
+        Arc::new(RwLock::new(client))
+    }
+
+    /// Answer `method` requests to `path` (without the `/_matrix/client/v3`
+    /// prefix) with `body` instead of the default response.
+    pub fn respond(&self, method: &str, path: &str, status: u16, body: Value) {
+        self.state.lock().unwrap()
+            .responses.insert((method.to_string(), path.to_string()), (status, body));
+    }
+
+    /// Queue the response to a sync request. Syncs without a queued response
+    /// get back an empty one.
+    pub fn queue_sync(&self, sync: Value) {
+        self.state.lock().unwrap().syncs.push_back(sync);
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Events sent to rooms, oldest first
+    pub fn sent(&self) -> Vec<SentEvent> {
+        self.requests().into_iter().filter_map(|r| {
+            let parts: Vec<&str> = r.path.split('/').collect();
+            match (r.method.as_str(), parts.as_slice()) {
+                ("PUT", ["", "rooms", room_id, "send", event_type, _]) => Some(SentEvent {
+                    room_id: room_id.to_string(),
+                    event_type: event_type.to_string(),
+                    content: r.body,
+                }),
+                _ => None,
+            }
+        }).collect()
+    }
+
+    /// Bodies of the messages sent to rooms, oldest first
+    pub fn replies(&self) -> Vec<String> {
+        self.sent().into_iter()
+            .filter(|e| e.event_type == "m.room.message")
+            .filter_map(|e| e.content["body"].as_str().map(String::from))
+            .collect()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+
+fn handle_request(state: &Mutex<MockState>, mut request: Request) {
+    let method = request.method().to_string();
+    let (path, query) = match request.url().split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (request.url().to_string(), String::new()),
+    };
+    let path = path.strip_prefix(CLIENT_API).unwrap_or(&path).to_string();
+    let query = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+
+    let mut raw_body = Vec::new();
+    request.as_reader().read_to_end(&mut raw_body).ok();
+    let body = serde_json::from_slice(&raw_body).unwrap_or(Value::Null);
+
+    let mut state = state.lock().unwrap();
+    state.requests.push(Recorded {
+        method: method.clone(),
+        path: path.clone(),
+        query,
+        body,
+    });
+
+    let (status, response) = match state.responses.get(&(method.clone(), path.clone())) {
+        Some(scripted) => scripted.clone(),
+        None => default_response(&mut state, &method, &path),
+    };
+    drop(state);
+
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let response = Response::from_string(response.to_string())
+                            .with_status_code(status)
+                            .with_header(content_type);
+    request.respond(response).ok();
+}
+
+/// What the mock homeserver answers when no response was scripted
+fn default_response(state: &mut MockState, method: &str, path: &str) -> (u16, Value) {
+    let parts: Vec<&str> = path.split('/').collect();
+    match (method, parts.as_slice()) {
+        ("POST", ["", "login"]) | ("GET", ["", "account", "whoami"]) => {
+            (200, json!({
+                "user_id": TEST_USER,
+                "access_token": "mock_token",
+                "device_id": "MOCKDEVICE",
+            }))
+        },
+        ("PUT", ["", "rooms", _, "send" | "redact", ..]) => {
+            state.event_count += 1;
+            (200, json!({ "event_id": format!("$mock{}", state.event_count) }))
+        },
+        ("GET", ["", "sync"]) => {
+            let sync = state.syncs.pop_front().unwrap_or_else(|| json!({ "next_batch": "mock" }));
+            (200, sync)
+        },
+        ("POST", ["", "user", _, "filter"]) => (200, json!({ "filter_id": "mock" })),
+        ("POST", ["", "join", room]) => (200, json!({ "room_id": room })),
+        ("GET", ["", "joined_rooms"]) => (200, json!({ "joined_rooms": [TEST_ROOM] })),
+        ("GET", ["", "rooms", _, "state"]) => (200, json!([])),
+        ("GET", _) => (404, json!({ "errcode": "M_NOT_FOUND" })),
+        _ => (200, json!({})),
+    }
+}
+
+
+/// A state directory of its own for the test holding it, removed once the
+/// test is done with it
+pub struct TestState(PathBuf);
+
+impl Drop for TestState {
+    fn drop(&mut self) {
+        state::TEST_STATE_DIR.with(|dir| dir.borrow_mut().take());
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Held by tests which save or load state, e.g. by syncing or loading the
+/// node graph, so they don't see each other's state or that of earlier runs
+pub fn test_state() -> TestState {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!("rustix-test-{}-{}", process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+    fs::remove_dir_all(&dir).ok();
+
+    state::TEST_STATE_DIR.with(|d| *d.borrow_mut() = Some(dir.clone()));
+    TestState(dir)
+}
+
+/// A text message event from `sender`
+pub fn message_event(sender: &str, body: &str) -> Event {
+    serde_json::from_value(json!({
+        "type": "m.room.message",
+        "sender": sender,
+        "event_id": "$incoming",
+        "origin_server_ts": 0,
+        "content": {
+            "msgtype": "m.text",
+            "body": body,
+        },
+    })).unwrap()
+}
+
+/// A text message from `TEST_SENDER` in `TEST_ROOM`, as handed to nodes
+pub fn text(body: &str) -> RoomEvent<'static> {
+    room_event(message_event(TEST_SENDER, body))
+}
+
+/// Wrap an event as if it was synced from `TEST_ROOM`
+pub fn room_event(raw_event: Event) -> RoomEvent<'static> {
+    RoomEvent {
+        room_id: TEST_ROOM,
+        from: "join",
+        raw_event,
+    }
+}
+
+/// A sync response carrying `events` in the timeline of `TEST_ROOM`
+pub fn sync_response(next_batch: &str, events: &[Event]) -> Value {
+    json!({
+        "next_batch": next_batch,
+        "rooms": {
+            "join": {
+                TEST_ROOM: {
+                    "account_data": {},
+                    "ephemeral": {},
+                    "state": { "events": [] },
+                    "timeline": { "events": events },
+                    "unread_notifications": {},
+                },
+            },
+        },
+    })
+}
+
+/// A state event as returned when fetching room state, to script responses
+pub fn state_event(event_type: &str, state_key: &str, content: Value) -> Value {
+    json!({
+        "type": event_type,
+        "state_key": state_key,
+        "sender": TEST_USER,
+        "event_id": format!("${}{}", event_type, state_key),
+        "origin_server_ts": 0,
+        "content": content,
+    })
+}
+
+/// Script the state of `TEST_ROOM`, as loaded into the bot's room state cache
+pub fn set_room_state(mock: &MockServer, events: Vec<Value>) {
+    mock.respond("GET", &format!("/rooms/{}/state", TEST_ROOM), 200, Value::Array(events));
+}
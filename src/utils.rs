@@ -1,4 +1,8 @@
+use std::path::Path;
+
 use rand::Rng;
+use regex::Regex;
+use serde_json::{json, Value};
 
 const K: usize = 10;
 
@@ -55,7 +59,7 @@ impl TrimMatch for str {
     /// # Arguments
     ///
     /// * `variants` - Reference to a slice of string references to compare the
-    ///                base string against
+    ///   base string against
     fn trim_match<'a>(&'a self, variants: &[&str]) -> Option<&'a str> {
         let trimmed = self.trim();
         for variant in variants {
@@ -72,4 +76,120 @@ impl TrimMatch for str {
 pub fn codeblock_format(message: &str) -> String {
     let sanitized = message.replace('<', "&lt;").replace('>', "&gt;");
     format!("<pre><code class=\"language-text\">{}</code></pre>", &sanitized)
-}
\ No newline at end of file
+}
+
+
+/// Guess the mimetype of a file from its extension
+pub fn guess_mimetype(path: &Path) -> &'static str {
+    let ext = path.extension()
+                  .and_then(|e| e.to_str())
+                  .map(|e| e.to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("txt") => "text/plain",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+
+/// Read the width and height of a PNG, GIF or JPEG image from its header
+pub fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let byte = |i: usize| data.get(i).map(|b| *b as u32);
+    let be16 = |i: usize| Some(byte(i)? << 8 | byte(i + 1)?);
+    let le16 = |i: usize| Some(byte(i + 1)? << 8 | byte(i)?);
+    let be32 = |i: usize| Some(be16(i)? << 16 | be16(i + 2)?);
+
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some((be32(16)?, be32(20)?));
+    }
+
+    if data.starts_with(b"GIF8") {
+        return Some((le16(6)?, le16(8)?));
+    }
+
+    if data.starts_with(&[0xFF, 0xD8]) {
+        // Walk the segments until the start of frame, which has the size
+        let mut i = 2;
+        while *data.get(i)? == 0xFF {
+            let marker = *data.get(i + 1)?;
+            if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+                return Some((be16(i + 7)?, be16(i + 5)?));
+            }
+            i += 2 + be16(i + 2)? as usize;
+        }
+    }
+
+    None
+}
+
+
+/// The `info` of an `m.image` or `m.file` message
+pub fn media_info(data: &[u8], mimetype: &str) -> Value {
+    let mut info = json!({
+        "mimetype": mimetype,
+        "size": data.len(),
+    });
+
+    if mimetype.starts_with("image/") {
+        if let Some((w, h)) = image_dimensions(data) {
+            info["w"] = w.into();
+            info["h"] = h.into();
+        }
+    }
+
+    info
+}
+
+
+/// Escape plain text for use in an html formatted message body
+pub fn html_escape(message: &str) -> String {
+    message.replace('&', "&amp;")
+           .replace('<', "&lt;")
+           .replace('>', "&gt;")
+           .replace('"', "&quot;")
+           .replace('\n', "<br />")
+}
+
+/// The body of a reply without its fallback: the leading `> ` lines quoting
+/// the replied to message and the empty line after them
+pub fn strip_reply_fallback(body: &str) -> &str {
+    let mut rest = body;
+    while rest.starts_with("> ") || rest.starts_with(">\n") || rest == ">" {
+        rest = rest.split_once('\n').map_or("", |(_, r)| r);
+    }
+
+    if rest.len() == body.len() {
+        return body;
+    }
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
+/// The html formatted body of a reply without the `<mx-reply>` block quoting
+/// the replied to message
+pub fn strip_mx_reply(formatted_body: &str) -> String {
+    const END: &str = "</mx-reply>";
+
+    match (formatted_body.find("<mx-reply>"), formatted_body.find(END)) {
+        (Some(start), Some(end)) if start < end => {
+            format!("{}{}", &formatted_body[..start], &formatted_body[end + END.len()..])
+        },
+        _ => formatted_body.to_string(),
+    }
+}
+
+/// Find user mention pills in an html formatted message body, giving the
+/// mentioned user id along with the text of the pill
+pub fn mention_pills(formatted_body: &str) -> Vec<(String, String)> {
+    let pill_re = Regex::new(r#"<a href="https://matrix\.to/#/((?:@|%40)[^"?/]+)[^"]*">(.*?)</a>"#).unwrap();
+
+    pill_re.captures_iter(formatted_body).map(|c| {
+        let user_id = c[1].replace("%40", "@").replace("%3A", ":").replace("%3a", ":");
+        (user_id, c[2].trim().to_string())
+    }).collect()
+}
@@ -0,0 +1,98 @@
+//! A small pool of threads for slow work, like requests to external services,
+//! which would otherwise hold up the handling of every other event. Each job
+//! hands back a completion which is run by the bot on its own thread.
+
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::bot::Bot;
+
+
+/// Delivers the result of a job, run on the bot's thread
+pub type Completion = Box<dyn FnOnce(&Bot) + Send>;
+
+type Job = Box<dyn FnOnce() -> Completion + Send>;
+
+
+pub struct WorkerPool {
+    jobs: mpsc::Sender<Job>,
+    completions: mpsc::Receiver<Completion>,
+    pending: Cell<usize>,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> Self {
+        let (jobs, job_queue) = mpsc::channel::<Job>();
+        let (done, completions) = mpsc::channel();
+        let job_queue = Arc::new(Mutex::new(job_queue));
+
+        for i in 0..threads {
+            let job_queue = Arc::clone(&job_queue);
+            let done = done.clone();
+
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || worker(&job_queue, &done))
+                .expect("Unable to start worker thread");
+        }
+
+        WorkerPool {
+            jobs,
+            completions,
+            pending: Cell::new(0),
+        }
+    }
+
+    pub fn submit(&self, job: impl FnOnce() -> Completion + Send + 'static) {
+        if self.jobs.send(Box::new(job)).is_ok() {
+            self.pending.set(self.pending.get() + 1);
+        }
+    }
+
+    /// Number of jobs whose completion hasn't been taken yet
+    pub fn pending(&self) -> usize {
+        self.pending.get()
+    }
+
+    /// Take the completion of a finished job, waiting up to `timeout` for one
+    pub fn completion(&self, timeout: Duration) -> Option<Completion> {
+        if self.pending.get() == 0 {
+            return None;
+        }
+
+        let completion = if timeout.is_zero() {
+            self.completions.try_recv().ok()
+        } else {
+            self.completions.recv_timeout(timeout).ok()
+        };
+
+        if completion.is_some() {
+            self.pending.set(self.pending.get() - 1);
+        }
+
+        completion
+    }
+}
+
+
+fn worker(job_queue: &Mutex<mpsc::Receiver<Job>>, done: &mpsc::Sender<Completion>) {
+    loop {
+        // The queue is closed once the pool is dropped
+        let job = match job_queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let completion = panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_else(|_| {
+            println!("Worker job panicked, dropping its result");
+            Box::new(|_: &Bot| ())
+        });
+
+        if done.send(completion).is_err() {
+            return;
+        }
+    }
+}